@@ -0,0 +1,227 @@
+//! Offline bundles: a gem and its whole runtime dependency closure as
+//! `.gem` files, ready for `gem install --local`.
+//!
+//! Resolution is greedy rather than a full backtracking resolver: each
+//! dependency gets the newest stored version that meets every requirement
+//! seen for it so far, and a later requirement that the chosen version
+//! doesn't meet is reported as a conflict instead of being solved.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs::File,
+    io::BufWriter,
+    path::Path,
+};
+
+use miette::{bail, miette};
+
+use crate::{
+    export::partial_path,
+    platform::Platform,
+    storage::{Gem, Index, Store},
+    version::{Requirement, Version},
+};
+
+/// Resolves `name` (at `version`, or its newest release) and its runtime
+/// dependencies among the stored, unyanked gems of every index. Gems for
+/// `platform` are preferred over pure-Ruby ones of the same version; without
+/// one, only pure-Ruby gems are picked.
+pub fn resolve(
+    indices: &[Index],
+    name: &str,
+    version: Option<&str>,
+    platform: Option<&Platform>,
+) -> miette::Result<Vec<Gem>> {
+    let candidates = |name: &str| {
+        let mut gems = indices
+            .iter()
+            .filter_map(|index| index.gems.get(name))
+            .flat_map(|namespace| namespace.versions.values())
+            .filter(|gem| gem.stored && !gem.yanked)
+            .filter(|gem| {
+                let gem_platform = Platform::parse(&gem.platform);
+                gem_platform.is_ruby()
+                    || platform.is_some_and(|platform| platform.matches(&gem_platform))
+            })
+            .collect::<Vec<_>>();
+        // Newest first, and a native gem before the pure-Ruby one
+        gems.sort_by(|a, b| {
+            Version::parse(&b.version)
+                .cmp(&Version::parse(&a.version))
+                .then_with(|| (a.platform == "ruby").cmp(&(b.platform == "ruby")))
+        });
+        gems
+    };
+
+    let root = candidates(name)
+        .into_iter()
+        .find(|gem| match version {
+            Some(version) => Version::parse(&gem.version) == Version::parse(version),
+            None => !Version::parse(&gem.version).is_prerelease(),
+        })
+        .ok_or_else(|| match version {
+            Some(version) => miette!("{} {} is not in any index", name, version),
+            None => miette!("{} has no releases in any index", name),
+        })?;
+
+    let mut chosen = BTreeMap::<String, &Gem>::new();
+    // Who required each chosen gem and how, for conflict messages
+    let mut required = BTreeMap::<String, Vec<(String, String)>>::new();
+    chosen.insert(root.name.clone(), root);
+    let mut queue = VecDeque::from([root]);
+    while let Some(gem) = queue.pop_front() {
+        for dependency in &gem.dependencies {
+            let requirements = dependency
+                .requirements
+                .iter()
+                .map(|requirement| Requirement::parse(requirement))
+                .collect::<Vec<_>>();
+            let requirement = dependency.requirements.join(", ");
+            required
+                .entry(dependency.name.clone())
+                .or_default()
+                .push((gem.full_name.clone(), requirement.clone()));
+
+            if let Some(existing) = chosen.get(&dependency.name) {
+                let existing_version = Version::parse(&existing.version);
+                if !requirements.iter().all(|r| r.matches(&existing_version)) {
+                    let wanted_by = required[&dependency.name]
+                        .iter()
+                        .map(|(by, requirement)| format!("{} ({})", by, requirement))
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    bail!(
+                        "Can't resolve {}: picked {} but it is required by {}",
+                        dependency.name,
+                        existing.version,
+                        wanted_by
+                    );
+                }
+                continue;
+            }
+
+            let allow_prerelease = requirements.iter().any(Requirement::is_prerelease);
+            let picked = candidates(&dependency.name).into_iter().find(|candidate| {
+                let version = Version::parse(&candidate.version);
+                (allow_prerelease || !version.is_prerelease())
+                    && requirements.iter().all(|r| r.matches(&version))
+            });
+            let Some(picked) = picked else {
+                bail!(
+                    "No version of {} in any index meets {}, required by {}",
+                    dependency.name,
+                    requirement,
+                    gem.full_name
+                );
+            };
+            chosen.insert(dependency.name.clone(), picked);
+            queue.push_back(picked);
+        }
+    }
+
+    let gems = chosen.into_values().cloned().collect::<Vec<_>>();
+    let unstored = gems
+        .iter()
+        .filter(|gem| !gem.stored)
+        .map(|gem| gem.full_name.as_str())
+        .collect::<Vec<_>>();
+    if !unstored.is_empty() {
+        bail!(
+            "Not stored yet, run an update first: {}",
+            unstored.join(", ")
+        );
+    }
+    Ok(gems)
+}
+
+/// Writes each of `gems` as `<full_name>.gem`, into a tar archive if `out`
+/// ends in `.tar` and into a directory otherwise
+pub fn write<T: Store>(store: &T, gems: &[Gem], out: &Path) -> miette::Result<()> {
+    if out.extension().is_some_and(|extension| extension == "tar") {
+        return write_tar(store, gems, out);
+    }
+    std::fs::create_dir_all(out)
+        .map_err(|e| miette!("Failed to create directory {}: {}", out.display(), e))?;
+    for gem in gems {
+        let path = out.join(format!("{}.gem", gem.full_name));
+        let blob = read_gem(store, gem)?;
+        std::fs::write(&path, blob)
+            .map_err(|e| miette!("Failed to write {}: {}", path.display(), e))?;
+    }
+    Ok(())
+}
+
+/// Like `export`, builds the archive under a `.partial` name and renames it
+/// into place once complete
+fn write_tar<T: Store>(store: &T, gems: &[Gem], out: &Path) -> miette::Result<()> {
+    let partial = partial_path(out);
+    let tar_error = |e| miette!("Failed to write {}: {}", partial.display(), e);
+    let result = (|| {
+        let file = File::create(&partial).map_err(tar_error)?;
+        let mut builder = tar::Builder::new(BufWriter::new(file));
+        for gem in gems {
+            let blob = read_gem(store, gem)?;
+            let mut header = tar::Header::new_gnu();
+            header.set_size(blob.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(
+                    &mut header,
+                    format!("{}.gem", gem.full_name),
+                    blob.as_slice(),
+                )
+                .map_err(tar_error)?;
+        }
+        builder
+            .into_inner()
+            .map_err(tar_error)?
+            .into_inner()
+            .map_err(|e| tar_error(e.into_error()))?
+            .sync_all()
+            .map_err(tar_error)?;
+        std::fs::rename(&partial, out).map_err(|e| {
+            miette!(
+                "Failed to rename {} to {}: {}",
+                partial.display(),
+                out.display(),
+                e
+            )
+        })
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&partial);
+    }
+    result
+}
+
+fn read_gem<T: Store>(store: &T, gem: &Gem) -> miette::Result<Vec<u8>> {
+    store
+        .get_blob(&gem.package_integrity)
+        .map_err(|e| e.wrap_err(format!("Failed to bundle {}", gem.full_name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{Namespace, parse_info_line};
+
+    #[test]
+    fn only_stored_versions_are_picked() {
+        let mut namespace = Namespace::default();
+        for (i, (version, stored)) in [("1.0.0", true), ("2.0.0", false)].into_iter().enumerate() {
+            let line = format!("{} |checksum:{:064x}", version, i + 1);
+            let mut gem = parse_info_line("rack", &line).unwrap();
+            gem.stored = stored;
+            namespace.versions.insert(gem.full_name.clone(), gem);
+        }
+        let mut index = Index::default();
+        index.gems.insert("rack".to_string(), namespace);
+        let indices = [index];
+
+        let gems = resolve(&indices, "rack", None, None).unwrap();
+        assert_eq!(gems.len(), 1);
+        assert_eq!(gems[0].version, "1.0.0");
+        assert!(resolve(&indices, "rack", Some("2.0.0"), None).is_err());
+    }
+}
@@ -0,0 +1,66 @@
+//! The SHA-256 of every stored gem, for pinning against the mirror with
+//! Bundler's `CHECKSUMS` lockfile section.
+
+use std::{collections::BTreeMap, fmt::Write as _, path::Path};
+
+use miette::miette;
+
+use crate::storage::{Store, content_address};
+
+#[derive(Debug, Clone, Copy, Default, clap::ValueEnum)]
+pub enum ChecksumsFormat {
+    /// An object mapping each full name to `sha256=<hex>`
+    #[default]
+    Json,
+    /// A `CHECKSUMS` section as Bundler writes it in `Gemfile.lock`, with a
+    /// `name (version[-platform]) sha256=<hex>` line per gem
+    Bundler,
+}
+
+/// Writes the checksum of every stored gem to `out`. A full name present in
+/// several indices is taken from the first.
+pub fn export_checksums<T: Store>(
+    store: &T,
+    out: &Path,
+    format: ChecksumsFormat,
+) -> miette::Result<()> {
+    // Full name → (the `name (version)` Bundler lists, checksum)
+    let mut checksums = BTreeMap::<String, (String, String)>::new();
+    for index in store.list_indices()?.iter() {
+        for namespace in index.gems.values() {
+            for gem in namespace.versions.values().filter(|gem| gem.stored) {
+                checksums.entry(gem.full_name.clone()).or_insert_with(|| {
+                    let version = match gem.platform.as_str() {
+                        "ruby" => gem.version.clone(),
+                        platform => format!("{}-{}", gem.version, platform),
+                    };
+                    let (_, hex) = content_address(&gem.package_integrity).to_hex();
+                    (
+                        format!("{} ({})", gem.name, version),
+                        format!("sha256={}", hex),
+                    )
+                });
+            }
+        }
+    }
+
+    let contents = match format {
+        ChecksumsFormat::Json => {
+            let json = checksums
+                .into_iter()
+                .map(|(full_name, (_, checksum))| (full_name, checksum))
+                .collect::<BTreeMap<_, _>>();
+            serde_json::to_string_pretty(&json)
+                .map_err(|e| miette!("Failed to serialize checksums: {}", e))?
+        }
+        ChecksumsFormat::Bundler => {
+            let lines = checksums.into_values().collect::<BTreeMap<_, _>>();
+            let mut section = String::from("CHECKSUMS\n");
+            for (spec, checksum) in lines {
+                let _ = writeln!(section, "  {} {}", spec, checksum);
+            }
+            section
+        }
+    };
+    std::fs::write(out, contents).map_err(|e| miette!("Failed to write {}: {}", out.display(), e))
+}
@@ -0,0 +1,113 @@
+//! Auditing a replica against its primary, e.g. after an `export` and
+//! `import`: which blobs, namespaces and versions only one of two stores
+//! has, and which versions they disagree on the contents of.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::storage::{FsStore, Store, content_address};
+
+/// The differences between two stores, `here` and `there`
+#[derive(Debug, Default)]
+pub struct Comparison {
+    /// Blobs in one store's content directory but not the other's
+    pub blobs_only_here: Vec<String>,
+    pub blobs_only_there: Vec<String>,
+    /// Gem names listed by one store's indices but not the other's
+    pub namespaces_only_here: Vec<String>,
+    pub namespaces_only_there: Vec<String>,
+    /// Full names of versions listed by one store but not the other, leaving
+    /// out those of namespaces only one store has
+    pub versions_only_here: Vec<String>,
+    pub versions_only_there: Vec<String>,
+    /// Versions listed by both stores with different package integrities
+    pub conflicts: Vec<Conflict>,
+}
+
+#[derive(Debug)]
+pub struct Conflict {
+    pub full_name: String,
+    pub here: Vec<String>,
+    pub there: Vec<String>,
+}
+
+impl Comparison {
+    pub fn is_empty(&self) -> bool {
+        self.blobs_only_here.is_empty()
+            && self.blobs_only_there.is_empty()
+            && self.namespaces_only_here.is_empty()
+            && self.namespaces_only_there.is_empty()
+            && self.versions_only_here.is_empty()
+            && self.versions_only_there.is_empty()
+            && self.conflicts.is_empty()
+    }
+}
+
+/// The package integrities listed for each full name across every index of
+/// `store`, by namespace
+type Listing = BTreeMap<String, BTreeMap<String, BTreeSet<String>>>;
+
+fn listing(store: &FsStore) -> miette::Result<Listing> {
+    let mut listing = Listing::new();
+    for index in store.list_indices()?.iter() {
+        for (name, namespace) in &index.gems {
+            let versions = listing.entry(name.clone()).or_default();
+            for gem in namespace.versions.values() {
+                versions
+                    .entry(gem.full_name.clone())
+                    .or_default()
+                    .insert(content_address(&gem.package_integrity).to_string());
+            }
+        }
+    }
+    Ok(listing)
+}
+
+fn blobs(store: &FsStore) -> miette::Result<BTreeSet<String>> {
+    Ok(store
+        .content_entries()?
+        .into_iter()
+        .map(|(integrity, _)| integrity.to_string())
+        .collect())
+}
+
+/// Compares the indices and blobs of `here` and `there`
+pub fn compare(here: &FsStore, there: &FsStore) -> miette::Result<Comparison> {
+    let mut comparison = Comparison::default();
+
+    let (blobs_here, blobs_there) = (blobs(here)?, blobs(there)?);
+    comparison.blobs_only_here = blobs_here.difference(&blobs_there).cloned().collect();
+    comparison.blobs_only_there = blobs_there.difference(&blobs_here).cloned().collect();
+
+    let (listing_here, listing_there) = (listing(here)?, listing(there)?);
+    for (name, versions_here) in &listing_here {
+        let Some(versions_there) = listing_there.get(name) else {
+            comparison.namespaces_only_here.push(name.clone());
+            continue;
+        };
+        for (full_name, integrities_here) in versions_here {
+            match versions_there.get(full_name) {
+                None => comparison.versions_only_here.push(full_name.clone()),
+                Some(integrities_there) if integrities_there != integrities_here => {
+                    comparison.conflicts.push(Conflict {
+                        full_name: full_name.clone(),
+                        here: integrities_here.iter().cloned().collect(),
+                        there: integrities_there.iter().cloned().collect(),
+                    });
+                }
+                Some(_) => {}
+            }
+        }
+        comparison.versions_only_there.extend(
+            versions_there
+                .keys()
+                .filter(|full_name| !versions_here.contains_key(*full_name))
+                .cloned(),
+        );
+    }
+    comparison.namespaces_only_there = listing_there
+        .keys()
+        .filter(|name| !listing_here.contains_key(*name))
+        .cloned()
+        .collect();
+    Ok(comparison)
+}
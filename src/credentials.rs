@@ -0,0 +1,160 @@
+//! API keys shared with RubyGems, so sources that `gem push` already has
+//! credentials for need no separate `add-index --token`.
+//!
+//! Keys come from `$GEM_HOST_API_KEY` or `~/.gem/credentials`, a flat YAML
+//! mapping of `:rubygems_api_key` and source URLs to keys. The environment
+//! key is only sent to `$RUBYGEMS_HOST`, and `:rubygems_api_key` is a push
+//! key, so it is only sent with `--send-rubygems-api-key`.
+
+use std::path::PathBuf;
+
+use miette::miette;
+
+use crate::http::source_path_under;
+
+const RUBYGEMS_HOST: &str = "https://rubygems.org";
+
+#[derive(Debug, Default)]
+pub struct GemCredentials {
+    /// `$RUBYGEMS_HOST`, or rubygems.org
+    rubygems_host: String,
+    /// `$GEM_HOST_API_KEY`, which RubyGems prefers over the credentials file
+    env_key: Option<String>,
+    /// The `:rubygems_api_key`, only ever sent to `rubygems_host`, and only
+    /// with `--send-rubygems-api-key`
+    rubygems_key: Option<String>,
+    /// Keys for other sources, by the URL they are listed under
+    hosts: Vec<(String, String)>,
+}
+
+impl GemCredentials {
+    /// Reads the environment and `~/.gem/credentials`, if it exists,
+    /// keeping `:rubygems_api_key` only if `send_rubygems_key`
+    pub fn load(send_rubygems_key: bool) -> miette::Result<Self> {
+        let mut credentials = Self {
+            rubygems_host: std::env::var("RUBYGEMS_HOST")
+                .ok()
+                .filter(|host| !host.is_empty())
+                .unwrap_or_else(|| RUBYGEMS_HOST.to_string()),
+            env_key: std::env::var("GEM_HOST_API_KEY")
+                .ok()
+                .filter(|key| !key.is_empty()),
+            ..Self::default()
+        };
+        let Some(path) = credentials_path().filter(|path| path.is_file()) else {
+            return Ok(credentials);
+        };
+        let contents = std::fs::read_to_string(&path)
+            .map_err(|e| miette!("Failed to read {}: {}", path.display(), e))?;
+        for line in contents.lines() {
+            let Some((key, value)) = parse_line(line) else {
+                continue;
+            };
+            if key == "rubygems_api_key" {
+                credentials.rubygems_key = Some(value).filter(|_| send_rubygems_key);
+            } else if key.contains("://") {
+                credentials.hosts.push((key, value));
+            }
+        }
+        Ok(credentials)
+    }
+
+    /// The API key to send to `source`, following RubyGems' precedence:
+    /// `$GEM_HOST_API_KEY` for `$RUBYGEMS_HOST`, then the key listed for the
+    /// longest URL `source` is under, then `:rubygems_api_key` for
+    /// `$RUBYGEMS_HOST`
+    pub fn api_key(&self, source: &str) -> Option<&str> {
+        let source = url::Url::parse(source).ok()?;
+        let rubygems = source_path_under(&self.rubygems_host, &source).is_some();
+        if rubygems && let Some(key) = &self.env_key {
+            return Some(key);
+        }
+        let listed = self
+            .hosts
+            .iter()
+            .filter_map(|(url, key)| Some((source_path_under(url, &source)?, key)))
+            .max_by_key(|(matched, _)| *matched);
+        if let Some((_, key)) = listed {
+            return Some(key);
+        }
+        self.rubygems_key.as_deref().filter(|_| rubygems)
+    }
+}
+
+fn credentials_path() -> Option<PathBuf> {
+    let home = std::env::var_os("HOME")?;
+    Some(PathBuf::from(home).join(".gem").join("credentials"))
+}
+
+/// Parses a `key: value` line of the flat YAML RubyGems writes, where
+/// symbol keys have a leading `:` and either side may be quoted
+fn parse_line(line: &str) -> Option<(String, String)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') || line == "---" {
+        return None;
+    }
+    // URL keys contain `:` themselves, so split on the `: ` separator
+    let (key, value) = line.rsplit_once(": ")?;
+    let unquote = |s: &str| s.trim().trim_matches(|c| c == '"' || c == '\'').to_string();
+    let key = unquote(key.trim().trim_start_matches(':'));
+    let value = unquote(value);
+    Some((key, value)).filter(|(key, value)| !key.is_empty() && !value.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credentials() -> GemCredentials {
+        GemCredentials {
+            rubygems_host: RUBYGEMS_HOST.to_string(),
+            env_key: None,
+            rubygems_key: None,
+            hosts: vec![
+                ("https://gems.example.com/".to_string(), "outer".to_string()),
+                (
+                    "https://gems.example.com/private/".to_string(),
+                    "inner".to_string(),
+                ),
+            ],
+        }
+    }
+
+    #[test]
+    fn listed_keys_match_by_path_prefix() {
+        let credentials = credentials();
+        for (source, key) in [
+            ("https://gems.example.com/private/", Some("inner")),
+            ("https://gems.example.com/private/sub", Some("inner")),
+            ("https://gems.example.com/privateer/", Some("outer")),
+            ("https://gems.example.com", Some("outer")),
+            ("http://gems.example.com/", None),
+            ("https://gems.example.com.evil/", None),
+            ("https://gems.example.com:8443/", None),
+        ] {
+            assert_eq!(credentials.api_key(source), key, "{source}");
+        }
+    }
+
+    #[test]
+    fn rubygems_keys_stay_on_rubygems_host() {
+        let credentials = GemCredentials {
+            env_key: Some("env".to_string()),
+            rubygems_key: Some("push".to_string()),
+            ..credentials()
+        };
+        assert_eq!(credentials.api_key("https://rubygems.org/"), Some("env"));
+        assert_eq!(
+            credentials.api_key("https://gems.example.com/private/"),
+            Some("inner")
+        );
+        assert_eq!(credentials.api_key("https://other.example.com/"), None);
+
+        let credentials = GemCredentials {
+            env_key: None,
+            ..credentials
+        };
+        assert_eq!(credentials.api_key("https://rubygems.org/"), Some("push"));
+        assert_eq!(credentials.api_key("https://other.example.com/"), None);
+    }
+}
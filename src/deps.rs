@@ -0,0 +1,60 @@
+//! Dumps of the dependency graph parsed from the compact index, for offline
+//! resolvers and visualization.
+
+use std::{collections::BTreeMap, fmt::Write as _, path::Path};
+
+use miette::miette;
+
+use crate::storage::{Dependency, Store};
+
+#[derive(Debug, Clone, Copy, Default, clap::ValueEnum)]
+pub enum DepsFormat {
+    /// An object mapping each full name to its dependencies
+    #[default]
+    Json,
+    /// A Graphviz digraph with an edge from each full name to the gems it
+    /// depends on, labelled with the requirements
+    Dot,
+}
+
+/// Writes the full name → dependencies graph of every index to `out`. A full
+/// name present in several indices is taken from the first.
+pub fn export_deps<T: Store>(store: &T, out: &Path, format: DepsFormat) -> miette::Result<()> {
+    let mut graph = BTreeMap::<String, Vec<Dependency>>::new();
+    for index in store.list_indices()?.iter() {
+        for namespace in index.gems.values() {
+            for gem in namespace.versions.values() {
+                graph
+                    .entry(gem.full_name.clone())
+                    .or_insert_with(|| gem.dependencies.clone());
+            }
+        }
+    }
+
+    let contents = match format {
+        DepsFormat::Json => serde_json::to_string_pretty(&graph)
+            .map_err(|e| miette!("Failed to serialize dependencies: {}", e))?,
+        DepsFormat::Dot => to_dot(&graph),
+    };
+    std::fs::write(out, contents).map_err(|e| miette!("Failed to write {}: {}", out.display(), e))
+}
+
+fn to_dot(graph: &BTreeMap<String, Vec<Dependency>>) -> String {
+    let mut dot = String::from("digraph dependencies {\n");
+    for (full_name, dependencies) in graph {
+        if dependencies.is_empty() {
+            let _ = writeln!(dot, "  {:?};", full_name);
+        }
+        for dependency in dependencies {
+            let _ = writeln!(
+                dot,
+                "  {:?} -> {:?} [label={:?}];",
+                full_name,
+                dependency.name,
+                dependency.requirements.join(", ")
+            );
+        }
+    }
+    dot.push_str("}\n");
+    dot
+}
@@ -0,0 +1,296 @@
+//! Portable archives of a store, for moving a mirror between machines.
+//!
+//! An archive is a tar file containing `indices.json` followed by every
+//! stored blob under `blobs/<algorithm>/<hex>`, optionally compressed with
+//! gzip or zstd.
+
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use flate2::{Compression as GzLevel, read::GzDecoder, write::GzEncoder};
+use miette::{bail, miette};
+use ssri::Integrity;
+
+use crate::storage::{Index, Store, read_indices, write_indices};
+
+const INDICES_ENTRY: &str = "indices.json";
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// How an exported archive is compressed.
+///
+/// `.gem` files are already gzipped tarballs, so expect only a few percent
+/// savings on them; `indices.json` and the `metadata.gz` sidecars are where
+/// compression pays off.
+#[derive(Debug, Clone, Copy, Default, clap::ValueEnum)]
+pub enum CompressionFormat {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+/// A `CompressionFormat` with its level, if one was chosen
+#[derive(Debug, Clone, Copy)]
+pub enum Compression {
+    None,
+    Gzip { level: Option<u32> },
+    Zstd { level: Option<i32> },
+}
+
+impl Compression {
+    /// Checks `level` against the range `format` accepts: 0 to 9 for gzip
+    /// and 1 to 22 for zstd
+    pub fn new(format: CompressionFormat, level: Option<u32>) -> miette::Result<Self> {
+        match (format, level) {
+            (CompressionFormat::None, Some(_)) => {
+                bail!("--compression-level needs --compression gzip or zstd")
+            }
+            (CompressionFormat::None, None) => Ok(Self::None),
+            (CompressionFormat::Gzip, Some(level)) if level > 9 => {
+                bail!("gzip levels go from 0 to 9, not {}", level)
+            }
+            (CompressionFormat::Gzip, level) => Ok(Self::Gzip { level }),
+            (CompressionFormat::Zstd, Some(level)) if !(1..=22).contains(&level) => {
+                bail!("zstd levels go from 1 to 22, not {}", level)
+            }
+            (CompressionFormat::Zstd, level) => Ok(Self::Zstd {
+                level: level.map(|level| level as i32),
+            }),
+        }
+    }
+}
+
+/// Writes the archive to `<out>.partial` and only renames it to `out` once
+/// it is complete, so an interrupted export never leaves behind a truncated
+/// archive under the final name.
+pub fn export<T: Store>(store: &T, out: &Path, compression: Compression) -> miette::Result<()> {
+    let partial = partial_path(out);
+    if partial.exists() {
+        eprintln!("Removing stale {}", partial.display());
+        std::fs::remove_file(&partial)
+            .map_err(|e| miette!("Failed to remove {}: {}", partial.display(), e))?;
+    }
+
+    let result = write_partial(store, &partial, compression).and_then(|()| {
+        std::fs::rename(&partial, out).map_err(|e| {
+            miette!(
+                "Failed to rename {} to {}: {}",
+                partial.display(),
+                out.display(),
+                e
+            )
+        })
+    });
+    if result.is_err() {
+        let _ = std::fs::remove_file(&partial);
+    }
+    result
+}
+
+pub(crate) fn partial_path(out: &Path) -> PathBuf {
+    let mut name = out.file_name().unwrap_or_default().to_os_string();
+    name.push(".partial");
+    out.with_file_name(name)
+}
+
+fn write_partial<T: Store>(
+    store: &T,
+    partial: &Path,
+    compression: Compression,
+) -> miette::Result<()> {
+    let file = File::create(partial)
+        .map_err(|e| miette!("Failed to create {}: {}", partial.display(), e))?;
+    let writer = BufWriter::new(file);
+    let finish_error = |e| miette!("Failed to write {}: {}", partial.display(), e);
+    let writer = match compression {
+        Compression::None => write_archive(store, writer)?,
+        Compression::Gzip { level } => {
+            let level = level.map(GzLevel::new).unwrap_or_default();
+            write_archive(store, GzEncoder::new(writer, level))?
+                .finish()
+                .map_err(finish_error)?
+        }
+        Compression::Zstd { level } => {
+            let encoder =
+                zstd::Encoder::new(writer, level.unwrap_or(zstd::DEFAULT_COMPRESSION_LEVEL))
+                    .map_err(finish_error)?;
+            write_archive(store, encoder)?
+                .finish()
+                .map_err(finish_error)?
+        }
+    };
+    writer
+        .into_inner()
+        .map_err(|e| finish_error(e.into_error()))?
+        .sync_all()
+        .map_err(finish_error)
+}
+
+fn write_archive<T: Store, W: Write>(store: &T, writer: W) -> miette::Result<W> {
+    let tar_error = |e| miette!("Failed to write archive: {}", e);
+    let mut builder = tar::Builder::new(writer);
+
+    let indices = store.list_indices()?;
+    let mut json = Vec::new();
+    write_indices(&mut json, &indices)?;
+    append(&mut builder, INDICES_ENTRY, &json).map_err(tar_error)?;
+
+    let mut written = HashSet::new();
+    for index in indices.iter() {
+        for namespace in index.gems.values() {
+            for gem in namespace.versions.values().filter(|gem| gem.stored) {
+                for integrity in [Some(&gem.package_integrity), gem.metadata_gz_blob()]
+                    .into_iter()
+                    .flatten()
+                {
+                    let (algorithm, hex) = integrity.to_hex();
+                    let path = format!("blobs/{}/{}", algorithm, hex);
+                    if !written.insert(path.clone()) {
+                        continue;
+                    }
+                    let blob = store.get_blob(integrity)?;
+                    append(&mut builder, &path, &blob).map_err(tar_error)?;
+                }
+            }
+        }
+    }
+
+    builder.into_inner().map_err(tar_error)
+}
+
+fn append<W: Write>(builder: &mut tar::Builder<W>, path: &str, data: &[u8]) -> std::io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append_data(&mut header, path, data)
+}
+
+/// Loads an archive produced by `export` into `store`, detecting its
+/// compression from the first bytes of the file.
+pub fn import<T: Store>(store: &mut T, input: &Path) -> miette::Result<()> {
+    let file =
+        File::open(input).map_err(|e| miette!("Failed to open {}: {}", input.display(), e))?;
+    let mut reader = BufReader::new(file);
+    let mut magic = [0u8; 4];
+    let read = reader
+        .by_ref()
+        .take(4)
+        .read(&mut magic)
+        .map_err(|e| miette!("Failed to read {}: {}", input.display(), e))?;
+    let reader = std::io::Cursor::new(magic[..read].to_vec()).chain(reader);
+
+    let imported = if magic.starts_with(&GZIP_MAGIC) {
+        read_archive(store, GzDecoder::new(reader))?
+    } else if magic == ZSTD_MAGIC {
+        let decoder = zstd::Decoder::new(reader)
+            .map_err(|e| miette!("Failed to read {}: {}", input.display(), e))?;
+        read_archive(store, decoder)?
+    } else {
+        read_archive(store, reader)?
+    };
+
+    store.with_indices(|_, indices| {
+        for theirs in imported {
+            let Some(ours) = indices.iter_mut().find(|i| i.source == theirs.source) else {
+                indices.push(theirs);
+                continue;
+            };
+            for (name, mut namespace) in theirs.gems {
+                // Prefer our copy of each version unless only theirs is stored
+                if let Some(existing) = ours.gems.get(&name) {
+                    namespace.merge(existing);
+                }
+                ours.gems.insert(name, namespace);
+            }
+        }
+        Ok(())
+    })?;
+    Ok(())
+}
+
+fn read_archive<T: Store, R: Read>(store: &T, reader: R) -> miette::Result<Vec<Index>> {
+    let tar_error = |e| miette!("Failed to read archive: {}", e);
+    let mut archive = tar::Archive::new(reader);
+    let mut indices = None;
+    for entry in archive.entries().map_err(tar_error)? {
+        let mut entry = entry.map_err(tar_error)?;
+        let path = entry
+            .path()
+            .map_err(tar_error)?
+            .to_string_lossy()
+            .into_owned();
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data).map_err(tar_error)?;
+
+        if path == INDICES_ENTRY {
+            indices = Some(read_indices(data.as_slice())?);
+        } else if let Some(blob) = path.strip_prefix("blobs/") {
+            let (algorithm, hex) = blob
+                .split_once('/')
+                .ok_or_else(|| miette!("Invalid blob path in archive: {}", path))?;
+            let algorithm = algorithm
+                .parse()
+                .map_err(|e| miette!("Invalid blob path in archive {}: {}", path, e))?;
+            let expected = Integrity::from_hex(hex, algorithm)
+                .map_err(|e| miette!("Invalid blob path in archive {}: {}", path, e))?;
+            store
+                .store_blob_expecting(&data, &expected)
+                .map_err(|e| e.wrap_err(format!("Corrupt blob {} in archive", path)))?;
+        }
+    }
+    indices.ok_or_else(|| miette!("Archive is missing {}", INDICES_ENTRY))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{MemoryStore, Namespace, parse_info_line};
+
+    #[test]
+    fn zstd_archives_round_trip() {
+        let mut store = MemoryStore::default();
+        let blob = store.store_blob(b"rack 3.0.0").unwrap();
+        let (_, hex) = blob.to_hex();
+        let mut gem = parse_info_line("rack", &format!("3.0.0 |checksum:{}", hex)).unwrap();
+        gem.stored = true;
+        store
+            .with_indices(|_, indices| {
+                let mut namespace = Namespace::default();
+                namespace.versions.insert(gem.full_name.clone(), gem);
+                let mut index = Index::default();
+                index.source = "https://a.example".to_string();
+                index.gems.insert("rack".to_string(), namespace);
+                indices.push(index);
+                Ok(())
+            })
+            .unwrap();
+
+        let out =
+            std::env::temp_dir().join(format!("gem-mirror-test-{}.tar.zst", std::process::id()));
+        let compression = Compression::new(CompressionFormat::Zstd, Some(19)).unwrap();
+        export(&store, &out, compression).unwrap();
+        let archive = std::fs::read(&out).unwrap();
+        assert!(archive.starts_with(&ZSTD_MAGIC));
+
+        let mut imported = MemoryStore::default();
+        import(&mut imported, &out).unwrap();
+        let _ = std::fs::remove_file(&out);
+        assert_eq!(imported.get_blob(&blob).unwrap(), b"rack 3.0.0");
+        let indices = imported.list_indices().unwrap();
+        assert!(indices[0].gems["rack"].versions["rack-3.0.0"].stored);
+    }
+
+    #[test]
+    fn compression_levels_are_checked() {
+        assert!(Compression::new(CompressionFormat::Gzip, Some(10)).is_err());
+        assert!(Compression::new(CompressionFormat::Zstd, Some(0)).is_err());
+        assert!(Compression::new(CompressionFormat::Zstd, Some(22)).is_ok());
+        assert!(Compression::new(CompressionFormat::None, Some(1)).is_err());
+    }
+}
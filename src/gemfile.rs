@@ -0,0 +1,181 @@
+//! Just enough `Gemfile` parsing to find the gems a project declares.
+//!
+//! This does not evaluate Ruby: it only recognizes `gem "name", ...` lines,
+//! which covers the overwhelming majority of real-world Gemfiles.
+
+/// Returns the names of every gem declared in `contents`, in order, without
+/// duplicates. `source`, `group`, `platforms`, etc. lines are ignored, but the
+/// gems declared inside their blocks are still included.
+pub fn parse_gemfile(contents: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    for line in contents.lines() {
+        let line = line.trim();
+        let Some(rest) = line
+            .strip_prefix("gem ")
+            .or_else(|| line.strip_prefix("gem("))
+        else {
+            continue;
+        };
+        let rest = rest.trim_start();
+        let Some(quote) = rest.chars().next().filter(|c| *c == '"' || *c == '\'') else {
+            continue;
+        };
+        let Some((name, _)) = rest[1..].split_once(quote) else {
+            continue;
+        };
+        if !name.is_empty() && !names.iter().any(|n| n == name) {
+            names.push(name.to_string());
+        }
+    }
+    names
+}
+
+/// A gem pinned by a `Gemfile.lock`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockedSpec {
+    pub name: String,
+    pub version: String,
+    pub platform: String,
+}
+
+impl LockedSpec {
+    /// The `name-version[-platform]` the gem is stored under
+    pub fn full_name(&self) -> String {
+        if self.platform == "ruby" {
+            format!("{}-{}", self.name, self.version)
+        } else {
+            format!("{}-{}-{}", self.name, self.version, self.platform)
+        }
+    }
+}
+
+/// Returns the specs of every `GEM` section in a `Gemfile.lock`. Gems from
+/// `PATH` and `GIT` sections aren't served by a gem source, so they're
+/// skipped.
+pub fn parse_lockfile(contents: &str) -> Vec<LockedSpec> {
+    let mut specs = Vec::new();
+    let mut in_gem_section = false;
+    for line in contents.lines() {
+        if !line.starts_with(' ') {
+            in_gem_section = line.trim() == "GEM";
+            continue;
+        }
+        // Specs are indented by four spaces, their dependencies by six
+        let Some(spec) = line.strip_prefix("    ") else {
+            continue;
+        };
+        if !in_gem_section || spec.starts_with(' ') {
+            continue;
+        }
+        let Some((name, rest)) = spec.split_once(" (") else {
+            continue;
+        };
+        let Some(version) = rest.strip_suffix(')') else {
+            continue;
+        };
+        let (version, platform) = version.split_once('-').unwrap_or((version, "ruby"));
+        specs.push(LockedSpec {
+            name: name.to_string(),
+            version: version.to_string(),
+            platform: platform.to_string(),
+        });
+    }
+    specs
+}
+
+/// Returns the platforms listed in a `Gemfile.lock`'s `PLATFORMS` section,
+/// which the project was resolved for
+pub fn parse_lockfile_platforms(contents: &str) -> Vec<String> {
+    let mut platforms = Vec::new();
+    let mut in_platforms_section = false;
+    for line in contents.lines() {
+        if !line.starts_with(' ') {
+            in_platforms_section = line.trim() == "PLATFORMS";
+            continue;
+        }
+        if in_platforms_section && !line.trim().is_empty() {
+            platforms.push(line.trim().to_string());
+        }
+    }
+    platforms
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gemfile_names_in_either_quotes() {
+        let gemfile = r#"
+source "https://rubygems.org"
+
+gem "rails", "~> 7.0"
+gem 'puma'
+gem("bootsnap", require: false)
+gem "rails" # listed twice
+"#;
+        assert_eq!(parse_gemfile(gemfile), ["rails", "puma", "bootsnap"]);
+    }
+
+    #[test]
+    fn gemfile_groups_and_comments() {
+        let gemfile = r#"
+# gem "commented-out"
+group :development, :test do
+  gem "rspec" # inline comment
+  # gem "pry"
+end
+platforms :jruby do
+  gem 'jdbc'
+end
+gemspec
+"#;
+        assert_eq!(parse_gemfile(gemfile), ["rspec", "jdbc"]);
+    }
+
+    const LOCKFILE: &str = "\
+GIT
+  remote: https://github.com/rails/rails.git
+  specs:
+    rails (7.1.0.alpha)
+
+GEM
+  remote: https://rubygems.org/
+  specs:
+    nokogiri (1.15.0-x86_64-linux)
+      racc (~> 1.4)
+    racc (1.7.1)
+    rack (3.0.0)
+
+PATH
+  remote: .
+  specs:
+    mine (0.1.0)
+";
+
+    #[test]
+    fn lockfile_specs_come_from_gem_sections_only() {
+        let specs = parse_lockfile(LOCKFILE);
+        let full_names = specs.iter().map(LockedSpec::full_name).collect::<Vec<_>>();
+        assert_eq!(
+            full_names,
+            ["nokogiri-1.15.0-x86_64-linux", "racc-1.7.1", "rack-3.0.0"]
+        );
+        assert_eq!(specs[0].platform, "x86_64-linux");
+        assert_eq!(specs[1].platform, "ruby");
+    }
+
+    #[test]
+    fn lockfile_platforms() {
+        assert!(parse_lockfile_platforms(LOCKFILE).is_empty());
+
+        let lockfile = format!(
+            "{}\nPLATFORMS\n  arm64-darwin-22\n  ruby\n\nBUNDLED WITH\n   2.4.10\n",
+            LOCKFILE
+        );
+        assert_eq!(
+            parse_lockfile_platforms(&lockfile),
+            ["arm64-darwin-22", "ruby"]
+        );
+    }
+}
@@ -0,0 +1,837 @@
+//! The HTTP client shared by everything that talks to gem sources.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::{
+        Arc, Condvar, Mutex, RwLock,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use miette::miette;
+use reqwest::{
+    Method, StatusCode,
+    blocking::{Client, RequestBuilder, Response},
+    dns::{Addrs, Name, Resolve, Resolving},
+    header::{AUTHORIZATION, HeaderMap, LOCATION, RETRY_AFTER},
+    redirect,
+};
+use serde::Serialize;
+
+use crate::{
+    credentials::GemCredentials,
+    merge_log::MergeLog,
+    statsd::StatsD,
+    storage::{Auth, UpdateOptions},
+};
+
+const MAX_REDIRECTS: usize = 10;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// Start waiting for the rate limit window to reset once a host reports this
+/// few requests remaining, leaving headroom for requests already in flight
+const RATE_LIMIT_RESERVE: u64 = 5;
+const MAX_RATE_LIMIT_WAIT: Duration = Duration::from_secs(300);
+/// How long an idle pooled connection is kept around for reuse
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+const TCP_KEEPALIVE: Duration = Duration::from_secs(60);
+
+/// Sent by `serve` with the served store's identifier on every response, so
+/// an update of that store can tell it is being pointed at itself
+pub const STORE_ID_HEADER: &str = "X-Gem-Mirror-Store";
+
+/// Builds the client. HTTP/2 is negotiated via ALPN where the server supports
+/// it, so the many small `/info` requests share a single connection, unless
+/// `http1_only` is set.
+pub fn client(options: &UpdateOptions) -> miette::Result<Client> {
+    let mut builder = Client::builder()
+        .redirect(redirect_policy(options.auth_redirect_host.clone()))
+        .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+        .tcp_keepalive(TCP_KEEPALIVE);
+    let prefer = match (options.prefer_ipv4, options.prefer_ipv6) {
+        (true, _) => Some(IpFamily::V4),
+        (_, true) => Some(IpFamily::V6),
+        _ => None,
+    };
+    if options.dns_cache || prefer.is_some() {
+        builder = builder.dns_resolver(Arc::new(Resolver {
+            cache: options.dns_cache.then(Default::default),
+            prefer,
+        }));
+    }
+    for ResolveOverride { host, ip } in &options.resolve {
+        // The port is ignored in favor of the one in the URL
+        builder = builder.resolve(host, SocketAddr::new(*ip, 0));
+    }
+    builder = if options.http1_only {
+        builder.http1_only()
+    } else {
+        builder.http2_adaptive_window(true)
+    };
+    builder
+        .build()
+        .map_err(|e| miette!("Failed to build HTTP client: {}", e))
+}
+
+/// A `--resolve host:ip` override, like curl's, for pointing a source at a
+/// staging mirror without touching DNS
+#[derive(Debug, Clone, Serialize)]
+pub struct ResolveOverride {
+    pub host: String,
+    pub ip: IpAddr,
+}
+
+impl FromStr for ResolveOverride {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, ip) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected HOST:IP, got {:?}", s))?;
+        let ip = ip
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse()
+            .map_err(|e| format!("invalid IP address {:?}: {}", ip, e))?;
+        Ok(Self {
+            host: host.to_string(),
+            ip,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IpFamily {
+    V4,
+    V6,
+}
+
+type DnsCache = Arc<Mutex<HashMap<String, Vec<SocketAddr>>>>;
+
+/// Resolves hosts with the system resolver, optionally caching each host's
+/// addresses for the rest of the run, so a large sync doesn't hammer flaky
+/// DNS, and putting one address family first.
+///
+/// Connections try the addresses in order and only race the other family
+/// after a short delay, so preferring IPv4 where IPv6 is broken avoids
+/// waiting on it, while a host with only IPv6 addresses still works.
+struct Resolver {
+    cache: Option<DnsCache>,
+    prefer: Option<IpFamily>,
+}
+
+impl Resolve for Resolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+        let cache = self.cache.clone();
+        let prefer = self.prefer;
+        Box::pin(async move {
+            let cached = cache
+                .as_ref()
+                .and_then(|cache| cache.lock().unwrap().get(&host).cloned());
+            let mut addrs = match cached {
+                Some(addrs) => addrs,
+                None => {
+                    let addrs = tokio::net::lookup_host((host.as_str(), 0))
+                        .await?
+                        .collect::<Vec<_>>();
+                    if let Some(cache) = &cache {
+                        cache.lock().unwrap().insert(host, addrs.clone());
+                    }
+                    addrs
+                }
+            };
+            if let Some(prefer) = prefer {
+                addrs.sort_by_key(|addr| addr.is_ipv4() != (prefer == IpFamily::V4));
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Why reading a response failed, and whether trying again might help
+pub enum ReadError {
+    /// A transient problem, such as a truncated body
+    Retry(miette::Report),
+    /// A problem that another attempt won't fix, such as a `404`
+    Fail(miette::Report),
+}
+
+/// Sends requests for an update run, retrying transient failures and
+/// limiting per-host concurrency.
+pub struct Fetcher {
+    client: Client,
+    limiter: HostLimiter,
+    rate_limits: RateLimits,
+    breakers: CircuitBreakers,
+    max_retries: u32,
+    verbose: bool,
+    retries: RetryCounters,
+    /// How many responses of each status code were received, plus
+    /// `timeout`s and other connection `error`s, across all attempts
+    statuses: Mutex<BTreeMap<String, usize>>,
+    /// Credentials for each source URL, sent with requests under it
+    auth: RwLock<Vec<(String, Auth)>>,
+    statsd: StatsD,
+    merge_log: MergeLog,
+    /// When `--timeout-total` runs out, counted from the start of the run
+    deadline: Option<Instant>,
+    /// API keys for sources with no credentials of their own
+    credentials: GemCredentials,
+    /// The identifier of the store being updated, to recognise a source
+    /// that is a `serve` of that same store
+    store_id: Option<String>,
+}
+
+impl Fetcher {
+    pub fn new(options: &UpdateOptions) -> miette::Result<Self> {
+        Ok(Self {
+            client: client(options)?,
+            limiter: HostLimiter::new(options.workers_per_host),
+            rate_limits: Default::default(),
+            breakers: CircuitBreakers::new(
+                options.circuit_breaker_threshold,
+                options.circuit_breaker_cooldown,
+            ),
+            max_retries: options.max_retries,
+            verbose: options.verbose,
+            retries: Default::default(),
+            statuses: Default::default(),
+            auth: Default::default(),
+            statsd: StatsD::new(options.statsd.as_deref(), &options.statsd_prefix)?,
+            merge_log: MergeLog::new(options.merge_log.as_deref())?,
+            deadline: options.timeout_total.map(|limit| Instant::now() + limit),
+            credentials: GemCredentials::load(options.send_rubygems_api_key)?,
+            store_id: None,
+        })
+    }
+
+    pub fn with_store_id(mut self, store_id: Option<String>) -> Self {
+        self.store_id = store_id;
+        self
+    }
+
+    pub fn store_id(&self) -> Option<&str> {
+        self.store_id.as_deref()
+    }
+
+    /// Whether the run has used up `--timeout-total` and should stop
+    /// starting new work
+    pub fn out_of_time(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// Whether `--verbose` asked for debugging details
+    pub fn verbose(&self) -> bool {
+        self.verbose
+    }
+
+    pub fn statsd(&self) -> &StatsD {
+        &self.statsd
+    }
+
+    pub fn merge_log(&self) -> &MergeLog {
+        &self.merge_log
+    }
+
+    /// Sends `auth` with every later request to a URL under `source`,
+    /// falling back to an API key RubyGems has for it
+    pub fn authenticate(&self, source: &str, auth: Option<&Auth>) {
+        let auth = auth.cloned().or_else(|| {
+            let key = self.credentials.api_key(source)?;
+            Some(Auth::ApiKey {
+                key: key.to_string(),
+            })
+        });
+        let mut sources = self.auth.write().unwrap();
+        sources.retain(|(s, _)| s != source);
+        if let Some(auth) = auth {
+            sources.push((source.to_string(), auth));
+        }
+    }
+
+    /// The credentials of the most specific source `url` is under
+    fn auth_for(&self, url: &str) -> Option<Auth> {
+        let url = url::Url::parse(url).ok()?;
+        let sources = self.auth.read().unwrap();
+        sources
+            .iter()
+            .filter_map(|(source, auth)| Some((source_path_under(source, &url)?, auth)))
+            .max_by_key(|(matched, _)| *matched)
+            .map(|(_, auth)| auth.clone())
+    }
+
+    fn request(&self, method: Method, url: &str, auth: Option<&Auth>) -> RequestBuilder {
+        let request = self.client.request(method, url);
+        match auth {
+            Some(Auth::Basic { username, password }) => {
+                request.basic_auth(username, password.as_ref())
+            }
+            Some(Auth::Bearer { token }) => request.bearer_auth(token),
+            Some(Auth::ApiKey { key }) => request.header(AUTHORIZATION, key),
+            None => request,
+        }
+    }
+
+    /// Sends one attempt at a request to `url` with the credentials of its
+    /// source, following redirects to `--auth-redirect-host`s with the same
+    /// credentials, which reqwest would drop on leaving the source's origin
+    fn execute(&self, method: &Method, url: &str, headers: HeaderMap) -> reqwest::Result<Response> {
+        let auth = self.auth_for(url);
+        let mut response = self
+            .request(method.clone(), url, auth.as_ref())
+            .headers(headers.clone())
+            .send();
+        for _ in 0..MAX_REDIRECTS {
+            let Some(target) = response.as_ref().ok().and_then(auth_redirect_target) else {
+                break;
+            };
+            if self.verbose && auth.is_some() {
+                eprintln!("Sending the credentials for {} on to {}", url, target);
+            }
+            response = self
+                .request(method.clone(), target.as_str(), auth.as_ref())
+                .headers(headers.clone())
+                .send();
+        }
+        response
+    }
+
+    /// GETs `url` and hands the response to `read`.
+    ///
+    /// Connection errors, `429`s and `5xx`s are retried with exponential
+    /// backoff (honoring `Retry-After`), as is anything `read` reports as
+    /// `ReadError::Retry`, up to `--max-retries` times.
+    pub fn get<T>(
+        &self,
+        url: &str,
+        read: impl FnMut(Response) -> Result<T, ReadError>,
+    ) -> miette::Result<T> {
+        self.get_with_headers(url, HeaderMap::new(), read)
+    }
+
+    /// Like `get`, but sends `headers` with every attempt
+    pub fn get_with_headers<T>(
+        &self,
+        url: &str,
+        headers: HeaderMap,
+        read: impl FnMut(Response) -> Result<T, ReadError>,
+    ) -> miette::Result<T> {
+        self.send(Method::GET, url, || headers.clone(), read)
+    }
+
+    /// Like `get_with_headers`, but asks `headers` for the headers of each
+    /// attempt, so a retry can ask for only what the last one didn't get
+    pub fn get_with_headers_from<T>(
+        &self,
+        url: &str,
+        headers: impl FnMut() -> HeaderMap,
+        read: impl FnMut(Response) -> Result<T, ReadError>,
+    ) -> miette::Result<T> {
+        self.send(Method::GET, url, headers, read)
+    }
+
+    /// Like `get`, but sends a `HEAD` request
+    pub fn head<T>(
+        &self,
+        url: &str,
+        read: impl FnMut(Response) -> Result<T, ReadError>,
+    ) -> miette::Result<T> {
+        self.send(Method::HEAD, url, HeaderMap::new, read)
+    }
+
+    fn send<T>(
+        &self,
+        method: Method,
+        url: &str,
+        mut headers: impl FnMut() -> HeaderMap,
+        mut read: impl FnMut(Response) -> Result<T, ReadError>,
+    ) -> miette::Result<T> {
+        let mut attempt = 0;
+        loop {
+            if !self.breakers.allow(url) {
+                return Err(miette!("Skipped {}: its host is unreachable", url));
+            }
+            let (result, retry_after) = {
+                self.rate_limits.wait(url, self.verbose);
+                let _permit = self.limiter.acquire(url);
+                let response = self.execute(&method, url, headers());
+                // A 429 is the host answering, only rate limited, which
+                // `rate_limits` waits out; it doesn't count towards an outage
+                self.breakers.record(
+                    url,
+                    response
+                        .as_ref()
+                        .is_ok_and(|resp| !resp.status().is_server_error()),
+                );
+                let status = match &response {
+                    Ok(resp) => resp.status().as_str().to_string(),
+                    Err(e) if e.is_timeout() => "timeout".to_string(),
+                    Err(_) => "error".to_string(),
+                };
+                *self.statuses.lock().unwrap().entry(status).or_default() += 1;
+                if let Ok(resp) = &response {
+                    self.rate_limits.observe(url, resp);
+                }
+                match response {
+                    Err(e) => (
+                        Err(ReadError::Retry(miette!("Failed to fetch {}: {}", url, e))),
+                        None,
+                    ),
+                    Ok(resp) if is_transient(resp.status()) => (
+                        Err(ReadError::Retry(miette!(
+                            "Failed to fetch {}: {}",
+                            url,
+                            resp.status()
+                        ))),
+                        retry_after(&resp),
+                    ),
+                    Ok(resp) => (read(resp), None),
+                }
+            };
+
+            let error = match result {
+                Ok(value) => return Ok(value),
+                Err(ReadError::Fail(error)) => return Err(error),
+                Err(ReadError::Retry(error)) => error,
+            };
+            if attempt >= self.max_retries {
+                if attempt > 0 {
+                    self.retries.exhausted.fetch_add(1, Ordering::Relaxed);
+                }
+                return Err(error);
+            }
+
+            attempt += 1;
+            if attempt == 1 {
+                self.retries.requests.fetch_add(1, Ordering::Relaxed);
+            }
+            self.retries.attempts.fetch_add(1, Ordering::Relaxed);
+            let delay = retry_after.unwrap_or_else(|| backoff(attempt));
+            if self.verbose {
+                eprintln!(
+                    "Retrying {} (attempt {} of {}) in {:?}: {}",
+                    url, attempt, self.max_retries, delay, error
+                );
+            }
+            std::thread::sleep(delay);
+        }
+    }
+
+    /// The number of responses with each status code so far
+    pub fn status_counts(&self) -> BTreeMap<String, usize> {
+        self.statuses.lock().unwrap().clone()
+    }
+
+    /// The hosts whose circuit breaker is open, with how many requests to
+    /// each were skipped because of it
+    pub fn unreachable_hosts(&self) -> Vec<(String, usize)> {
+        self.breakers.open()
+    }
+
+    pub fn retry_summary(&self) -> RetrySummary {
+        RetrySummary {
+            requests_retried: self.retries.requests.load(Ordering::Relaxed),
+            retries: self.retries.attempts.load(Ordering::Relaxed),
+            requests_failed: self.retries.exhausted.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct RetryCounters {
+    requests: AtomicUsize,
+    attempts: AtomicUsize,
+    exhausted: AtomicUsize,
+}
+
+/// How much retrying an update run needed
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct RetrySummary {
+    /// Requests that needed at least one retry
+    pub requests_retried: usize,
+    /// The total number of retry attempts
+    pub retries: usize,
+    /// Requests that still failed after exhausting their retries
+    pub requests_failed: usize,
+}
+
+/// Reads a compact index response body as text.
+///
+/// The compact index is ASCII, so invalid UTF-8 means the response was
+/// mangled along the way and is worth fetching again.
+pub fn read_text(url: &str, resp: Response) -> Result<String, ReadError> {
+    let bytes = resp
+        .bytes()
+        .map_err(|e| ReadError::Retry(miette!("Failed to read {}: {}", url, e)))?;
+    String::from_utf8(bytes.to_vec()).map_err(|e| {
+        ReadError::Retry(miette!(
+            "Invalid UTF-8 in response from {} at byte {}",
+            url,
+            e.utf8_error().valid_up_to()
+        ))
+    })
+}
+
+fn is_transient(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+fn backoff(attempt: u32) -> Duration {
+    INITIAL_BACKOFF
+        .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+        .min(MAX_BACKOFF)
+}
+
+/// Parses a `Retry-After` header given in seconds, falling back to when the
+/// rate limit window resets
+fn retry_after(resp: &Response) -> Option<Duration> {
+    let seconds = resp
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|v| v.to_str().ok());
+    match seconds.and_then(|s| s.trim().parse().ok()) {
+        Some(s) => Some(Duration::from_secs(s).min(MAX_BACKOFF)),
+        None => rate_limit_reset(resp).map(|reset| reset.min(MAX_BACKOFF)),
+    }
+}
+
+/// Reads `RateLimit-Reset` (or `X-RateLimit-Reset`), which servers send
+/// either as seconds until the window resets or as a Unix timestamp
+fn rate_limit_reset(resp: &Response) -> Option<Duration> {
+    let reset: u64 = rate_limit_header(resp, "ratelimit-reset")?;
+    // Anything this large can only be a timestamp, not a delay
+    if reset > 1_000_000_000 {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+        Some(Duration::from_secs(reset).saturating_sub(now))
+    } else {
+        Some(Duration::from_secs(reset))
+    }
+}
+
+fn rate_limit_header(resp: &Response, name: &str) -> Option<u64> {
+    let headers = resp.headers();
+    let value = headers
+        .get(name)
+        .or_else(|| headers.get(format!("x-{}", name)))?;
+    value.to_str().ok()?.trim().parse().ok()
+}
+
+/// Slows down ahead of a host's rate limit: once a response says only a few
+/// requests remain in the window, requests to that host wait until it resets
+/// rather than running into `429`s.
+#[derive(Default)]
+struct RateLimits {
+    paused_until: Mutex<HashMap<String, Instant>>,
+}
+
+impl RateLimits {
+    fn wait(&self, url: &str, verbose: bool) {
+        let Some(host) = host(url) else {
+            return;
+        };
+        let until = self.paused_until.lock().unwrap().get(&host).copied();
+        if let Some(until) = until {
+            let delay = until.saturating_duration_since(Instant::now());
+            if !delay.is_zero() {
+                if verbose {
+                    eprintln!(
+                        "Waiting {:?} for the rate limit of {} to reset",
+                        delay, host
+                    );
+                }
+                std::thread::sleep(delay);
+            }
+        }
+    }
+
+    fn observe(&self, url: &str, resp: &Response) {
+        let Some(remaining) = rate_limit_header(resp, "ratelimit-remaining") else {
+            return;
+        };
+        if remaining > RATE_LIMIT_RESERVE {
+            return;
+        }
+        let (Some(host), Some(reset)) = (host(url), rate_limit_reset(resp)) else {
+            return;
+        };
+        let until = Instant::now() + reset.min(MAX_RATE_LIMIT_WAIT);
+        let mut paused_until = self.paused_until.lock().unwrap();
+        let entry = paused_until.entry(host).or_insert(until);
+        *entry = (*entry).max(until);
+    }
+}
+
+/// Stops sending requests to a host after `threshold` consecutive failed
+/// attempts, so a dead source fails fast instead of every request to it
+/// running through its retries. After `cooldown`, a single request is let
+/// through to probe the host: success closes the breaker again, failure
+/// keeps it open for another `cooldown`.
+///
+/// Only connection errors and 5xx responses are failures. A rate limited
+/// host is up, so a 429 counts as a success.
+struct CircuitBreakers {
+    /// `None` disables the breakers
+    threshold: Option<u32>,
+    cooldown: Duration,
+    hosts: Mutex<HashMap<String, Breaker>>,
+}
+
+#[derive(Default)]
+struct Breaker {
+    consecutive_failures: u32,
+    /// Set while the breaker is open; requests are refused until then
+    open_until: Option<Instant>,
+    /// Whether the probe of a half-open breaker is in flight
+    probing: bool,
+    /// Requests refused while open
+    skipped: usize,
+}
+
+impl CircuitBreakers {
+    fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold: Some(threshold).filter(|threshold| *threshold > 0),
+            cooldown,
+            hosts: Default::default(),
+        }
+    }
+
+    /// Whether a request to `url` may be sent
+    fn allow(&self, url: &str) -> bool {
+        let (Some(_), Some(host)) = (self.threshold, host(url)) else {
+            return true;
+        };
+        let mut hosts = self.hosts.lock().unwrap();
+        let breaker = hosts.entry(host).or_default();
+        let Some(open_until) = breaker.open_until else {
+            return true;
+        };
+        if Instant::now() < open_until || breaker.probing {
+            breaker.skipped += 1;
+            return false;
+        }
+        breaker.probing = true;
+        true
+    }
+
+    fn record(&self, url: &str, success: bool) {
+        let (Some(threshold), Some(host)) = (self.threshold, host(url)) else {
+            return;
+        };
+        let mut hosts = self.hosts.lock().unwrap();
+        let breaker = hosts.entry(host.clone()).or_default();
+        if success {
+            if breaker.open_until.is_some() {
+                eprintln!("{} is reachable again", host);
+            }
+            *breaker = Breaker::default();
+            return;
+        }
+        breaker.consecutive_failures += 1;
+        if breaker.probing || breaker.consecutive_failures == threshold {
+            if !breaker.probing {
+                eprintln!(
+                    "{} failed {} times in a row; pausing requests to it for {:?}",
+                    host, threshold, self.cooldown
+                );
+            }
+            breaker.probing = false;
+            breaker.open_until = Some(Instant::now() + self.cooldown);
+        }
+    }
+
+    fn open(&self) -> Vec<(String, usize)> {
+        let hosts = self.hosts.lock().unwrap();
+        let mut open = hosts
+            .iter()
+            .filter(|(_, breaker)| breaker.open_until.is_some())
+            .map(|(host, breaker)| (host.clone(), breaker.skipped))
+            .collect::<Vec<_>>();
+        open.sort();
+        open
+    }
+}
+
+/// Where a redirect that `redirect_policy` left for `Fetcher::execute` to
+/// follow points. Only such redirects reach it: reqwest follows the rest.
+fn auth_redirect_target(resp: &Response) -> Option<url::Url> {
+    if !matches!(resp.status().as_u16(), 301 | 302 | 303 | 307 | 308) {
+        return None;
+    }
+    let location = resp.headers().get(LOCATION)?.to_str().ok()?;
+    resp.url().join(location).ok()
+}
+
+/// How much of `url`'s path `source` covers, if `url` is under it: same
+/// scheme, host and port, and a path that is the source's or continues it
+/// past a `/`, so `https://a.com/x` covers neither `https://a.com.evil/x`
+/// nor `https://a.com/xy`
+pub(crate) fn source_path_under(source: &str, url: &url::Url) -> Option<usize> {
+    let source = url::Url::parse(source).ok()?;
+    if source.scheme() != url.scheme()
+        || source.host_str() != url.host_str()
+        || source.port_or_known_default() != url.port_or_known_default()
+    {
+        return None;
+    }
+    let prefix = source.path().trim_end_matches('/');
+    let rest = url.path().strip_prefix(prefix)?;
+    (rest.is_empty() || rest.starts_with('/')).then_some(prefix.len())
+}
+
+pub(crate) fn host(url: &str) -> Option<String> {
+    url::Url::parse(url).ok()?.host_str().map(str::to_string)
+}
+
+/// Follows redirects like reqwest's default policy, but makes redirects that
+/// leave the original origin visible, since reqwest strips sensitive headers
+/// when crossing origins.
+///
+/// A redirect that `forwards_credentials` allows isn't followed here: the
+/// response goes back to `Fetcher::execute`, which follows it itself with
+/// the credentials of the source the request was for.
+fn redirect_policy(auth_hosts: Vec<String>) -> redirect::Policy {
+    redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() > MAX_REDIRECTS {
+            return attempt.error("too many redirects");
+        }
+        if let Some(previous) = attempt.previous().last()
+            && previous.origin() != attempt.url().origin()
+        {
+            eprintln!(
+                "Redirected across origins: {} -> {}",
+                previous,
+                attempt.url()
+            );
+            if forwards_credentials(previous, attempt.url(), &auth_hosts) {
+                return attempt.stop();
+            }
+        }
+        attempt.follow()
+    })
+}
+
+/// Whether a redirect from `from` to `to` on another origin carries the
+/// source's credentials along: `to` must be one of `auth_hosts`, over
+/// `https` or the scheme `from` used, so credentials are never downgraded
+/// to cleartext
+fn forwards_credentials(from: &url::Url, to: &url::Url, auth_hosts: &[String]) -> bool {
+    to.host_str()
+        .is_some_and(|host| auth_hosts.iter().any(|allowed| allowed == host))
+        && (to.scheme() == "https" || to.scheme() == from.scheme())
+}
+
+/// Caps the number of in-flight requests to any single host, independent of
+/// the total number of workers.
+pub struct HostLimiter {
+    per_host: Option<usize>,
+    in_flight: Mutex<HashMap<String, usize>>,
+    released: Condvar,
+}
+
+impl HostLimiter {
+    pub fn new(per_host: Option<usize>) -> Self {
+        Self {
+            per_host,
+            in_flight: Default::default(),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Blocks until a request to `url`'s host is allowed, returning a permit
+    /// that frees the slot when dropped.
+    pub fn acquire(&self, url: &str) -> HostPermit<'_> {
+        let host = self.per_host.and_then(|_| host(url));
+        if let (Some(limit), Some(host)) = (self.per_host, &host) {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            while in_flight.get(host).copied().unwrap_or(0) >= limit.max(1) {
+                in_flight = self.released.wait(in_flight).unwrap();
+            }
+            *in_flight.entry(host.clone()).or_default() += 1;
+        }
+        HostPermit {
+            limiter: self,
+            host,
+        }
+    }
+}
+
+pub struct HostPermit<'a> {
+    limiter: &'a HostLimiter,
+    host: Option<String>,
+}
+
+impl Drop for HostPermit<'_> {
+    fn drop(&mut self) {
+        if let Some(host) = &self.host {
+            let mut in_flight = self.limiter.in_flight.lock().unwrap();
+            if let Some(count) = in_flight.get_mut(host) {
+                *count -= 1;
+            }
+            self.limiter.released.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn covered(source: &str, url: &str) -> Option<usize> {
+        source_path_under(source, &url::Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn sources_cover_only_their_own_origin_and_path() {
+        assert!(covered("https://a.com", "https://a.com/gems/x.gem").is_some());
+        assert!(covered("https://a.com/", "https://a.com:443/info/x").is_some());
+        assert!(covered("https://a.com", "https://a.com.evil/gems/x.gem").is_none());
+        assert!(covered("https://a.com", "https://a.com:8443/gems/x.gem").is_none());
+        assert!(covered("https://a.com", "http://a.com/gems/x.gem").is_none());
+        assert!(covered("https://a.com/private", "https://a.com/private/info/x").is_some());
+        assert!(covered("https://a.com/private", "https://a.com/private2/info/x").is_none());
+    }
+
+    #[test]
+    fn credentials_follow_redirects_only_to_allowed_hosts_without_downgrading() {
+        let hosts = ["cdn.example".to_string()];
+        let forwards = |from: &str, to: &str| {
+            forwards_credentials(
+                &url::Url::parse(from).unwrap(),
+                &url::Url::parse(to).unwrap(),
+                &hosts,
+            )
+        };
+        assert!(forwards(
+            "https://a.com/gems/x.gem",
+            "https://cdn.example/x.gem"
+        ));
+        assert!(forwards(
+            "http://a.com/gems/x.gem",
+            "http://cdn.example/x.gem"
+        ));
+        assert!(forwards(
+            "http://a.com/gems/x.gem",
+            "https://cdn.example/x.gem"
+        ));
+        assert!(!forwards(
+            "https://a.com/gems/x.gem",
+            "http://cdn.example/x.gem"
+        ));
+        assert!(!forwards(
+            "https://a.com/gems/x.gem",
+            "https://evil.example/x.gem"
+        ));
+    }
+
+    #[test]
+    fn longer_source_paths_match_more() {
+        let url = "https://a.com/org/private/info/x";
+        assert!(covered("https://a.com/org/private", url) > covered("https://a.com/org", url));
+    }
+}
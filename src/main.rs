@@ -1,16 +1,41 @@
 #![feature(impl_trait_in_assoc_type)]
 
 use std::{
-    io::stdout,
-    path::PathBuf,
+    collections::{BTreeMap, BTreeSet, HashSet},
+    fs::File,
+    io::{BufWriter, Write, stdout},
+    net::TcpListener,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, UNIX_EPOCH},
 };
 
 use clap::Parser;
 use miette::{Result, bail, miette};
+use output::{Output, OutputFormat};
 use serde_json::json;
 use storage::Store as _;
+use version::Version;
 
+mod bundle;
+mod checksums;
+mod compare;
+mod credentials;
+mod deps;
+mod export;
+mod gemfile;
+mod http;
+mod md5;
+mod merge_log;
+mod output;
+pub mod platform;
+mod progress;
+mod serve;
+mod statsd;
 pub mod storage;
+mod time;
+pub mod version;
+mod watch;
 
 #[derive(Debug, clap::Parser)]
 struct Command {
@@ -21,68 +46,1144 @@ struct Command {
     /// The path to the store
     #[clap(long, default_value = "store")]
     store_path: PathBuf,
+
+    /// The path to store gem blobs in, if different from the store path
+    #[clap(long)]
+    blob_cache_dir: Option<PathBuf>,
+
+    /// The hashes to compute for each stored blob; SHA-256 is always included
+    #[clap(long, value_delimiter = ',', default_value = "sha256")]
+    integrity_algos: Vec<ssri::Algorithm>,
+
+    /// Keep a per-namespace shard of each index up to date for fast lookups
+    #[clap(long)]
+    shard_namespaces: bool,
+
+    /// Skip rehashing blobs when reading them; faster, but corruption on disk
+    /// goes unnoticed until the next `verify`
+    #[clap(long)]
+    no_verify_on_read: bool,
+
+    /// How informational commands print their results. `each-gem`,
+    /// `latest` and `list-stale` default to `json`, the rest to `human`,
+    /// which for `print-config` is pretty-printed JSON
+    #[clap(long, value_enum, global = true)]
+    output_format: Option<OutputFormat>,
 }
 
 #[derive(Debug, clap::Subcommand)]
 /// The command to run
 enum CommandType {
     /// Update the store
-    Update,
+    Update {
+        #[clap(flatten)]
+        options: storage::UpdateOptions,
+    },
+    /// Update the store on an interval until interrupted, as a daemon
+    Watch {
+        /// How long to wait between updates, e.g. `1h` or `15m`
+        #[clap(long, value_parser = storage::parse_duration)]
+        interval: Duration,
+        /// Wait up to this much longer, chosen at random each time, so that
+        /// many mirrors don't hit the source at once
+        #[clap(long, value_parser = storage::parse_duration)]
+        jitter: Option<Duration>,
+        #[clap(flatten)]
+        options: storage::UpdateOptions,
+    },
+    /// Serve the store as a compact index that Bundler and RubyGems can
+    /// install from
+    Serve {
+        /// The address to listen on
+        #[clap(long, default_value = "127.0.0.1:9292")]
+        bind: String,
+        /// Also update the store on this interval, serving the refreshed
+        /// indices once each update finishes
+        #[clap(long, value_parser = storage::parse_duration)]
+        refresh_interval: Option<Duration>,
+        /// Wait up to this much longer between refreshes, chosen at random
+        #[clap(long, value_parser = storage::parse_duration, requires = "refresh_interval")]
+        jitter: Option<Duration>,
+        /// Stamp `/versions` with this `YYYY-MM-DDTHH:MM:SSZ` timestamp, e.g.
+        /// to match an upstream's, rather than with when the served versions
+        /// last changed
+        #[clap(long, value_name = "TIMESTAMP", value_parser = time::parse_timestamp)]
+        versions_created_at: Option<String>,
+        #[clap(flatten)]
+        options: storage::UpdateOptions,
+    },
+    /// Mirror exactly the gems listed by full name in a file
+    FetchList {
+        /// A file with one `name-version[-platform]` per line
+        file: PathBuf,
+        #[clap(flatten)]
+        options: storage::UpdateOptions,
+    },
+    /// Store the gem whose package has the given checksum
+    FetchByIntegrity {
+        /// A `sha256=<hex>` lockfile checksum, bare SHA-256 hex, or SRI string
+        integrity: String,
+        /// Only look for the gem in this index
+        #[clap(long)]
+        hint_source: Option<String>,
+        #[clap(flatten)]
+        options: storage::UpdateOptions,
+    },
     /// Add a new index
-    AddIndex { url: String },
-    /// Print the path to each gem on a line
-    EachGem {},
+    AddIndex {
+        url: String,
+        /// The username to authenticate to the source with
+        #[clap(long)]
+        username: Option<String>,
+        /// The password to authenticate to the source with
+        #[clap(long, requires = "username")]
+        password: Option<String>,
+        /// A bearer token to authenticate to the source with
+        #[clap(long, conflicts_with = "username")]
+        token: Option<String>,
+        /// Only mirror gems whose names match this glob; may be repeated
+        #[clap(long = "filter", value_name = "GLOB")]
+        filters: Vec<String>,
+        /// Keep this index's blobs in this directory instead of the store's
+        /// blob cache, e.g. on another disk. Gems any index already has are
+        /// still not downloaded again
+        #[clap(long, value_name = "PATH")]
+        blob_root: Option<PathBuf>,
+        /// Replace the configuration of an existing index, keeping its gems
+        /// and, without `--blob-root`, its blob root. The blob root can't
+        /// change while the index has stored gems
+        #[clap(long)]
+        clobber: bool,
+    },
+    /// Print the path to each stored gem on a line, or its details with
+    /// `--output-format json`
+    EachGem {
+        /// Collect all gems and print them sorted by this key
+        #[clap(long, value_enum)]
+        sort: Option<SortKey>,
+        /// Write to this file instead of stdout, replacing it only once
+        /// every gem has been written
+        #[clap(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Check that the blobs of every stored gem are present
+    Verify {
+        /// Read and rehash every blob instead of only checking it exists
+        #[clap(long)]
+        deep: bool,
+        /// Mark gems with missing or corrupt blobs as not stored
+        #[clap(long)]
+        repair: bool,
+        /// Rehash only this many randomly chosen blobs, as a cheap spot-check
+        #[clap(long, value_name = "N")]
+        sample: Option<usize>,
+        /// Seed the choice of sampled blobs, to repeat a spot-check
+        #[clap(long, requires = "sample")]
+        seed: Option<u64>,
+        /// Rehash every blob, like `--deep`, except those a deep verify found
+        /// intact within this long, e.g. `7d`, which are only checked to be
+        /// present
+        #[clap(
+            long,
+            value_name = "DURATION",
+            value_parser = storage::parse_duration,
+            conflicts_with = "sample"
+        )]
+        skip_recent: Option<Duration>,
+    },
+    /// Exit successfully if the store is usable, for liveness probes
+    Healthcheck {},
+    /// Check that every gem pinned by a `Gemfile.lock` is stored
+    CheckLock {
+        /// The lockfile to check
+        path: PathBuf,
+    },
+    /// Rewrite `indices.json` in the current format
+    Migrate {},
+    /// Regenerate the per-namespace shards from `indices.json`
+    Shard {},
+    /// Delete blobs that no gem in any index refers to
+    Prune {
+        /// Only print what would be deleted
+        #[clap(long)]
+        dry_run: bool,
+        /// Prune even if the store directory lacks the `.gem-mirror-store`
+        /// marker
+        #[clap(long)]
+        force: bool,
+    },
+    /// Remove abandoned temp files and repair the blob cache index
+    Compact {
+        /// Also re-hash every blob and remove any that are corrupt
+        #[clap(long)]
+        verify: bool,
+    },
+    /// Print every version of one gem in each index, reading only its
+    /// shards when `--shard-namespaces` keeps them current
+    Show {
+        /// The gem's name
+        name: String,
+    },
+    /// Print the newest version of every gem
+    Latest {
+        /// Consider prerelease versions too
+        #[clap(long)]
+        include_prerelease: bool,
+        /// Report the newest version for each platform, not just `ruby`
+        #[clap(long)]
+        all_platforms: bool,
+    },
+    /// Print how many stored versions each platform has and how much space
+    /// their blobs take, largest first
+    ListPlatforms {},
+    /// Print the gem blobs more than one index refers to, with those indices
+    /// and the bytes saved by storing each blob once, most saved first
+    ReportDuplicatesAcrossIndices {},
+    /// Print the namespaces no update has confirmed as current recently,
+    /// e.g. because their source stopped responding
+    ListStale {
+        /// How long ago a namespace must have last been confirmed, like
+        /// `7d` or `12h`
+        #[clap(long, value_parser = storage::parse_duration)]
+        older_than: Duration,
+    },
+    /// Write the indices and all stored blobs to a tar archive
+    Export {
+        /// The archive to write
+        out: PathBuf,
+        /// How to compress the archive
+        #[clap(long, value_enum, default_value_t)]
+        compression: export::CompressionFormat,
+        /// The compression level: for gzip from 0 (fastest) to 9 (smallest),
+        /// for zstd from 1 to 22
+        #[clap(long)]
+        compression_level: Option<u32>,
+    },
+    /// Write the dependency graph of every gem in the indices
+    ExportDeps {
+        /// The file to write
+        out: PathBuf,
+        #[clap(long, value_enum, default_value_t)]
+        format: deps::DepsFormat,
+    },
+    /// Write the SHA-256 of every stored gem, e.g. for Bundler's `CHECKSUMS`
+    ExportChecksums {
+        /// The file to write
+        out: PathBuf,
+        #[clap(long, value_enum, default_value_t)]
+        format: checksums::ChecksumsFormat,
+    },
+    /// Write a gem and its runtime dependencies as `.gem` files, for
+    /// `gem install --local` on a machine without network access
+    Bundle {
+        /// The gem to bundle
+        name: String,
+        /// The version to bundle, instead of the newest release
+        version: Option<String>,
+        /// The directory to write to, or a tar archive if it ends in `.tar`
+        #[clap(long, short)]
+        out: PathBuf,
+        /// Prefer gems built for this platform over pure-Ruby ones
+        #[clap(long)]
+        platform: Option<platform::Platform>,
+    },
+    /// Load an archive written by `export` into the store
+    Import {
+        /// The archive to read
+        input: PathBuf,
+    },
+    /// Remove a gem version from every index for good, e.g. for a takedown
+    Purge {
+        /// The `name-version[-platform]` to remove
+        full_name: String,
+        /// Also delete its blobs, unless another version refers to them
+        #[clap(long)]
+        delete_blob: bool,
+        /// Purge even if the store directory lacks the `.gem-mirror-store`
+        /// marker
+        #[clap(long)]
+        force: bool,
+    },
+    /// Change the URL of an index in place, keeping everything mirrored from
+    /// it, for a source that moved
+    RewriteSource {
+        /// The current URL of the index
+        from: String,
+        /// The URL to sync it from from now on
+        to: String,
+    },
+    /// Report indices that mirror the same upstream, judged by how many of
+    /// their namespaces have identical `/info` checksums
+    DedupeSources {
+        /// The fraction of namespaces that must match, from 0 to 1
+        #[clap(long, default_value_t = 0.9, value_parser = storage::parse_threshold)]
+        threshold: f64,
+        /// Fold each duplicate into the earlier index and remove it
+        #[clap(long)]
+        merge: bool,
+    },
+    /// Compare the store with another, such as a replica, and fail if any
+    /// version both list has different contents
+    Compare {
+        /// The other store directory
+        other: PathBuf,
+        /// The other store's blob cache directory, if it isn't the store
+        /// directory
+        #[clap(long)]
+        other_blob_cache_dir: Option<PathBuf>,
+    },
+    /// Print the effective settings as JSON and exit, taking the same flags
+    /// as `update`
+    ///
+    /// The JSON is pretty-printed, or on one line with `--output-format json`.
+    PrintConfig {
+        #[clap(flatten)]
+        options: storage::UpdateOptions,
+    },
+    /// Print the gem-mirror version, the store's formats and size, and the
+    /// optional features built in, for bug reports; changes nothing
+    Info {},
+    /// Mark versions of a gem as not stored so the next update refetches them
+    Invalidate {
+        /// The gem name
+        name: String,
+        /// Only invalidate this version (all platforms); defaults to every version
+        version: Option<String>,
+        /// Also delete the invalidated blobs from the store
+        #[clap(long)]
+        delete_blob: bool,
+    },
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum SortKey {
+    /// By name, then version, then platform
+    Name,
+    /// By package integrity
+    Integrity,
+    /// By blob size on disk
+    Size,
+}
+
+/// The optional parts of gem-mirror built into this binary. None of them are
+/// behind cargo features yet, so this is the same for every build.
+const FEATURES: &[&str] = &["serve"];
+
+fn info(store_path: &Path, blob_cache_dir: &Path, format: OutputFormat) -> Result<()> {
+    let store = storage::StoreInfo::read(store_path, blob_cache_dir)?;
+    let mut human = format!("gem-mirror {}\n", env!("CARGO_PKG_VERSION"));
+    if store.exists {
+        human += &format!(
+            "Store: {} (id {})\n",
+            store.store_path.display(),
+            store.store_id.as_deref().unwrap_or("unknown")
+        );
+    } else {
+        human += &format!("Store: {} (doesn't exist)\n", store.store_path.display());
+    }
+    human += &format!(
+        "Blob cache: {} (cacache {})\n",
+        store.blob_cache_dir.display(),
+        storage::BLOB_FORMAT
+    );
+    human += &match store.indices_format {
+        Some(version) => format!(
+            "Indices format: {} (this build writes {})\n",
+            version,
+            storage::INDICES_FORMAT_VERSION
+        ),
+        None => format!(
+            "Indices format: none yet (this build writes {})\n",
+            storage::INDICES_FORMAT_VERSION
+        ),
+    };
+    human += &format!(
+        "Indices: {} ({} namespaces, {} versions)\n",
+        store.indices, store.namespaces, store.versions
+    );
+    human += &format!("Features: {}", FEATURES.join(", "));
+    let json = json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "blob_format": storage::BLOB_FORMAT,
+        "indices_format_supported": storage::INDICES_FORMAT_VERSION,
+        "features": FEATURES,
+        "store": store,
+    });
+    Output::new(format, stdout().lock()).record(&json, human)
 }
 
 fn main() -> Result<()> {
     let command = Command::try_parse().map_err(|e| miette!(e))?;
+    // These printed JSON lines before `--output-format` existed, and
+    // scripts reading them shouldn't break
+    let output_format = command.output_format.unwrap_or(match command.command {
+        CommandType::EachGem { .. }
+        | CommandType::Latest { .. }
+        | CommandType::ListStale { .. } => OutputFormat::Json,
+        _ => OutputFormat::Human,
+    });
 
-    let mut store = storage::FsStore::new(&command.store_path)?;
+    let blob_cache_dir = command
+        .blob_cache_dir
+        .as_ref()
+        .unwrap_or(&command.store_path);
+    // Opening the store would create it, and `info` must leave it as it is
+    if let CommandType::Info {} = command.command {
+        return info(&command.store_path, blob_cache_dir, output_format);
+    }
+    let mut store = storage::FsStore::new(&command.store_path, blob_cache_dir)?
+        .with_algorithms(command.integrity_algos.clone())
+        .with_sharding(command.shard_namespaces)
+        .with_verify_on_read(!command.no_verify_on_read);
 
     match command.command {
-        CommandType::Update => {
-            storage::update_store(store)?;
-        }
-        CommandType::AddIndex { url } => {
-            store.add_index(url)?;
-        }
-        CommandType::EachGem {} => {
-            for index in store.list_indices()? {
-                for namespace in index.gems.values() {
-                    for (_, gem) in namespace.versions.iter() {
-                        if !gem.stored {
-                            bail!("Gem {} is not stored", gem.full_name);
+        CommandType::Update { options } => match update(&mut store, &options) {
+            Err(e) if e.downcast_ref::<storage::TimedOut>().is_some() => {
+                eprintln!("{}", e);
+                std::process::exit(storage::TimedOut::EXIT_CODE);
+            }
+            result => result?,
+        },
+        CommandType::Watch {
+            interval,
+            jitter,
+            options,
+        } => {
+            watch::watch(interval, jitter, || update(&mut store, &options))?;
+        }
+        CommandType::Serve {
+            bind,
+            refresh_interval,
+            jitter,
+            versions_created_at,
+            options,
+        } => {
+            let listener = TcpListener::bind(&bind)
+                .map_err(|e| miette!("Failed to listen on {}: {}", bind, e))?;
+            println!("Listening on {}", bind);
+            let server = Arc::new(serve::Server::new(store.clone(), versions_created_at)?);
+            let Some(interval) = refresh_interval else {
+                return server.run(listener);
+            };
+            let serving = Arc::clone(&server);
+            std::thread::spawn(move || serving.run(listener));
+            // Requests keep being answered from the previous snapshot while
+            // an update runs, and the process exits once a signal stops this.
+            watch::watch(interval, jitter, || {
+                update(&mut store, &options)?;
+                server.reload()
+            })?;
+        }
+        CommandType::FetchList { file, options } => {
+            storage::fetch_list(store, &file, &options)?;
+        }
+        CommandType::FetchByIntegrity {
+            integrity,
+            hint_source,
+            options,
+        } => {
+            let integrity = storage::parse_checksum(&integrity)?;
+            let full_name = storage::fetch_by_integrity(
+                &mut store,
+                &integrity,
+                hint_source.as_deref(),
+                &options,
+            )?;
+            println!("Stored {}", full_name);
+        }
+        CommandType::AddIndex {
+            url,
+            username,
+            password,
+            token,
+            filters,
+            blob_root,
+            clobber,
+        } => {
+            let auth = match (username, token) {
+                (Some(username), _) => Some(storage::Auth::Basic { username, password }),
+                (None, Some(token)) => Some(storage::Auth::Bearer { token }),
+                (None, None) => None,
+            };
+            let blob_root = blob_root
+                .map(|blob_root| {
+                    storage::create_store_dir(&blob_root)?;
+                    // Relative paths would depend on where later commands run
+                    std::fs::canonicalize(&blob_root)
+                        .map_err(|e| miette!("Failed to resolve {}: {}", blob_root.display(), e))
+                })
+                .transpose()?;
+            let config = storage::IndexConfig {
+                auth,
+                filters,
+                blob_root,
+            };
+            store.add_index(url, config, clobber)?;
+        }
+        CommandType::EachGem { sort, output: None } => {
+            each_gem(
+                &store,
+                sort,
+                &mut Output::new(output_format, stdout().lock()),
+            )?;
+        }
+        CommandType::EachGem {
+            sort,
+            output: Some(output),
+        } => {
+            // Written under a temporary name so a failed dump never leaves a
+            // truncated file behind under the final one
+            let partial = export::partial_path(&output);
+            let write_error = |e| miette!("Failed to write {}: {}", partial.display(), e);
+            let result = File::create(&partial)
+                .map_err(write_error)
+                .and_then(|file| {
+                    let mut writer = Output::new(output_format, BufWriter::new(file));
+                    let count = each_gem(&store, sort, &mut writer)?;
+                    writer
+                        .into_inner()
+                        .into_inner()
+                        .map_err(|e| write_error(e.into_error()))?
+                        .sync_all()
+                        .map_err(write_error)?;
+                    Ok(count)
+                })
+                .and_then(|count| {
+                    std::fs::rename(&partial, &output).map_err(|e| {
+                        miette!(
+                            "Failed to rename {} to {}: {}",
+                            partial.display(),
+                            output.display(),
+                            e
+                        )
+                    })?;
+                    Ok(count)
+                });
+            if result.is_err() {
+                let _ = std::fs::remove_file(&partial);
+            }
+            println!("Wrote {} gems to {}", result?, output.display());
+        }
+        CommandType::Show { name } => {
+            let namespaces = store.get_namespaces(&name)?;
+            if namespaces.is_empty() {
+                bail!("{} is not in any index", name);
+            }
+            let mut output = Output::new(output_format, stdout().lock());
+            for (source, namespace) in &namespaces {
+                let mut gems = namespace.versions.values().collect::<Vec<_>>();
+                gems.sort_by(|a, b| {
+                    Version::parse(&a.version)
+                        .cmp(&Version::parse(&b.version))
+                        .then_with(|| a.platform.cmp(&b.platform))
+                });
+                for gem in gems {
+                    let json = json!({
+                        "source": source,
+                        "full_name": gem.full_name,
+                        "version": gem.version,
+                        "platform": gem.platform,
+                        "stored": gem.stored,
+                        "yanked": gem.yanked,
+                    });
+                    let state = match (gem.stored, gem.yanked) {
+                        (_, true) => " (yanked)",
+                        (false, false) => " (not stored)",
+                        (true, false) => "",
+                    };
+                    output.record(&json, format!("{} ({}){}", gem.full_name, source, state))?;
+                }
+            }
+        }
+        CommandType::Latest {
+            include_prerelease,
+            all_platforms,
+        } => {
+            let mut output = Output::new(output_format, stdout().lock());
+            for index in store.list_indices()?.iter() {
+                let mut names = index.gems.keys().collect::<Vec<_>>();
+                names.sort();
+                for name in names {
+                    let mut latest = BTreeMap::<&str, (Version, &storage::Gem)>::new();
+                    for gem in index.gems[name].versions.values() {
+                        if !all_platforms && gem.platform != "ruby" {
+                            continue;
+                        }
+                        let version = Version::parse(&gem.version);
+                        if !include_prerelease && version.is_prerelease() {
+                            continue;
+                        }
+                        match latest.get(gem.platform.as_str()) {
+                            Some((newest, _)) if *newest >= version => {}
+                            _ => {
+                                latest.insert(&gem.platform, (version, gem));
+                            }
                         }
-                        let gem_path = content_path(&command.store_path, &gem.package_integrity);
+                    }
+                    for (_, gem) in latest.values() {
                         let json = json!({
                             "name": gem.name,
                             "version": gem.version,
                             "source": index.source,
                             "platform": gem.platform,
                             "full_name": gem.full_name,
-                            "integrity": gem.package_integrity.to_string(),
-                            "path": gem_path.display().to_string(),
                         });
-                        serde_json::to_writer(stdout(), &json)
-                            .map_err(|e| miette!("failed to serialize json: {}", e))?;
-                        println!();
+                        output.record(&json, format!("{} ({})", gem.full_name, index.source))?;
+                    }
+                }
+            }
+        }
+        CommandType::ListPlatforms {} => {
+            let platforms = platform_usage(&store)?;
+            let mut output = Output::new(output_format, stdout().lock());
+            let width = platforms
+                .iter()
+                .map(|(platform, _, _)| platform.len())
+                .chain(["PLATFORM".len()])
+                .max()
+                .unwrap_or_default();
+            output.note(format!(
+                "{:width$}  {:>8}  {:>12}",
+                "PLATFORM", "VERSIONS", "BYTES"
+            ))?;
+            for (platform, versions, bytes) in &platforms {
+                let json = json!({
+                    "platform": platform,
+                    "versions": versions,
+                    "bytes": bytes,
+                });
+                output.record(
+                    &json,
+                    format!("{:width$}  {:>8}  {:>12}", platform, versions, bytes),
+                )?;
+            }
+        }
+        CommandType::ReportDuplicatesAcrossIndices {} => {
+            let duplicates = duplicates_across_indices(&store)?;
+            let mut output = Output::new(output_format, stdout().lock());
+            for duplicate in &duplicates {
+                let json = json!({
+                    "integrity": duplicate.integrity,
+                    "full_names": duplicate.full_names,
+                    "sources": duplicate.sources,
+                    "bytes": duplicate.bytes,
+                    "bytes_saved": duplicate.bytes_saved(),
+                });
+                output.record(
+                    &json,
+                    format!(
+                        "{} ({} bytes) in {}: {}",
+                        duplicate
+                            .full_names
+                            .iter()
+                            .cloned()
+                            .collect::<Vec<_>>()
+                            .join(", "),
+                        duplicate.bytes,
+                        duplicate.sources.join(", "),
+                        duplicate.integrity
+                    ),
+                )?;
+            }
+            output.note(format!(
+                "{} blobs shared between indices, saving {} bytes",
+                duplicates.len(),
+                duplicates.iter().map(Duplicate::bytes_saved).sum::<u64>()
+            ))?;
+        }
+        CommandType::ListStale { older_than } => {
+            let cutoff = storage::unix_now().saturating_sub(older_than.as_secs());
+            let mut output = Output::new(output_format, stdout().lock());
+            for index in store.list_indices()?.iter() {
+                let mut names = index.gems.keys().collect::<Vec<_>>();
+                names.sort();
+                for name in names {
+                    let last_updated = index.gems[name].last_updated;
+                    if last_updated.is_some_and(|last_updated| last_updated >= cutoff) {
+                        continue;
+                    }
+                    let last_updated = last_updated
+                        .map(|secs| time::format_timestamp(UNIX_EPOCH + Duration::from_secs(secs)));
+                    let human = format!(
+                        "{} ({}) last updated {}",
+                        name,
+                        index.source,
+                        last_updated.as_deref().unwrap_or("never")
+                    );
+                    let json = json!({
+                        "source": index.source,
+                        "name": name,
+                        "last_updated": last_updated,
+                    });
+                    output.record(&json, human)?;
+                }
+            }
+        }
+        CommandType::Export {
+            out,
+            compression,
+            compression_level,
+        } => {
+            let compression = export::Compression::new(compression, compression_level)?;
+            export::export(&store, &out, compression)?;
+        }
+        CommandType::ExportDeps { out, format } => {
+            deps::export_deps(&store, &out, format)?;
+        }
+        CommandType::ExportChecksums { out, format } => {
+            checksums::export_checksums(&store, &out, format)?;
+        }
+        CommandType::Bundle {
+            name,
+            version,
+            out,
+            platform,
+        } => {
+            let gems = bundle::resolve(
+                &store.list_indices()?,
+                &name,
+                version.as_deref(),
+                platform.as_ref(),
+            )?;
+            bundle::write(&store, &gems, &out)?;
+            for gem in &gems {
+                println!("Bundled {}", gem.full_name);
+            }
+            println!("Wrote {} gems to {}", gems.len(), out.display());
+        }
+        CommandType::Import { input } => {
+            export::import(&mut store, &input)?;
+        }
+        CommandType::Info {} => unreachable!("handled before opening the store"),
+        CommandType::Invalidate {
+            name,
+            version,
+            delete_blob,
+        } => {
+            let count = storage::invalidate(&mut store, &name, version.as_deref(), delete_blob)?;
+            println!("Invalidated {} versions", count);
+        }
+        CommandType::Purge {
+            full_name,
+            delete_blob,
+            force,
+        } => {
+            store.check_marker(force)?;
+            let count = storage::purge(&mut store, &full_name, delete_blob)?;
+            println!("Purged {} from {} indices", full_name, count);
+        }
+        CommandType::RewriteSource { from, to } => {
+            storage::rewrite_source(&mut store, &from, &to)?;
+            println!("Rewrote {} to {}", from, to);
+        }
+        CommandType::DedupeSources { threshold, merge } => {
+            let duplicates = storage::dedupe_sources(&mut store, threshold, merge)?;
+            let mut output = Output::new(output_format, stdout().lock());
+            for duplicate in &duplicates {
+                output.record(
+                    duplicate,
+                    format!(
+                        "{} duplicates {} ({} of {} namespaces identical)",
+                        duplicate.duplicate, duplicate.kept, duplicate.shared, duplicate.total
+                    ),
+                )?;
+            }
+            if duplicates.is_empty() {
+                output.note("No duplicate sources")?;
+            }
+        }
+        CommandType::Compare {
+            other,
+            other_blob_cache_dir,
+        } => {
+            // Opening a directory that isn't a store yet would make it one
+            if !other.is_dir() {
+                bail!("No store at {}", other.display());
+            }
+            let other_store =
+                storage::FsStore::new(&other, other_blob_cache_dir.as_ref().unwrap_or(&other))?;
+            let comparison = compare::compare(&store, &other_store)?;
+            let (here, there) = (command.store_path.display(), other.display());
+            let mut output = Output::new(output_format, stdout().lock());
+            for (only_here, only_there, kind, what) in [
+                (
+                    &comparison.blobs_only_here,
+                    &comparison.blobs_only_there,
+                    "blob",
+                    "Blob ",
+                ),
+                (
+                    &comparison.namespaces_only_here,
+                    &comparison.namespaces_only_there,
+                    "namespace",
+                    "Namespace ",
+                ),
+                (
+                    &comparison.versions_only_here,
+                    &comparison.versions_only_there,
+                    "version",
+                    "",
+                ),
+            ] {
+                for (items, store_path) in [(only_here, &here), (only_there, &there)] {
+                    for item in items {
+                        let json = json!({
+                            "kind": kind,
+                            "name": item,
+                            "only_in": store_path.to_string(),
+                        });
+                        output
+                            .record(&json, format!("{}{} is only in {}", what, item, store_path))?;
                     }
                 }
             }
+            for conflict in &comparison.conflicts {
+                let json = json!({
+                    "kind": "conflict",
+                    "name": conflict.full_name,
+                    "here": conflict.here,
+                    "there": conflict.there,
+                });
+                output.record(
+                    &json,
+                    format!(
+                        "{} is {} in {} but {} in {}",
+                        conflict.full_name,
+                        conflict.here.join(", "),
+                        here,
+                        conflict.there.join(", "),
+                        there
+                    ),
+                )?;
+            }
+            if !comparison.conflicts.is_empty() {
+                bail!(
+                    "{} versions have different contents in the two stores",
+                    comparison.conflicts.len()
+                );
+            }
+            if comparison.is_empty() {
+                output.note("The stores match")?;
+            }
+        }
+        CommandType::PrintConfig { options } => {
+            // Credentials are left out so the output is safe to paste in a bug report
+            let indices = store
+                .list_indices()?
+                .iter()
+                .map(|index| {
+                    let auth = index.config.auth.as_ref().map(|auth| match auth {
+                        storage::Auth::Basic { .. } => "basic",
+                        storage::Auth::Bearer { .. } => "bearer",
+                        storage::Auth::ApiKey { .. } => "api_key",
+                    });
+                    json!({
+                        "source": index.source,
+                        "auth": auth,
+                        "filters": index.config.filters,
+                    })
+                })
+                .collect::<Vec<_>>();
+            let json = json!({
+                "store_path": command.store_path,
+                "blob_cache_dir": blob_cache_dir,
+                "integrity_algos": command
+                    .integrity_algos
+                    .iter()
+                    .map(|algorithm| algorithm.to_string())
+                    .collect::<Vec<_>>(),
+                "shard_namespaces": command.shard_namespaces,
+                "verify_on_read": !command.no_verify_on_read,
+                "update": options,
+                "indices": indices,
+            });
+            let human = serde_json::to_string_pretty(&json)
+                .map_err(|e| miette!("failed to serialize json: {}", e))?;
+            Output::new(output_format, stdout().lock()).record(&json, human)?;
+        }
+        CommandType::Verify {
+            deep,
+            repair,
+            sample,
+            seed,
+            skip_recent,
+        } => {
+            let sample = sample.map(|size| storage::Sample { size, seed });
+            let report = storage::verify(&mut store, deep, repair, sample, skip_recent)?;
+            let mut human = Vec::new();
+            for full_name in &report.missing {
+                human.push(format!("Missing blob for {}", full_name));
+            }
+            for full_name in &report.corrupt {
+                human.push(format!("Corrupt blob for {}", full_name));
+            }
+            human.push(format!("Checked {} blobs", report.checked));
+            if let Some(window) = skip_recent {
+                human.push(format!(
+                    "Skipped rehashing {} blobs verified within {:?}",
+                    report.skipped_recent, window
+                ));
+            }
+            if let Some(confidence) = report.confidence() {
+                human.push(confidence);
+            }
+            if repair {
+                human.push(format!("Marked {} gems for refetch", report.repaired));
+            }
+            Output::new(output_format, stdout().lock()).record(&report, human.join("\n"))?;
+            if !report.is_ok() && !repair {
+                bail!(
+                    "{} missing and {} corrupt blobs",
+                    report.missing.len(),
+                    report.corrupt.len()
+                );
+            }
+        }
+        CommandType::Healthcheck {} => {
+            store.healthcheck()?;
+            Output::new(output_format, stdout().lock()).record(&json!({ "status": "ok" }), "ok")?;
+        }
+        CommandType::CheckLock { path } => {
+            let contents = std::fs::read_to_string(&path)
+                .map_err(|e| miette!("Failed to read {}: {}", path.display(), e))?;
+            let full_names = gemfile::parse_lockfile(&contents)
+                .iter()
+                .map(gemfile::LockedSpec::full_name)
+                .collect::<Vec<_>>();
+            let mut problems = 0;
+            let mut output = Output::new(output_format, stdout().lock());
+            for (full_name, status) in storage::check_lock(&store, &full_names)? {
+                if status != storage::LockStatus::Present {
+                    problems += 1;
+                }
+                let status = match status {
+                    storage::LockStatus::Present => "present",
+                    storage::LockStatus::NotStored => "not stored",
+                    storage::LockStatus::BlobMissing => "blob missing",
+                    storage::LockStatus::NotInIndex => "missing",
+                };
+                let json = json!({ "full_name": full_name, "status": status });
+                output.record(&json, format!("{}: {}", full_name, status))?;
+            }
+            if problems > 0 {
+                bail!(
+                    "{} of {} locked gems can't be served",
+                    problems,
+                    full_names.len()
+                );
+            }
+        }
+        CommandType::Migrate {} => {
+            store.with_indices(|_, _| Ok(()))?;
+            println!(
+                "Migrated indices to format version {}",
+                storage::INDICES_FORMAT_VERSION
+            );
+        }
+        CommandType::Shard {} => {
+            let count = store.write_shards()?;
+            println!("Wrote {} namespace shards", count);
+        }
+        CommandType::Prune { dry_run, force } => {
+            store.check_marker(force)?;
+            let report = store.prune(dry_run)?;
+            let verb = if dry_run { "Would prune" } else { "Pruned" };
+            println!(
+                "{} {} blobs ({} bytes)",
+                verb, report.blobs_removed, report.bytes_reclaimed
+            );
+        }
+        CommandType::Compact { verify } => {
+            let report = store.compact(verify)?;
+            println!("Reclaimed {} bytes", report.bytes_reclaimed);
+            println!("Removed {} temp files", report.tmp_files_removed);
+            println!(
+                "Removed {} dangling index entries",
+                report.index_entries_removed
+            );
+            if verify {
+                println!("Removed {} corrupt blobs", report.corrupt_blobs_removed);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes a line per stored gem to `out`, returning how many were written.
+/// Versions left unstored on purpose, e.g. by `--platforms` or
+/// `--published-after`, are skipped and counted on stderr.
+fn each_gem(
+    store: &storage::FsStore,
+    sort: Option<SortKey>,
+    out: &mut Output<impl Write>,
+) -> Result<usize> {
+    let mut count = 0;
+    let mut unstored = 0;
+    let mut records = Vec::new();
+    store.for_each_gem(|source, gem| {
+        if !gem.stored {
+            unstored += 1;
+            return Ok(());
+        }
+        let gem_path = store
+            .blob_location(&gem.package_integrity)
+            .ok_or_else(|| miette!("Blob for {} is missing", gem.full_name))?;
+        // Sorting needs every gem at once; otherwise each is printed as soon
+        // as it is read and then dropped
+        if sort.is_some() {
+            records.push((source.to_string(), gem.clone(), gem_path));
+        } else {
+            print_gem(out, source, gem, &gem_path)?;
+            count += 1;
+        }
+        Ok(())
+    })?;
+
+    match sort {
+        None => {}
+        Some(SortKey::Name) => records.sort_by(|(_, a, _), (_, b, _)| {
+            a.name
+                .cmp(&b.name)
+                .then_with(|| Version::parse(&a.version).cmp(&Version::parse(&b.version)))
+                .then_with(|| a.platform.cmp(&b.platform))
+        }),
+        Some(SortKey::Integrity) => {
+            records.sort_by_cached_key(|(_, gem, _)| gem.package_integrity.to_string())
+        }
+        Some(SortKey::Size) => records.sort_by_cached_key(|(_, gem, path)| {
+            (
+                std::fs::metadata(path).map(|m| m.len()).unwrap_or(0),
+                gem.full_name.clone(),
+            )
+        }),
+    }
+    for (source, gem, gem_path) in &records {
+        print_gem(out, source, gem, gem_path)?;
+    }
+    if unstored > 0 {
+        eprintln!("Skipped {} versions that aren't stored", unstored);
+    }
+    Ok(count + records.len())
+}
+
+/// Counts the stored versions of each platform and the bytes of their
+/// distinct blobs, sorted by bytes then name. A version in several indices
+/// is counted once, while a blob shared by versions of different platforms
+/// counts towards each.
+fn platform_usage(store: &storage::FsStore) -> Result<Vec<(String, usize, u64)>> {
+    let mut full_names = HashSet::new();
+    let mut blobs = HashSet::new();
+    let mut usage = BTreeMap::<String, (usize, u64)>::new();
+    for index in store.list_indices()?.iter() {
+        for namespace in index.gems.values() {
+            for gem in namespace.versions.values() {
+                if !gem.stored || !full_names.insert(gem.full_name.clone()) {
+                    continue;
+                }
+                let (versions, bytes) = usage.entry(gem.platform.clone()).or_default();
+                *versions += 1;
+                if blobs.insert((gem.platform.clone(), gem.package_integrity.to_string())) {
+                    *bytes += store
+                        .blob_location(&gem.package_integrity)
+                        .and_then(|path| std::fs::metadata(path).ok())
+                        .map_or(0, |metadata| metadata.len());
+                }
+            }
         }
     }
+    let mut usage = usage
+        .into_iter()
+        .map(|(platform, (versions, bytes))| (platform, versions, bytes))
+        .collect::<Vec<_>>();
+    usage.sort_by_key(|(_, _, bytes)| std::cmp::Reverse(*bytes));
+    Ok(usage)
+}
 
+/// A stored blob that more than one index refers to
+struct Duplicate {
+    integrity: String,
+    full_names: BTreeSet<String>,
+    /// The indices referring to it, in the order of `indices.json`
+    sources: Vec<String>,
+    bytes: u64,
+}
+
+impl Duplicate {
+    /// What keeping a copy per index would have cost on top of the one
+    fn bytes_saved(&self) -> u64 {
+        self.bytes * (self.sources.len() as u64 - 1)
+    }
+}
+
+/// Joins the stored gems of every index on the SHA-256 of their package, the
+/// address of its blob, so indices that recorded other algorithms too still
+/// match
+fn duplicates_across_indices(store: &storage::FsStore) -> Result<Vec<Duplicate>> {
+    let mut blobs = BTreeMap::<String, Duplicate>::new();
+    for index in store.list_indices()?.iter() {
+        for namespace in index.gems.values() {
+            for gem in namespace.versions.values() {
+                if !gem.stored {
+                    continue;
+                }
+                let integrity = storage::content_address(&gem.package_integrity).to_string();
+                let blob = blobs.entry(integrity.clone()).or_insert_with(|| Duplicate {
+                    integrity,
+                    full_names: BTreeSet::new(),
+                    sources: Vec::new(),
+                    bytes: store
+                        .blob_location(&gem.package_integrity)
+                        .and_then(|path| std::fs::metadata(path).ok())
+                        .map_or(0, |metadata| metadata.len()),
+                });
+                blob.full_names.insert(gem.full_name.clone());
+                if !blob.sources.contains(&index.source) {
+                    blob.sources.push(index.source.clone());
+                }
+            }
+        }
+    }
+    let mut duplicates = blobs
+        .into_values()
+        .filter(|blob| blob.sources.len() > 1)
+        .collect::<Vec<_>>();
+    // Stable, so ties stay ordered by integrity
+    duplicates.sort_by_key(|duplicate| std::cmp::Reverse(duplicate.bytes_saved()));
+    Ok(duplicates)
+}
+
+/// Syncs the store, then prunes it if asked to
+fn update(store: &mut storage::FsStore, options: &storage::UpdateOptions) -> Result<()> {
+    // Any error fails the update before we get here, so a gem that merely
+    // failed to refetch never has its blob pruned.
+    storage::update_store(store, options)?;
+    if options.prune_after_update {
+        store.check_marker(false)?;
+        let report = store.prune(false)?;
+        println!(
+            "Pruned {} blobs ({} bytes)",
+            report.blobs_removed, report.bytes_reclaimed
+        );
+    }
     Ok(())
 }
 
-fn content_path(cache: &PathBuf, sri: &ssri::Integrity) -> PathBuf {
-    let mut path = PathBuf::new();
-    let (algo, hex) = sri.to_hex();
-    path.push(cache);
-    path.push(format!("content-v{}", 2));
-    path.push(algo.to_string());
-    path.push(&hex[0..2]);
-    path.push(&hex[2..4]);
-    path.push(&hex[4..]);
-    path
+fn print_gem(
+    out: &mut Output<impl Write>,
+    source: &str,
+    gem: &storage::Gem,
+    gem_path: &Path,
+) -> Result<()> {
+    let json = json!({
+        "name": gem.name,
+        "version": gem.version,
+        "source": source,
+        "platform": gem.platform,
+        "full_name": gem.full_name,
+        "integrity": gem.package_integrity.to_string(),
+        "path": gem_path.display().to_string(),
+        "yanked": gem.yanked,
+    });
+    out.record(&json, gem_path.display())
 }
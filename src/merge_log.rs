@@ -0,0 +1,155 @@
+//! An audit trail of how each namespace changed during updates, for
+//! `--merge-log`.
+//!
+//! Every namespace that an update adds, changes or drops is appended to the
+//! log as one JSON object per line, so the history of a namespace across
+//! runs can be replayed and a version republished upstream with different
+//! contents stands out.
+
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    path::Path,
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use miette::miette;
+use serde::Serialize;
+use ssri::Integrity;
+
+use crate::{storage::Namespace, time::format_timestamp};
+
+/// The appended-to merge log, or a no-op if none was configured
+#[derive(Debug, Default)]
+pub struct MergeLog {
+    file: Option<Mutex<BufWriter<File>>>,
+}
+
+impl MergeLog {
+    pub fn new(path: Option<&Path>) -> miette::Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| miette!("Failed to open {}: {}", path.display(), e))?;
+        Ok(Self {
+            file: Some(Mutex::new(BufWriter::new(file))),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.file.is_some()
+    }
+
+    /// Appends `record` as a line and flushes it, so the log survives an
+    /// update that is interrupted
+    pub fn record(&self, record: &MergeRecord) -> miette::Result<()> {
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+        let mut file = file.lock().unwrap();
+        serde_json::to_writer(&mut *file, record)
+            .map_err(|e| miette!("Failed to write merge log: {}", e))?;
+        writeln!(file)
+            .and_then(|()| file.flush())
+            .map_err(|e| miette!("Failed to write merge log: {}", e))
+    }
+}
+
+/// What one update did to one namespace
+#[derive(Debug, Serialize)]
+pub struct MergeRecord {
+    pub time: String,
+    pub source: String,
+    pub namespace: String,
+    /// The `/info` checksum before the update, if the namespace was known
+    pub previous_checksum: Option<String>,
+    /// The `/info` checksum after the update, if the namespace was kept
+    pub checksum: Option<String>,
+    /// Full names of versions that are new
+    pub added: Vec<String>,
+    /// Full names of versions that are gone
+    pub removed: Vec<String>,
+    /// Full names of versions newly flagged as yanked
+    pub yanked: Vec<String>,
+    /// The number of versions that were there before and still are
+    pub kept: usize,
+    /// Versions listed upstream with a different checksum than the one
+    /// already known
+    pub conflicted: Vec<Conflict>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Conflict {
+    pub full_name: String,
+    /// The integrity already in the index, which a stored version keeps
+    pub known: String,
+    /// The integrity now listed upstream
+    pub upstream: String,
+}
+
+impl MergeRecord {
+    /// Compares a namespace `before` and `after` an update, given the
+    /// package integrities `upstream` listed for it
+    pub fn new(
+        source: &str,
+        namespace: &str,
+        before: Option<&Namespace>,
+        upstream: &HashMap<String, Integrity>,
+        after: Option<&Namespace>,
+    ) -> Self {
+        let empty = HashMap::new();
+        let before_versions = before.map_or(&empty, |namespace| &namespace.versions);
+        let after_versions = after.map_or(&empty, |namespace| &namespace.versions);
+
+        let mut record = Self {
+            time: format_timestamp(SystemTime::now()),
+            source: source.to_string(),
+            namespace: namespace.to_string(),
+            previous_checksum: before.map(|namespace| namespace.info_checksum().to_string()),
+            checksum: after.map(|namespace| namespace.info_checksum().to_string()),
+            added: Vec::new(),
+            removed: Vec::new(),
+            yanked: Vec::new(),
+            kept: 0,
+            conflicted: Vec::new(),
+        };
+        for (full_name, gem) in after_versions {
+            match before_versions.get(full_name) {
+                None => record.added.push(full_name.clone()),
+                Some(previous) => {
+                    record.kept += 1;
+                    if gem.yanked && !previous.yanked {
+                        record.yanked.push(full_name.clone());
+                    }
+                }
+            }
+        }
+        for (full_name, gem) in before_versions {
+            if !after_versions.contains_key(full_name) {
+                record.removed.push(full_name.clone());
+            }
+            if let Some(listed) = upstream.get(full_name)
+                && *listed != gem.package_integrity
+            {
+                record.conflicted.push(Conflict {
+                    full_name: full_name.clone(),
+                    known: gem.package_integrity.to_string(),
+                    upstream: listed.to_string(),
+                });
+            }
+        }
+        record.added.sort();
+        record.removed.sort();
+        record.yanked.sort();
+        record
+            .conflicted
+            .sort_by(|a, b| a.full_name.cmp(&b.full_name));
+        record
+    }
+}
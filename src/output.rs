@@ -0,0 +1,60 @@
+//! The results of informational commands, printed for people or, with
+//! `--output-format json`, as a JSON object per line for scripts.
+//!
+//! Commands hand each result to an `Output` in both forms rather than
+//! formatting JSON themselves, so every command honours the flag the same
+//! way.
+//!
+//! `each-gem`, `latest` and `list-stale` print JSON unless asked otherwise,
+//! as they did before the flag existed.
+
+use std::{fmt::Display, io::Write};
+
+use miette::miette;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Plain text
+    #[default]
+    Human,
+    /// A JSON object per line
+    Json,
+}
+
+pub struct Output<W: Write> {
+    format: OutputFormat,
+    out: W,
+}
+
+impl<W: Write> Output<W> {
+    pub fn new(format: OutputFormat, out: W) -> Self {
+        Self { format, out }
+    }
+
+    /// Writes one result, as a line of `json` or as `human`
+    pub fn record(&mut self, json: &impl Serialize, human: impl Display) -> miette::Result<()> {
+        match self.format {
+            OutputFormat::Human => writeln!(self.out, "{}", human),
+            OutputFormat::Json => {
+                serde_json::to_writer(&mut self.out, json)
+                    .map_err(|e| miette!("failed to serialize json: {}", e))?;
+                writeln!(self.out)
+            }
+        }
+        .map_err(|e| miette!("failed to write output: {}", e))
+    }
+
+    /// Writes a line only meant for people, such as a heading or a summary,
+    /// which JSON output leaves out
+    pub fn note(&mut self, human: impl Display) -> miette::Result<()> {
+        if self.format == OutputFormat::Json {
+            return Ok(());
+        }
+        writeln!(self.out, "{}", human).map_err(|e| miette!("failed to write output: {}", e))
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
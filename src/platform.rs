@@ -0,0 +1,279 @@
+//! RubyGems-compatible platform parsing and matching.
+//!
+//! This mirrors `Gem::Platform`: a platform string is split into a CPU, an OS
+//! and an optional OS version, with the many spellings gems are published
+//! under (`i686-linux`, `x86_64-linux-gnu`, `universal-darwin-22`, ...)
+//! normalized so that equivalent platforms compare equal.
+
+use std::{fmt, str::FromStr};
+
+use serde::{Serialize, Serializer};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Platform {
+    cpu: Option<String>,
+    os: String,
+    version: Option<String>,
+}
+
+impl Platform {
+    pub fn parse(platform: &str) -> Self {
+        let platform = platform.trim();
+        if platform.is_empty() || platform == "ruby" {
+            return Self::ruby();
+        }
+
+        let mut parts = platform.split('-').map(str::to_string).collect::<Vec<_>>();
+        // Reassemble e.g. `x86_64-linux-gnu` into `x86_64` and `linux-gnu`,
+        // but leave a trailing OS version like `universal-darwin-22` alone.
+        if parts.len() > 2 && !is_numeric_version(&parts[parts.len() - 1]) {
+            let extra = parts.pop().unwrap();
+            let last = parts.last_mut().unwrap();
+            last.push('-');
+            last.push_str(&extra);
+        }
+
+        let mut parts = parts.into_iter();
+        let first = parts.next().unwrap_or_default();
+        let mut cpu = Some(match first.as_str() {
+            cpu if is_x86(cpu) => "x86".to_string(),
+            cpu => cpu.to_string(),
+        });
+        let rest = parts.collect::<Vec<_>>();
+        if let [os, version] = rest.as_slice()
+            && is_numeric_version(version)
+        {
+            return Self {
+                cpu,
+                os: os.clone(),
+                version: Some(version.clone()),
+            };
+        }
+
+        // A bare OS, like the legacy `java` or `mswin32`
+        let os = match rest.into_iter().next() {
+            Some(os) => os,
+            None => {
+                cpu = None;
+                first
+            }
+        };
+        let (os, version) = parse_os(&os, &mut cpu);
+        Self { cpu, os, version }
+    }
+
+    /// The platform of pure-Ruby gems
+    pub fn ruby() -> Self {
+        Self {
+            cpu: None,
+            os: "ruby".to_string(),
+            version: None,
+        }
+    }
+
+    pub fn is_ruby(&self) -> bool {
+        self.cpu.is_none() && self.os == "ruby" && self.version.is_none()
+    }
+
+    /// Whether a gem built for `other` runs on this platform, like
+    /// `Gem::Platform#===`. A missing or `universal` CPU and a missing OS
+    /// version act as wildcards, and a bare `linux` covers both the glibc
+    /// and musl variants.
+    pub fn matches(&self, other: &Platform) -> bool {
+        if self.is_ruby() || other.is_ruby() {
+            return self.is_ruby() && other.is_ruby();
+        }
+
+        let is_wildcard =
+            |cpu: &Option<String>| cpu.as_deref().is_none_or(|cpu| cpu == "universal");
+        if (self.cpu.as_deref() == Some("universal") || other.cpu.as_deref() == Some("universal"))
+            && self.os.starts_with("mingw")
+            && other.os.starts_with("mingw")
+        {
+            return true;
+        }
+
+        let cpu_matches = is_wildcard(&self.cpu)
+            || is_wildcard(&other.cpu)
+            || self.cpu == other.cpu
+            || (self.cpu.as_deref() == Some("arm")
+                && other
+                    .cpu
+                    .as_deref()
+                    .is_some_and(|cpu| cpu.starts_with("armv")));
+        if !cpu_matches || self.os != other.os {
+            return false;
+        }
+
+        if self.os == "linux" {
+            let musl = ["musl", "musleabi", "musleabihf"]
+                .map(|abi| format!("{}{}", abi, self.version.as_deref().unwrap_or("")));
+            self.normalized_linux_version() == other.normalized_linux_version()
+                || other
+                    .version
+                    .as_ref()
+                    .is_some_and(|version| musl.contains(version))
+        } else {
+            self.version.is_none() || other.version.is_none() || self.version == other.version
+        }
+    }
+
+    /// The libc of a linux platform, ignoring the default `gnu` and any ABI
+    /// suffix, so `linux`, `linux-gnu` and `linux-gnueabihf` are all `None`
+    fn normalized_linux_version(&self) -> Option<&str> {
+        let version = self.version.as_deref()?;
+        let version = version.strip_prefix("gnu").unwrap_or(version);
+        let version = version
+            .strip_suffix("eabihf")
+            .or_else(|| version.strip_suffix("eabi"))
+            .unwrap_or(version);
+        Some(version).filter(|version| !version.is_empty())
+    }
+}
+
+/// `i386` through `i686` are all plain `x86`
+fn is_x86(cpu: &str) -> bool {
+    let bytes = cpu.as_bytes();
+    bytes.len() == 4 && bytes[0] == b'i' && bytes[1].is_ascii_digit() && &bytes[2..] == b"86"
+}
+
+/// Matches `\d+(\.\d+)?`
+fn is_numeric_version(s: &str) -> bool {
+    let (major, minor) = s.split_once('.').unwrap_or((s, "0"));
+    [major, minor]
+        .iter()
+        .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// The leading run of characters of `s` that satisfy `f`, or `None` if empty
+fn leading(s: &str, f: impl Fn(char) -> bool) -> Option<String> {
+    let end = s.find(|c| !f(c)).unwrap_or(s.len());
+    Some(s[..end].to_string()).filter(|s| !s.is_empty())
+}
+
+/// Splits an OS into its canonical name and version, following the table in
+/// `Gem::Platform#initialize`
+fn parse_os(os: &str, cpu: &mut Option<String>) -> (String, Option<String>) {
+    let digits = |rest: &str| leading(rest, |c| c.is_ascii_digit());
+    let dotted = |rest: &str| leading(rest, |c| c.is_ascii_digit() || c == '.');
+    let word = |rest: &str| {
+        let rest = rest.strip_prefix('-').unwrap_or(rest);
+        leading(rest, |c| c.is_ascii_alphanumeric() || c == '_')
+    };
+    let after = |needle: &str| os.find(needle).map(|i| &os[i + needle.len()..]);
+
+    if let Some(rest) = after("aix") {
+        ("aix".to_string(), digits(rest))
+    } else if os.contains("cygwin") {
+        ("cygwin".to_string(), None)
+    } else if let Some(rest) = after("darwin") {
+        ("darwin".to_string(), digits(rest))
+    } else if os == "macruby" {
+        ("macruby".to_string(), None)
+    } else if let Some(rest) = after("freebsd") {
+        ("freebsd".to_string(), digits(rest))
+    } else if os == "java" || os == "jruby" {
+        ("java".to_string(), None)
+    } else if let Some(rest) = os.strip_prefix("java") {
+        ("java".to_string(), dotted(rest))
+    } else if let Some(rest) = os.strip_prefix("dalvik") {
+        ("dalvik".to_string(), digits(rest))
+    } else if let Some(rest) = os.strip_prefix("dotnet") {
+        ("dotnet".to_string(), dotted(rest))
+    } else if let Some(rest) = after("linux") {
+        ("linux".to_string(), word(rest))
+    } else if os.contains("mingw32") {
+        ("mingw32".to_string(), None)
+    } else if let Some(rest) = after("mingw") {
+        ("mingw".to_string(), word(rest))
+    } else if let Some(rest) = after("mswin") {
+        let number = digits(rest).unwrap_or_default();
+        let version = rest[number.len()..].strip_prefix('_').and_then(digits);
+        if cpu.is_none() && number.ends_with("32") {
+            *cpu = Some("x86".to_string());
+        }
+        (format!("mswin{}", number), version)
+    } else if os.contains("netbsdelf") {
+        ("netbsdelf".to_string(), None)
+    } else if let Some(rest) = after("openbsd") {
+        (
+            "openbsd".to_string(),
+            dotted(rest).filter(|v| is_numeric_version(v)),
+        )
+    } else if let Some(rest) = after("solaris") {
+        (
+            "solaris".to_string(),
+            dotted(rest).filter(|v| is_numeric_version(v)),
+        )
+    } else if os.contains("wasi") {
+        ("wasi".to_string(), None)
+    } else {
+        ("unknown".to_string(), None)
+    }
+}
+
+impl FromStr for Platform {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self::parse(s))
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parts = [self.cpu.as_deref(), Some(&self.os), self.version.as_deref()];
+        let parts = parts.into_iter().flatten().collect::<Vec<_>>();
+        f.write_str(&parts.join("-"))
+    }
+}
+
+impl Serialize for Platform {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn java_round_trips() {
+        let platform = Platform::parse("java");
+        assert!(!platform.is_ruby());
+        assert_eq!(platform.to_string(), "java");
+        assert_eq!(Platform::parse(&platform.to_string()), platform);
+    }
+
+    #[test]
+    fn universal_darwin_keeps_its_os_version() {
+        for spelling in ["universal-darwin-20", "universal-darwin-22"] {
+            let platform = Platform::parse(spelling);
+            assert_eq!(platform.to_string(), spelling);
+            assert_eq!(Platform::parse(&platform.to_string()), platform);
+        }
+    }
+
+    #[test]
+    fn ruby_and_native_platforms_round_trip() {
+        assert!(Platform::parse("ruby").is_ruby());
+        assert!(Platform::parse("").is_ruby());
+        for spelling in [
+            "x86_64-linux",
+            "x86_64-linux-musl",
+            "arm64-darwin",
+            "x64-mingw-ucrt",
+        ] {
+            let platform = Platform::parse(spelling);
+            assert_eq!(platform.to_string(), spelling);
+            assert_eq!(Platform::parse(&platform.to_string()), platform);
+        }
+    }
+
+    #[test]
+    fn x86_spellings_normalize() {
+        assert_eq!(Platform::parse("i686-linux").to_string(), "x86-linux");
+        assert_eq!(Platform::parse("i686-linux"), Platform::parse("x86-linux"));
+    }
+}
@@ -0,0 +1,244 @@
+//! Progress bars for `--progress`: one for an index's downloads as a whole,
+//! and one for each download worker.
+//!
+//! Workers only ever touch atomics, so counting never contends for a lock
+//! and the totals stay exact however many downloads run at once. A separate
+//! reporter thread reads them to drive an `indicatif::MultiProgress`.
+
+use std::{
+    io::{IsTerminal, stderr},
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    thread,
+    time::Duration,
+};
+
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressStyle};
+
+/// How often the bars are redrawn on a terminal
+const REDRAW_INTERVAL: Duration = Duration::from_millis(250);
+/// How often a line is printed when stderr isn't a terminal, where bars
+/// can't be redrawn in place
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+pub struct Progress {
+    source: String,
+    total: usize,
+    done: AtomicUsize,
+    failed: AtomicUsize,
+    bytes: AtomicU64,
+    workers: Vec<Worker>,
+    finished: AtomicBool,
+}
+
+/// The counts behind one worker's bar
+#[derive(Default)]
+struct Worker {
+    busy: AtomicBool,
+    done: AtomicUsize,
+    bytes: AtomicU64,
+}
+
+/// A download in flight on one worker, counted as active until finished or
+/// dropped
+pub struct Active<'a> {
+    progress: &'a Progress,
+    /// The worker it runs on, or `None` if more downloads started at once
+    /// than there are workers
+    worker: Option<&'a Worker>,
+}
+
+impl Active<'_> {
+    /// Counts the finished gem against its worker as well as the totals
+    pub fn finish(self, downloaded: Option<usize>, ok: bool) {
+        if let Some(worker) = self.worker {
+            if let Some(bytes) = downloaded {
+                worker.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+            }
+            worker.done.fetch_add(1, Ordering::Relaxed);
+        }
+        self.progress.finish(downloaded, ok);
+    }
+}
+
+impl Drop for Active<'_> {
+    fn drop(&mut self) {
+        if let Some(worker) = self.worker {
+            worker.busy.store(false, Ordering::Release);
+        }
+    }
+}
+
+impl Progress {
+    pub fn new(source: &str, total: usize, workers: usize) -> Self {
+        Self {
+            source: source.to_string(),
+            total,
+            done: AtomicUsize::new(0),
+            failed: AtomicUsize::new(0),
+            bytes: AtomicU64::new(0),
+            workers: (0..workers).map(|_| Worker::default()).collect(),
+            finished: AtomicBool::new(false),
+        }
+    }
+
+    /// Claims an idle worker for a download
+    pub fn start(&self) -> Active<'_> {
+        let worker = self.workers.iter().find(|worker| {
+            worker
+                .busy
+                .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+        });
+        Active {
+            progress: self,
+            worker,
+        }
+    }
+
+    /// Counts a finished gem, and the bytes downloaded for it, if any
+    pub fn finish(&self, downloaded: Option<usize>, ok: bool) {
+        if let Some(bytes) = downloaded {
+            self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+        if !ok {
+            self.failed.fetch_add(1, Ordering::Relaxed);
+        }
+        self.done.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The bytes downloaded and failures so far, after the gem count
+    fn summary(&self) -> String {
+        let mut summary = format!(
+            "{} downloaded",
+            HumanBytes(self.bytes.load(Ordering::Relaxed))
+        );
+        let failed = self.failed.load(Ordering::Relaxed);
+        if failed > 0 {
+            summary += &format!(", {} failed", failed);
+        }
+        summary
+    }
+
+    fn line(&self) -> String {
+        let busy = self
+            .workers
+            .iter()
+            .filter(|worker| worker.busy.load(Ordering::Relaxed))
+            .count();
+        format!(
+            "{}: {}/{} gems, {}, {}/{} workers busy",
+            self.source,
+            self.done.load(Ordering::Relaxed),
+            self.total,
+            self.summary(),
+            busy,
+            self.workers.len()
+        )
+    }
+
+    /// Draws the bars on stderr until `stop` is called, then draws them
+    /// once more with the final counts. Run this on its own thread.
+    ///
+    /// When stderr isn't a terminal, a line is logged every so often
+    /// instead.
+    pub fn report(&self) {
+        if !stderr().is_terminal() {
+            loop {
+                let finished = self.finished.load(Ordering::Acquire);
+                eprintln!("{}", self.line());
+                if finished {
+                    return;
+                }
+                thread::park_timeout(LOG_INTERVAL);
+            }
+        }
+
+        let bars = MultiProgress::new();
+        let overall = bars.add(
+            ProgressBar::new(self.total as u64)
+                .with_style(
+                    ProgressStyle::with_template("{prefix} [{bar:30}] {pos}/{len} gems, {msg}")
+                        .expect("valid progress template")
+                        .progress_chars("=> "),
+                )
+                .with_prefix(self.source.clone()),
+        );
+        let worker_style =
+            ProgressStyle::with_template("  {prefix} {msg}").expect("valid progress template");
+        let worker_bars = (0..self.workers.len())
+            .map(|i| {
+                bars.add(
+                    ProgressBar::no_length()
+                        .with_style(worker_style.clone())
+                        .with_prefix(format!("worker {}", i + 1)),
+                )
+            })
+            .collect::<Vec<_>>();
+        loop {
+            let finished = self.finished.load(Ordering::Acquire);
+            overall.set_position(self.done.load(Ordering::Relaxed) as u64);
+            overall.set_message(self.summary());
+            for (worker, bar) in self.workers.iter().zip(&worker_bars) {
+                bar.set_message(format!(
+                    "{}, {} gems, {}",
+                    if worker.busy.load(Ordering::Relaxed) {
+                        "busy"
+                    } else {
+                        "idle"
+                    },
+                    worker.done.load(Ordering::Relaxed),
+                    HumanBytes(worker.bytes.load(Ordering::Relaxed))
+                ));
+            }
+            if finished {
+                for bar in &worker_bars {
+                    bar.finish_and_clear();
+                }
+                overall.finish();
+                return;
+            }
+            thread::park_timeout(REDRAW_INTERVAL);
+        }
+    }
+
+    /// Tells `report` to finish; unpark its thread to have it do so at once
+    pub fn stop(&self) {
+        self.finished.store(true, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_are_exact_under_concurrency() {
+        let progress = Progress::new("https://rubygems.org/", 8 * 500, 8);
+        thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for i in 0..500 {
+                        let active = progress.start();
+                        assert!(active.worker.is_some());
+                        active.finish(Some(3), i % 100 != 0);
+                    }
+                });
+            }
+        });
+        assert_eq!(progress.done.load(Ordering::Relaxed), 4000);
+        assert_eq!(progress.failed.load(Ordering::Relaxed), 40);
+        assert_eq!(progress.bytes.load(Ordering::Relaxed), 12000);
+        let worker_done = progress
+            .workers
+            .iter()
+            .map(|worker| worker.done.load(Ordering::Relaxed))
+            .sum::<usize>();
+        assert_eq!(worker_done, 4000);
+        assert!(
+            progress
+                .workers
+                .iter()
+                .all(|worker| !worker.busy.load(Ordering::Relaxed))
+        );
+    }
+}
@@ -0,0 +1,467 @@
+//! A compact index server for the store, so Bundler and RubyGems can install
+//! straight from the mirror.
+//!
+//! `/versions`, `/names` and every `/info/<name>` are rendered up front from
+//! the stored, unyanked gems of all indices into an immutable `Snapshot`.
+//! Each request works from the snapshot current when it arrived, so a reload
+//! swaps in a new one without disturbing requests already in flight.
+//!
+//! `/versions` opens with the `created_at: <timestamp>` line and `---`
+//! separator that Bundler skips past before reading version lines. A reload
+//! that changes no version line keeps the previous timestamp, so the file and
+//! its ETag only change when its contents do.
+//!
+//! `/health` runs the same checks as the `healthcheck` command, answering
+//! `200 ok` or `503` with what failed, for load balancer probes.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use miette::miette;
+use ssri::Integrity;
+
+use crate::{
+    http::STORE_ID_HEADER,
+    md5,
+    platform::Platform,
+    storage::{FsStore, Gem, Index, Store, content_address},
+    time::format_timestamp,
+    version::Version,
+};
+
+/// How long a client may take to send its request, and how long a write to
+/// it may stall, before its connection is dropped
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(60);
+/// The most a request line and headers may take up; compact index clients
+/// send a handful of short headers
+const MAX_HEAD_LEN: u64 = 64 * 1024;
+
+/// Sent as the `Server` header on every response
+pub const SERVER: &str = concat!("gem-mirror/", env!("CARGO_PKG_VERSION"));
+
+/// The rendered compact index of a store at one point in time
+#[derive(Debug, Default)]
+struct Snapshot {
+    /// `/versions`, `/names` and `/info/<name>` and their MD5 checksums, by
+    /// request path
+    files: HashMap<String, (String, String)>,
+    /// The package integrity of each servable gem, by full name
+    gems: HashMap<String, Integrity>,
+    /// Other spellings of full names, mapped to the full name in `gems`: an
+    /// explicit `-ruby` suffix, and the platform as RubyGems formats it when
+    /// the index lists it differently (e.g. `x86-linux` for `i686-linux`)
+    aliases: HashMap<String, String>,
+}
+
+impl Snapshot {
+    /// Renders every stored, unyanked version. A full name present in
+    /// several indices is taken from the first. `/versions` is stamped with
+    /// `created_at` if given, else with the time from `previous` if that
+    /// listed the same versions, else with the current time.
+    fn build(indices: &[Index], previous: Option<&Snapshot>, created_at: Option<&str>) -> Self {
+        let mut namespaces = BTreeMap::<&str, BTreeMap<&str, &Gem>>::new();
+        for index in indices {
+            for (name, namespace) in &index.gems {
+                for gem in namespace.versions.values() {
+                    if !gem.stored || gem.yanked {
+                        continue;
+                    }
+                    namespaces
+                        .entry(name)
+                        .or_default()
+                        .entry(&gem.full_name)
+                        .or_insert(gem);
+                }
+            }
+        }
+
+        let mut snapshot = Snapshot::default();
+        let mut versions = String::new();
+        let mut names = String::from("---\n");
+        for (name, gems) in namespaces {
+            let mut gems = gems.into_values().collect::<Vec<_>>();
+            gems.sort_by(|a, b| {
+                Version::parse(&a.version)
+                    .cmp(&Version::parse(&b.version))
+                    .then_with(|| a.platform.cmp(&b.platform))
+            });
+
+            let mut info = String::from("---\n");
+            for gem in &gems {
+                info.push_str(&info_line(gem));
+                info.push('\n');
+            }
+            let checksum = snapshot.insert_file(format!("/info/{}", name), info);
+
+            let listed = gems
+                .iter()
+                .map(|gem| version_with_platform(gem))
+                .collect::<Vec<_>>()
+                .join(",");
+            versions.push_str(&format!("{} {} {}\n", name, listed, checksum));
+            names.push_str(name);
+            names.push('\n');
+            for gem in &gems {
+                snapshot
+                    .gems
+                    .insert(gem.full_name.clone(), gem.package_integrity.clone());
+                let canonical = format!(
+                    "{}-{}-{}",
+                    name,
+                    gem.version,
+                    Platform::parse(&gem.platform)
+                );
+                if canonical != gem.full_name {
+                    snapshot.aliases.insert(canonical, gem.full_name.clone());
+                }
+            }
+        }
+        let created_at = created_at
+            .map(str::to_string)
+            .or_else(|| previous.and_then(|previous| previous.versions_created_at(&versions)))
+            .unwrap_or_else(|| format_timestamp(SystemTime::now()));
+        let versions = format!("created_at: {}\n---\n{}", created_at, versions);
+        snapshot.insert_file("/versions".to_string(), versions);
+        snapshot.insert_file("/names".to_string(), names);
+        snapshot
+    }
+
+    /// The package integrity of the gem a client asked for as `full_name`.
+    /// Clients name ruby-platform gems without a suffix and native gems
+    /// with the platform as RubyGems formats it, and the index's own
+    /// spelling resolves too.
+    fn gem(&self, full_name: &str) -> Option<&Integrity> {
+        self.gems.get(full_name).or_else(|| {
+            self.aliases
+                .get(full_name)
+                .and_then(|full_name| self.gems.get(full_name))
+        })
+    }
+
+    /// The `created_at` of this snapshot's `/versions`, if it lists exactly
+    /// `lines` after its front matter
+    fn versions_created_at(&self, lines: &str) -> Option<String> {
+        let (versions, _) = self.files.get("/versions")?;
+        let (header, listed) = versions.split_once("\n---\n")?;
+        let created_at = header.strip_prefix("created_at: ")?;
+        (listed == lines).then(|| created_at.to_string())
+    }
+
+    /// Adds a file to serve at `path`, returning its checksum
+    fn insert_file(&mut self, path: String, contents: String) -> String {
+        let checksum = md5::hex_digest(contents.as_bytes());
+        self.files.insert(path, (contents, checksum.clone()));
+        checksum
+    }
+}
+
+fn version_with_platform(gem: &Gem) -> String {
+    if gem.platform == "ruby" {
+        gem.version.clone()
+    } else {
+        format!("{}-{}", gem.version, gem.platform)
+    }
+}
+
+/// A line of an `/info` file:
+/// `version[-platform] dep:req&req,...|checksum:hex[,ruby:req][,rubygems:req]`
+fn info_line(gem: &Gem) -> String {
+    let dependencies = gem
+        .dependencies
+        .iter()
+        .map(|dependency| format!("{}:{}", dependency.name, dependency.requirements.join("&")))
+        .collect::<Vec<_>>()
+        .join(",");
+    let (_, sha256) = content_address(&gem.package_integrity).to_hex();
+    let mut line = format!(
+        "{} {}|checksum:{}",
+        version_with_platform(gem),
+        dependencies,
+        sha256
+    );
+    if let Some(ruby) = &gem.required_ruby {
+        line.push_str(&format!(",ruby:{}", ruby));
+    }
+    if let Some(rubygems) = &gem.required_rubygems {
+        line.push_str(&format!(",rubygems:{}", rubygems));
+    }
+    line
+}
+
+pub struct Server {
+    store: FsStore,
+    store_id: Option<String>,
+    /// The `created_at` to stamp `/versions` with, from
+    /// `--versions-created-at`
+    versions_created_at: Option<String>,
+    snapshot: RwLock<Arc<Snapshot>>,
+}
+
+impl Server {
+    pub fn new(store: FsStore, versions_created_at: Option<String>) -> miette::Result<Self> {
+        let server = Self {
+            store_id: store.store_id()?,
+            versions_created_at,
+            store,
+            snapshot: Default::default(),
+        };
+        server.reload()?;
+        Ok(server)
+    }
+
+    /// Re-reads the indices and swaps in a fresh snapshot of them
+    pub fn reload(&self) -> miette::Result<()> {
+        let previous = Arc::clone(&self.snapshot.read().unwrap());
+        let snapshot = Snapshot::build(
+            &self.store.list_indices()?,
+            Some(&previous),
+            self.versions_created_at.as_deref(),
+        );
+        println!(
+            "Serving {} gems in {} namespaces",
+            snapshot.gems.len(),
+            snapshot.files.len() - 2
+        );
+        *self.snapshot.write().unwrap() = Arc::new(snapshot);
+        Ok(())
+    }
+
+    /// Accepts connections forever, handling each on its own thread
+    pub fn run(self: Arc<Self>, listener: TcpListener) -> miette::Result<()> {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("Failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+            let server = Arc::clone(&self);
+            std::thread::spawn(move || {
+                if let Err(e) = server.handle(stream) {
+                    eprintln!("{:?}", e);
+                }
+            });
+        }
+        Err(miette!("Listener closed"))
+    }
+
+    fn handle(&self, stream: TcpStream) -> miette::Result<()> {
+        let io_error = |e| miette!("Failed to handle request: {}", e);
+        stream
+            .set_read_timeout(Some(READ_TIMEOUT))
+            .and_then(|()| stream.set_write_timeout(Some(WRITE_TIMEOUT)))
+            .map_err(io_error)?;
+        let mut reader = BufReader::new(stream.try_clone().map_err(io_error)?.take(MAX_HEAD_LEN));
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).map_err(io_error)?;
+        let mut if_none_match = None;
+        let mut complete = false;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).map_err(io_error)? == 0 {
+                break;
+            }
+            if line.trim().is_empty() {
+                complete = true;
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':')
+                && name.eq_ignore_ascii_case("if-none-match")
+            {
+                if_none_match = Some(value.trim().to_string());
+            }
+        }
+
+        let mut response = Response {
+            stream,
+            head: false,
+            store_id: self.store_id.as_deref(),
+        };
+        if !complete {
+            // Either the client gave up, or its headers ran past the limit
+            return match reader.get_ref().limit() {
+                0 => response.send(431, "text/plain", None, b"Request Headers Too Large\n"),
+                _ => Ok(()),
+            };
+        }
+
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().unwrap_or_default();
+        let path = parts.next().unwrap_or_default();
+        let path = path.split('?').next().unwrap_or_default();
+        response.head = method == "HEAD";
+        if method != "GET" && method != "HEAD" {
+            return response.send(405, "text/plain", None, b"Method Not Allowed\n");
+        }
+
+        if path == "/health" {
+            return match self.store.healthcheck() {
+                Ok(()) => response.send(200, "text/plain", None, b"ok\n"),
+                Err(e) => {
+                    let causes = e.chain().map(ToString::to_string).collect::<Vec<_>>();
+                    let body = format!("{}\n", causes.join(": "));
+                    response.send(503, "text/plain", None, body.as_bytes())
+                }
+            };
+        }
+
+        // Hold on to this request's snapshot even if a reload swaps it out
+        let snapshot = Arc::clone(&self.snapshot.read().unwrap());
+        if let Some((body, checksum)) = snapshot.files.get(path) {
+            let etag = format!("\"{}\"", checksum);
+            if if_none_match.as_deref() == Some(etag.as_str()) {
+                return response.send(304, "text/plain", Some(&etag), b"");
+            }
+            return response.send(
+                200,
+                "text/plain; charset=utf-8",
+                Some(&etag),
+                body.as_bytes(),
+            );
+        }
+
+        let location = path
+            .strip_prefix("/gems/")
+            .and_then(|file| file.strip_suffix(".gem"))
+            .and_then(|full_name| snapshot.gem(full_name))
+            .and_then(|integrity| self.store.blob_location(integrity));
+        match location {
+            Some(location) => response.send_file(&location),
+            None => response.send(404, "text/plain", None, b"Not Found\n"),
+        }
+    }
+}
+
+struct Response<'a> {
+    stream: TcpStream,
+    /// Whether to leave out the body, for `HEAD` requests
+    head: bool,
+    store_id: Option<&'a str>,
+}
+
+impl Response<'_> {
+    fn write_head(
+        &mut self,
+        status: u16,
+        content_type: &str,
+        etag: Option<&str>,
+        len: u64,
+    ) -> std::io::Result<()> {
+        let reason = match status {
+            200 => "OK",
+            304 => "Not Modified",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            431 => "Request Header Fields Too Large",
+            503 => "Service Unavailable",
+            _ => "Internal Server Error",
+        };
+        write!(self.stream, "HTTP/1.1 {} {}\r\n", status, reason)?;
+        write!(self.stream, "Server: {}\r\n", SERVER)?;
+        if let Some(store_id) = self.store_id {
+            write!(self.stream, "{}: {}\r\n", STORE_ID_HEADER, store_id)?;
+        }
+        write!(self.stream, "Content-Type: {}\r\n", content_type)?;
+        write!(self.stream, "Content-Length: {}\r\n", len)?;
+        if let Some(etag) = etag {
+            write!(self.stream, "ETag: {}\r\n", etag)?;
+        }
+        write!(self.stream, "Connection: close\r\n\r\n")
+    }
+
+    fn send(
+        &mut self,
+        status: u16,
+        content_type: &str,
+        etag: Option<&str>,
+        body: &[u8],
+    ) -> miette::Result<()> {
+        let body = if status == 304 { &[][..] } else { body };
+        self.write_head(status, content_type, etag, body.len() as u64)
+            .and_then(|()| match self.head {
+                true => Ok(()),
+                false => self.stream.write_all(body),
+            })
+            .map_err(|e| miette!("Failed to send response: {}", e))
+    }
+
+    fn send_file(&mut self, path: &std::path::Path) -> miette::Result<()> {
+        let send_error = |e| miette!("Failed to send {}: {}", path.display(), e);
+        let mut file = File::open(path).map_err(send_error)?;
+        let len = file.metadata().map_err(send_error)?.len();
+        self.write_head(200, "application/octet-stream", None, len)
+            .map_err(send_error)?;
+        if !self.head {
+            std::io::copy(&mut file, &mut self.stream).map_err(send_error)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{Namespace, parse_info_line};
+
+    /// A snapshot of one stored namespace, each version with a checksum of
+    /// its own
+    fn snapshot(name: &str, lines: &[&str]) -> Snapshot {
+        let mut namespace = Namespace::default();
+        for (i, line) in lines.iter().enumerate() {
+            let line = format!("{} |checksum:{:064x}", line, i + 1);
+            let mut gem = parse_info_line(name, &line).unwrap();
+            gem.stored = true;
+            namespace.versions.insert(gem.full_name.clone(), gem);
+        }
+        let mut index = Index::default();
+        index.gems.insert(name.to_string(), namespace);
+        Snapshot::build(&[index], None, None)
+    }
+
+    #[test]
+    fn ruby_gems_resolve_with_or_without_a_suffix() {
+        let snapshot = snapshot("rack", &["3.0.0"]);
+        assert!(snapshot.gem("rack-3.0.0").is_some());
+        assert!(snapshot.gem("rack-3.0.0-ruby").is_some());
+        assert!(snapshot.gem("rack-3.0.0-java").is_none());
+    }
+
+    #[test]
+    fn native_gems_resolve_by_either_platform_spelling() {
+        let snapshot = snapshot("nokogiri", &["1.15.0", "1.15.0-i686-linux", "1.15.0-java"]);
+        assert_eq!(
+            snapshot.gem("nokogiri-1.15.0-i686-linux"),
+            snapshot.gem("nokogiri-1.15.0-x86-linux")
+        );
+        assert!(snapshot.gem("nokogiri-1.15.0-x86-linux").is_some());
+        assert_ne!(
+            snapshot.gem("nokogiri-1.15.0-x86-linux"),
+            snapshot.gem("nokogiri-1.15.0")
+        );
+        assert!(snapshot.gem("nokogiri-1.15.0-java").is_some());
+        assert!(snapshot.gem("nokogiri-1.15.0-x86_64-linux").is_none());
+    }
+
+    #[test]
+    fn info_lines_keep_ruby_and_rubygems_requirements() {
+        let line = format!(
+            "7.0.0 rack:>= 2.2&< 4|checksum:{:064x},ruby:>= 2.7,rubygems:>= 3.0&< 4",
+            1
+        );
+        let gem = parse_info_line("rails", &line).unwrap();
+        assert_eq!(gem.required_ruby.as_deref(), Some(">= 2.7"));
+        assert_eq!(gem.required_rubygems.as_deref(), Some(">= 3.0&< 4"));
+        assert_eq!(info_line(&gem), line);
+
+        let line = format!("1.0.0 |checksum:{:064x}", 1);
+        assert_eq!(info_line(&parse_info_line("rake", &line).unwrap()), line);
+    }
+}
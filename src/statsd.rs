@@ -0,0 +1,50 @@
+//! Fire-and-forget StatsD metrics for update runs.
+//!
+//! Metrics are sent over UDP, one datagram per metric, and send failures are
+//! ignored so a missing collector never slows down or breaks a sync.
+
+use std::{net::UdpSocket, time::Duration};
+
+use miette::miette;
+
+/// A StatsD client, or a no-op if no endpoint was configured
+#[derive(Debug, Default)]
+pub struct StatsD {
+    socket: Option<UdpSocket>,
+    prefix: String,
+}
+
+impl StatsD {
+    pub fn new(addr: Option<&str>, prefix: &str) -> miette::Result<Self> {
+        let Some(addr) = addr else {
+            return Ok(Self::default());
+        };
+        let socket = UdpSocket::bind("0.0.0.0:0")
+            .and_then(|socket| socket.connect(addr).map(|()| socket))
+            .map_err(|e| miette!("Failed to connect to StatsD at {}: {}", addr, e))?;
+        Ok(Self {
+            socket: Some(socket),
+            prefix: prefix.to_string(),
+        })
+    }
+
+    pub fn count(&self, name: &str, value: u64) {
+        self.send(name, &value.to_string(), "c");
+    }
+
+    pub fn timing(&self, name: &str, elapsed: Duration) {
+        self.send(name, &elapsed.as_millis().to_string(), "ms");
+    }
+
+    fn send(&self, name: &str, value: &str, kind: &str) {
+        let Some(socket) = &self.socket else {
+            return;
+        };
+        let metric = if self.prefix.is_empty() {
+            format!("{}:{}|{}", name, value, kind)
+        } else {
+            format!("{}.{}:{}|{}", self.prefix, name, value, kind)
+        };
+        let _ = socket.send(metric.as_bytes());
+    }
+}
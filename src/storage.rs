@@ -1,16 +1,37 @@
 use std::{
+    borrow::Cow,
     cell::RefCell,
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet},
     ffi::OsStr,
     fs::{self},
-    io::Read as _,
+    io::Read,
     path::{Path, PathBuf},
-    sync::RwLock,
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc,
+    },
+    time::{Duration, Instant},
 };
 
+use base64::{Engine as _, prelude::BASE64_STANDARD};
 use miette::{bail, miette};
-use serde::{Deserialize, Serialize};
-use ssri::Integrity;
+use percent_encoding::{AsciiSet, CONTROLS, utf8_percent_encode};
+use reqwest::header::{
+    ACCEPT, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, HeaderMap, HeaderValue,
+    IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED, RANGE, SERVER,
+};
+use serde::{
+    Deserialize, Serialize,
+    de::{self, DeserializeSeed, MapAccess, SeqAccess, Visitor},
+};
+use ssri::{Algorithm, Integrity, IntegrityOpts};
+
+use crate::http::{Fetcher, ReadError, ResolveOverride, RetrySummary, STORE_ID_HEADER, read_text};
+use crate::md5;
+use crate::merge_log::MergeRecord;
+use crate::platform::Platform;
+use crate::progress::Progress;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Gem {
@@ -19,8 +40,103 @@ pub struct Gem {
     pub version: String,
     pub platform: String,
     pub package_integrity: Integrity,
+    /// The integrity of the extracted `metadata.gz`. It may be kept inline
+    /// rather than as a blob, so it is only ever read through `metadata_gz`.
+    #[serde(default)]
     metadata_gz_integrity: Option<Integrity>,
+    /// `metadata.gz` itself, base64-encoded, when `--store-blob-threshold`
+    /// kept it in the index instead of in a blob of its own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    metadata_gz_inline: Option<String>,
     pub stored: bool,
+    #[serde(default)]
+    pub dependencies: Vec<Dependency>,
+    /// Whether the version has disappeared upstream but was kept by
+    /// `--mark-yanked`
+    #[serde(default)]
+    pub yanked: bool,
+    /// Integrities the version had before `--refetch-changed-blobs` picked
+    /// up a republish, oldest first. Their blobs are kept.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub previous_integrities: Vec<Integrity>,
+    /// The `YYYY-MM-DD` date from the gem's metadata, once a download under
+    /// `--published-after`/`--published-before` has read it, so a version
+    /// outside the window isn't downloaded again just to be discarded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub published: Option<String>,
+    /// The `ruby:` requirement from the version's `/info` line, its
+    /// requirements joined with `&` as upstream writes them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub required_ruby: Option<String>,
+    /// The `rubygems:` requirement from the version's `/info` line
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub required_rubygems: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Dependency {
+    pub name: String,
+    pub requirements: Vec<String>,
+}
+
+impl Gem {
+    /// The integrity of the `metadata.gz` blob in the store, if metadata was
+    /// extracted and isn't kept inline, for accounting for the blobs on disk.
+    /// Reading the metadata goes through `metadata_gz`.
+    pub fn metadata_gz_blob(&self) -> Option<&Integrity> {
+        self.metadata_gz_integrity
+            .as_ref()
+            .filter(|_| self.metadata_gz_inline.is_none())
+    }
+
+    /// The extracted `metadata.gz`, from the index if it is kept inline and
+    /// from `store` otherwise. This is the only way to read it: inline
+    /// metadata has no blob, so `Store::get_blob` can't find it.
+    pub fn metadata_gz<T: Store>(&self, store: &T) -> miette::Result<Option<Vec<u8>>> {
+        let Some(integrity) = &self.metadata_gz_integrity else {
+            return Ok(None);
+        };
+        let Some(inline) = &self.metadata_gz_inline else {
+            return store.get_blob(integrity).map(Some);
+        };
+        let metadata = BASE64_STANDARD
+            .decode(inline)
+            .map_err(|e| miette!("Invalid inline metadata.gz for {}: {}", self.full_name, e))?;
+        integrity.check(&metadata).map_err(|e| {
+            miette!(
+                "Inline metadata.gz for {} is corrupt: {}",
+                self.full_name,
+                e
+            )
+        })?;
+        Ok(Some(metadata))
+    }
+
+    /// Whether the extracted `metadata.gz` is kept in the index
+    fn has_inline_metadata(&self) -> bool {
+        self.metadata_gz_inline.is_some()
+    }
+
+    /// Moves the extracted metadata of `other` onto this version, when both
+    /// have the same contents
+    fn take_metadata(&mut self, other: &mut Gem) {
+        self.metadata_gz_integrity = other.metadata_gz_integrity.take();
+        self.metadata_gz_inline = other.metadata_gz_inline.take();
+    }
+
+    fn set_metadata(&mut self, metadata: Option<Metadata>) {
+        (self.metadata_gz_integrity, self.metadata_gz_inline) = match metadata {
+            Some(metadata) => (Some(metadata.integrity), metadata.inline),
+            None => (None, None),
+        };
+    }
+}
+
+/// An extracted `metadata.gz`: its integrity, and its base64-encoded content
+/// if it is small enough to keep in the index
+struct Metadata {
+    integrity: Integrity,
+    inline: Option<String>,
 }
 
 impl PartialEq for Gem {
@@ -48,22 +164,141 @@ pub struct Namespace {
     name: String,
     info_checksum: String,
     pub versions: HashMap<String, Gem>,
+    /// When an update last confirmed this namespace is current, in seconds
+    /// since the Unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_updated: Option<u64>,
+    /// Lines of the `/info` this was just parsed from that were skipped, so
+    /// versions upstream still lists may be missing from it
+    #[serde(skip)]
+    skipped_lines: usize,
 }
 
 impl Namespace {
-    fn merge(&mut self, other: &Namespace) {
+    /// The checksum of the `/info` file this namespace was parsed from
+    pub(crate) fn info_checksum(&self) -> &str {
+        &self.info_checksum
+    }
+
+    pub(crate) fn merge(&mut self, other: &Namespace) {
         let mut versions = other.versions.clone();
         for (key, version) in self.versions.iter() {
-            if let Some(other_version) = versions.get(key) {
+            if let Some(other_version) = versions.get_mut(key) {
                 if version.stored && !other_version.stored {
-                    versions.insert(key.clone(), version.clone());
+                    *other_version = version.clone();
+                } else {
+                    // Upstream's requirements are the current ones
+                    other_version.required_ruby = version.required_ruby.clone();
+                    other_version.required_rubygems = version.required_rubygems.clone();
                 }
             } else {
                 versions.insert(key.clone(), version.clone());
             }
+            // Listed upstream again, so no longer yanked
+            if let Some(version) = versions.get_mut(key) {
+                version.yanked = false;
+            }
         }
         self.versions = versions;
     }
+
+    /// Like `merge`, but drops the versions of `other` that are no longer in
+    /// `self`, for mirrors that should follow yanks upstream.
+    ///
+    /// With `mark_yanked`, those versions are kept but flagged as yanked.
+    fn reconcile(&mut self, other: &Namespace, mark_yanked: bool) {
+        for (key, version) in self.versions.iter_mut() {
+            let Some(other_version) = other.versions.get(key) else {
+                continue;
+            };
+            if other_version.stored && !version.stored {
+                *version = Gem {
+                    yanked: false,
+                    required_ruby: version.required_ruby.take(),
+                    required_rubygems: version.required_rubygems.take(),
+                    ..other_version.clone()
+                };
+            } else if other_version.package_integrity == version.package_integrity {
+                version.published = version
+                    .published
+                    .take()
+                    .or_else(|| other_version.published.clone());
+            }
+        }
+        for (key, version) in other.versions.iter() {
+            if self.versions.contains_key(key) {
+                continue;
+            }
+            if mark_yanked {
+                if !version.yanked {
+                    println!("Marked {} as yanked", key);
+                }
+                let version = Gem {
+                    yanked: true,
+                    ..version.clone()
+                };
+                self.versions.insert(key.clone(), version);
+            } else {
+                println!("Removed {}", key);
+            }
+        }
+    }
+
+    /// Applies `policy` to the versions of a merged namespace that
+    /// `upstream` lists differently. Under `KeepLocal` the merge already did
+    /// everything; `Fail` errors on a stored version whose upstream package
+    /// integrity differs; `PreferRemote` takes every upstream copy, keeping
+    /// the stored blob of unchanged versions and refetching changed ones
+    /// while remembering their old integrity.
+    fn resolve_conflicts(
+        &mut self,
+        upstream: HashMap<String, Gem>,
+        policy: ConflictPolicy,
+    ) -> miette::Result<()> {
+        for (full_name, mut remote) in upstream {
+            let Some(version) = self.versions.get_mut(&full_name) else {
+                continue;
+            };
+            let conflict = version.package_integrity != remote.package_integrity;
+            match policy {
+                ConflictPolicy::KeepLocal => {}
+                ConflictPolicy::Fail if conflict && version.stored => bail!(
+                    "{} was republished upstream with different contents ({} -> {}); rerun with --on-conflict keep-local or prefer-remote to pick a copy",
+                    full_name,
+                    version.package_integrity,
+                    remote.package_integrity
+                ),
+                ConflictPolicy::Fail => {}
+                ConflictPolicy::PreferRemote if conflict => {
+                    eprintln!(
+                        "WARNING: {} was republished upstream with different contents ({} -> {}); refetching it",
+                        full_name, version.package_integrity, remote.package_integrity
+                    );
+                    remote.previous_integrities = std::mem::take(&mut version.previous_integrities);
+                    remote
+                        .previous_integrities
+                        .push(version.package_integrity.clone());
+                    *version = remote;
+                }
+                ConflictPolicy::PreferRemote => {
+                    remote.stored = version.stored;
+                    remote.take_metadata(version);
+                    remote.previous_integrities = std::mem::take(&mut version.previous_integrities);
+                    *version = remote;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Flags every version as yanked, for a namespace no longer listed
+    /// upstream at all
+    fn mark_yanked(&mut self) {
+        for version in self.versions.values_mut().filter(|version| !version.yanked) {
+            println!("Marked {} as yanked", version.full_name);
+            version.yanked = true;
+        }
+    }
 }
 
 impl PartialEq for Namespace {
@@ -86,7 +321,117 @@ impl Ord for Namespace {
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Index {
     pub source: String,
+    #[serde(flatten)]
+    pub config: IndexConfig,
+    /// How much of `/versions` has been processed, for incremental updates
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub versions_cursor: Option<VersionsCursor>,
+    /// Namespaces whose `/info` failed to sync in the last update
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failed_namespaces: Vec<String>,
+    /// Full names removed by `purge`, which updates never add back
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub purged: Vec<String>,
     pub gems: HashMap<String, Namespace>,
+    /// Namespaces `--drop-empty-namespaces` removed during this update
+    #[serde(skip)]
+    dropped_namespaces: Vec<String>,
+}
+
+/// The end of the part of an index's `/versions` that has been fully synced.
+///
+/// `/versions` is append-only between periodic rewrites, so the next update
+/// only needs to fetch and diff the bytes after `len`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VersionsCursor {
+    pub etag: Option<String>,
+    pub len: u64,
+}
+
+/// What fetching `/versions` from a cursor turned up
+enum VersionsListing {
+    /// The whole file
+    Full(String),
+    /// Only the lines appended since the cursor
+    Appended(String),
+    /// Nothing has changed since the cursor
+    Unchanged,
+}
+
+impl Index {
+    /// Whether the index's filters let `name` be mirrored
+    pub fn allows(&self, name: &str) -> bool {
+        self.config.filters.is_empty()
+            || self
+                .config
+                .filters
+                .iter()
+                .any(|pattern| glob_match(pattern, name))
+    }
+}
+
+/// How to talk to an index's source, as set by `add-index`
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct IndexConfig {
+    /// Credentials sent with every request to the source. These are stored
+    /// in plain text in `indices.json`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth: Option<Auth>,
+    /// Glob patterns of gem names to mirror; empty means every gem
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub filters: Vec<String>,
+    /// A cacache directory to keep this index's blobs in, apart from the
+    /// store's own, e.g. on another disk
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blob_root: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Auth {
+    Basic {
+        username: String,
+        password: Option<String>,
+    },
+    Bearer {
+        token: String,
+    },
+    /// A RubyGems API key, sent as a bare `Authorization` header like
+    /// `gem push` does
+    ApiKey {
+        key: String,
+    },
+}
+
+/// Matches `name` against a glob where `*` matches any run of characters and
+/// `?` matches exactly one
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+    let (mut p, mut n) = (0, 0);
+    // Where to resume after the last `*` if the rest fails to match
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    n = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
 }
 
 impl PartialEq for Index {
@@ -108,324 +453,4883 @@ impl Ord for Index {
     }
 }
 
-pub trait Store {
-    fn list_indices(&self) -> miette::Result<Vec<Index>>;
-    fn add_index(&mut self, source: String) -> miette::Result<()> {
-        self.with_indices(|_, indices| {
-            if indices.iter().any(|i| i.source == source) {
-                return Ok(());
-            }
-            indices.push(Index {
-                source,
-                gems: Default::default(),
-            });
-            Ok(())
-        })?;
-        Ok(())
+/// The current format of `indices.json`.
+///
+/// Bump this when a change can't be handled by `#[serde(default)]` alone, and
+/// teach `read_indices` to upgrade older formats.
+pub const INDICES_FORMAT_VERSION: u32 = 1;
+
+/// The serialized form of the indices, in any format version we can read
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum IndicesFile {
+    Versioned {
+        version: u32,
+        indices: Vec<Index>,
+    },
+    /// Before format versioning, the file was a bare array of indices
+    Unversioned(Vec<Index>),
+}
+
+#[derive(Serialize)]
+struct IndicesFileRef<'a> {
+    version: u32,
+    indices: &'a [Index],
+}
+
+pub fn read_indices<R: std::io::Read>(reader: R) -> miette::Result<Vec<Index>> {
+    let file =
+        serde_json::from_reader(reader).map_err(|e| miette!("Failed to parse indices: {}", e))?;
+    match file {
+        IndicesFile::Unversioned(indices) => Ok(indices),
+        IndicesFile::Versioned { version, indices } if version <= INDICES_FORMAT_VERSION => {
+            Ok(indices)
+        }
+        IndicesFile::Versioned { version, .. } => bail!(
+            "Indices are in format version {}, but this gem-mirror only understands up to {}",
+            version,
+            INDICES_FORMAT_VERSION
+        ),
     }
-    fn store_blob<B: AsRef<[u8]>>(&self, blob: B) -> miette::Result<Integrity>;
-    fn get_blob(&self, sha256: &Integrity) -> miette::Result<Vec<u8>>;
-    fn has_blob(&self, sha256: &Integrity) -> bool {
-        let blobs = self.get_blob(sha256);
-        blobs.is_ok()
+}
+
+/// The format version of a serialized `indices.json`, read from its first
+/// bytes without parsing the rest. `write_indices` puts the version first;
+/// an unversioned file is a bare array, and counts as version 0.
+fn read_indices_version<R: std::io::Read>(reader: R) -> miette::Result<u32> {
+    let mut head = Vec::new();
+    reader
+        .take(64)
+        .read_to_end(&mut head)
+        .map_err(|e| miette!("Failed to read indices: {}", e))?;
+    let head = String::from_utf8_lossy(&head);
+    let head = head.trim_start();
+    if head.starts_with('[') {
+        return Ok(0);
     }
-    fn with_indices<F>(&mut self, f: F) -> miette::Result<Vec<Index>>
-    where
-        F: FnOnce(&mut Self, &mut Vec<Index>) -> miette::Result<()>;
+    let version = head
+        .strip_prefix("{\"version\":")
+        .map(|rest| rest.trim_start())
+        .and_then(|rest| {
+            let digits = rest.find(|c: char| !c.is_ascii_digit())?;
+            rest[..digits].parse::<u32>().ok()
+        })
+        .ok_or_else(|| miette!("Failed to find the format version of the indices"))?;
+    if version > INDICES_FORMAT_VERSION {
+        bail!(
+            "Indices are in format version {}, but this gem-mirror only understands up to {}",
+            version,
+            INDICES_FORMAT_VERSION
+        );
+    }
+    Ok(version)
 }
 
-pub fn update_store<T: Store>(mut store: T) -> miette::Result<()> {
-    store.with_indices(|store, indices| {
-        for index in indices {
-            println!("Index source: {}", index.source);
-            let mut versions_url = index.source.clone();
-            versions_url.push_str("/versions");
-            let resp = reqwest::blocking::get(&versions_url).unwrap();
-            if resp.status() != reqwest::StatusCode::OK {
-                bail!("Failed to fetch {}: {}", versions_url, resp.status());
-            }
-            let text = resp.text().unwrap();
-            let mut versions = text.lines().collect::<Vec<_>>();
-            if let Some((idx, _)) = versions
-                .iter()
-                .enumerate()
-                .find(|(_, name)| **name == "---")
-            {
-                versions = versions[idx + 1..].to_vec();
-            } else {
-                bail!("Failed to find separator in versions");
-            }
-            let versions = {
-                let mut h = HashMap::<&str, &str>::new();
-                for line in versions {
-                    let parts = line.split(" ").collect::<Vec<_>>();
-                    let name = parts[0];
-                    let info_checksum = parts[parts.len() - 1];
-                    h.insert(name, info_checksum);
-                }
-                h
-            };
-            for (name, info_checksum) in versions {
-                let existing = index.gems.get(name);
-                if let Some(existing) = existing {
-                    if existing.info_checksum == info_checksum
-                        || (existing.info_checksum.starts_with('"')
-                            && existing.info_checksum.ends_with('"')
-                            && existing.info_checksum[1..existing.info_checksum.len() - 1]
-                                == *info_checksum)
-                    {
-                        continue;
-                    }
-                }
-                if existing.is_some_and(|n| n.info_checksum == info_checksum) {
-                    // println!("Already stored {} {}", name, info_checksum);
-                    continue;
-                } else {
-                    // eprintln!("New gem: {} {} vs {:?}", name, info_checksum, existing);
-                }
+/// Calls `f` with the source and each gem of every index in a serialized
+/// `indices.json` as it is parsed, so only one gem is in memory at a time
+/// instead of every index.
+///
+/// Gems come in file order. Each index's `source` has to come before its
+/// `gems`, as `write_indices` writes them.
+pub fn for_each_gem_in<R: std::io::Read>(
+    reader: R,
+    mut f: impl FnMut(&str, &Gem) -> miette::Result<()>,
+) -> miette::Result<()> {
+    walk_indices(reader, &mut f, false).map(|_| ())
+}
 
-                let gem_url = format!("{}/info/{}", index.source, name);
-                let resp = match reqwest::blocking::get(&gem_url) {
-                    Ok(resp) => resp,
-                    Err(e) => {
-                        eprintln!("Failed to fetch {}: {}", gem_url, e);
-                        continue;
-                    }
-                };
-                let mut info_checksum = resp.headers().get("ETag").unwrap().to_str().unwrap();
-                info_checksum = info_checksum.trim_start_matches("W/");
-                info_checksum = info_checksum.trim_matches('"');
-                let info_checksum = info_checksum.to_string();
+/// The `blob_root`s of the indices in a serialized `indices.json`, read
+/// without holding any gems in memory
+fn read_blob_roots<R: std::io::Read>(reader: R) -> miette::Result<Vec<PathBuf>> {
+    walk_indices(reader, &mut |_, _| Ok(()), true)
+}
 
-                if resp.status() != reqwest::StatusCode::OK {
-                    bail!("Failed to fetch gem");
-                }
-                let text = resp.text().unwrap();
-                let mut versions = text.lines().collect::<Vec<_>>();
-                if let Some((idx, _)) = versions
-                    .iter()
-                    .enumerate()
-                    .find(|(_, name)| **name == "---")
-                {
-                    versions = versions[idx + 1..].to_vec();
-                } else {
-                    bail!("Failed to find separator in info for {}", name);
-                }
-                let versions = versions
-                    .iter()
-                    .map(|line| parse_info_line(name, line).map(|gem| (gem.full_name.clone(), gem)))
-                    .collect::<miette::Result<HashMap<_, _>>>()?;
+fn walk_indices<R: std::io::Read>(
+    reader: R,
+    f: &mut dyn FnMut(&str, &Gem) -> miette::Result<()>,
+    skip_gems: bool,
+) -> miette::Result<Vec<PathBuf>> {
+    let mut walker = GemWalker {
+        f,
+        stopped: None,
+        skip_gems,
+        blob_roots: Vec::new(),
+    };
+    let mut deserializer = serde_json::Deserializer::from_reader(reader);
+    let result = IndicesSeed(&mut walker)
+        .deserialize(&mut deserializer)
+        .and_then(|()| deserializer.end());
+    if let Some(error) = walker.stopped {
+        return Err(error);
+    }
+    result.map_err(|e| miette!("Failed to parse indices: {}", e))?;
+    Ok(walker.blob_roots)
+}
 
-                let mut namespace = Namespace {
-                    name: name.to_string(),
-                    info_checksum,
-                    versions,
-                };
+/// The state shared by the seeds `for_each_gem_in` parses with. An error
+/// from `f` is kept in `stopped`, since serde errors can only carry a message.
+struct GemWalker<'a> {
+    f: &'a mut dyn FnMut(&str, &Gem) -> miette::Result<()>,
+    stopped: Option<miette::Report>,
+    /// Skip over the gems, only collecting `blob_roots`
+    skip_gems: bool,
+    blob_roots: Vec<PathBuf>,
+}
 
-                if let Some(existing) = existing {
-                    if existing.info_checksum != namespace.info_checksum {
-                        println!(
-                            "Checksum mismatch for {}: {} vs {}",
-                            name, existing.info_checksum, namespace.info_checksum
-                        );
-                        namespace.merge(existing);
-                    } else {
-                        println!("No changes for {}", name);
-                    }
-                } else {
-                    println!("New namespace: {}", name);
-                    index.gems.insert(name.to_string(), namespace);
-                }
-            }
+impl GemWalker<'_> {
+    fn stop<E: de::Error>(&mut self, error: miette::Report) -> E {
+        let message = E::custom(&error);
+        self.stopped = Some(error);
+        message
+    }
+}
 
-            let gems = &mut index.gems;
+/// Either the bare array of indices or the versioned object around it
+struct IndicesSeed<'w, 'a>(&'w mut GemWalker<'a>);
 
-            for (_, gem) in gems.iter_mut() {
-                for (_, version) in gem.versions.iter_mut() {
-                    if version.stored {
-                        println!("Already stored {}", version.full_name);
-                        continue;
-                    }
+impl<'de> DeserializeSeed<'de> for IndicesSeed<'_, '_> {
+    type Value = ();
 
-                    if !store.has_blob(&version.package_integrity) {
-                        println!("Fetching blob for {}", version.full_name);
-                        let blob_url = format!("{}/gems/{}.gem", index.source, version.full_name);
-                        let resp = reqwest::blocking::get(&blob_url).unwrap();
-                        if resp.status() != reqwest::StatusCode::OK {
-                            bail!("Failed to fetch blob");
-                        }
-                        let blob = resp.bytes().unwrap();
-                        let integrity = store.store_blob(&blob)?;
-                        integrity.matches(&version.package_integrity).unwrap();
-                    }
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
 
-                    // Extract metadata from the blob
-
-                    let dot_gem = store.get_blob(&version.package_integrity)?;
-                    let mut archive = tar::Archive::new(dot_gem.as_slice());
-                    let mut metadata = None;
-                    for entry in archive.entries().unwrap() {
-                        let mut entry = entry.unwrap();
-                        if entry.path().unwrap().as_ref() == OsStr::new("metadata.gz") {
-                            let mut buf = Vec::new();
-                            entry.read_to_end(&mut buf).unwrap();
-                            metadata = Some(buf);
-                            break;
-                        }
-                    }
-                    if metadata.is_none() {
-                        bail!(
-                            "Failed to find metadata.gz in blob for {}",
-                            version.full_name
-                        );
-                    }
-                    let metadata_gz_integrity = store.store_blob(metadata.unwrap())?;
+impl<'de> Visitor<'de> for IndicesSeed<'_, '_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("indices")
+    }
 
-                    version.metadata_gz_integrity = Some(metadata_gz_integrity.clone());
-                    version.stored = true;
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        while seq.next_element_seed(IndexSeed(self.0))?.is_some() {}
+        Ok(())
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "version" => {
+                    let version = map.next_value::<u32>()?;
+                    if version > INDICES_FORMAT_VERSION {
+                        return Err(self.0.stop(miette!(
+                            "Indices are in format version {}, but this gem-mirror only understands up to {}",
+                            version,
+                            INDICES_FORMAT_VERSION
+                        )));
+                    }
+                }
+                "indices" => map.next_value_seed(IndicesSeed(&mut *self.0))?,
+                _ => {
+                    map.next_value::<de::IgnoredAny>()?;
                 }
             }
         }
-
         Ok(())
-    })?;
-
-    Ok(())
+    }
 }
 
-fn parse_info_line(name: &str, line: &str) -> miette::Result<Gem> {
-    let (version, rest) = line
-        .split_once(" ")
-        .ok_or_else(|| miette!("Invalid line format"))?;
+struct IndexSeed<'w, 'a>(&'w mut GemWalker<'a>);
 
-    let (_deps, metadata) = rest
-        .split_once("|")
-        .ok_or_else(|| miette!("Invalid line format"))?;
+impl<'de> DeserializeSeed<'de> for IndexSeed<'_, '_> {
+    type Value = ();
 
-    let (version, platform) = version.split_once("-").unwrap_or((version, "ruby"));
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
 
-    let full_name = if platform == "ruby" {
-        format!("{}-{}", name, version)
-    } else {
-        format!("{}-{}-{}", name, version, platform)
-    };
+impl<'de> Visitor<'de> for IndexSeed<'_, '_> {
+    type Value = ();
 
-    let mut sha256 = String::new();
-    metadata.split(',').for_each(|item| {
-        if item.starts_with("checksum:") {
-            sha256 = item.split(':').nth(1).unwrap_or("").to_string();
-        }
-    });
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("an index")
+    }
 
-    Ok(Gem {
-        full_name: full_name.to_string(),
-        name: name.to_string(),
-        version: version.to_string(),
-        platform: platform.to_string(),
-        package_integrity: Integrity::from_hex(sha256, ssri::Algorithm::Sha256).unwrap(),
-        metadata_gz_integrity: None,
-        stored: false,
-    })
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        let mut source = None;
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "source" => source = Some(map.next_value::<String>()?),
+                "blob_root" => {
+                    if let Some(blob_root) = map.next_value::<Option<PathBuf>>()? {
+                        self.0.blob_roots.push(blob_root);
+                    }
+                }
+                "gems" if self.0.skip_gems => {
+                    map.next_value::<de::IgnoredAny>()?;
+                }
+                "gems" => {
+                    let source = source
+                        .as_deref()
+                        .ok_or_else(|| de::Error::custom("index lists `gems` before `source`"))?;
+                    map.next_value_seed(NamespacesSeed(&mut *self.0, source))?;
+                }
+                _ => {
+                    map.next_value::<de::IgnoredAny>()?;
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
-#[derive(Debug, Default)]
-pub struct MemoryStore {
-    indices: Vec<Index>,
+/// An index's namespaces, by name
+struct NamespacesSeed<'w, 'a, 's>(&'w mut GemWalker<'a>, &'s str);
 
-    blobs: RwLock<RefCell<HashMap<String, Vec<u8>>>>,
-}
+impl<'de> DeserializeSeed<'de> for NamespacesSeed<'_, '_, '_> {
+    type Value = ();
 
-impl Store for MemoryStore {
-    fn list_indices(&self) -> miette::Result<Vec<Index>> {
-        Ok(self.indices.clone())
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
     }
+}
 
-    fn store_blob<B: AsRef<[u8]>>(&self, blob: B) -> miette::Result<Integrity> {
-        let integrity = Integrity::from(blob.as_ref());
-        let blobs = self.blobs.read().unwrap();
-        blobs
-            .borrow_mut()
-            .insert(integrity.to_string(), blob.as_ref().to_vec());
-        Ok(integrity)
+impl<'de> Visitor<'de> for NamespacesSeed<'_, '_, '_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("a map of namespaces")
     }
 
-    fn get_blob(&self, sha256: &Integrity) -> miette::Result<Vec<u8>> {
-        let blobs = self.blobs.read().unwrap();
-        if let Some(blob) = blobs.borrow().get(sha256.to_string().as_str()) {
-            sha256.check(blob.as_slice()).unwrap();
-            Ok(blob.clone())
-        } else {
-            bail!("Blob not found")
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while map.next_key::<de::IgnoredAny>()?.is_some() {
+            map.next_value_seed(NamespaceSeed(&mut *self.0, self.1))?;
         }
+        Ok(())
     }
+}
 
-    fn has_blob(&self, sha256: &Integrity) -> bool {
-        let blobs = self.blobs.read().unwrap();
-        blobs.borrow().contains_key(sha256.to_string().as_str())
-    }
+struct NamespaceSeed<'w, 'a, 's>(&'w mut GemWalker<'a>, &'s str);
 
-    fn with_indices<F>(&mut self, f: F) -> miette::Result<Vec<Index>>
-    where
-        F: FnOnce(&mut Self, &mut Vec<Index>) -> miette::Result<()>,
-    {
-        let mut indices = std::mem::take(&mut self.indices);
-        f(self, &mut indices)?;
-        self.indices = indices;
-        Ok(self.indices.clone())
+impl<'de> DeserializeSeed<'de> for NamespaceSeed<'_, '_, '_> {
+    type Value = ();
+
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
     }
 }
 
-pub struct FsStore {
-    root: PathBuf,
-}
+impl<'de> Visitor<'de> for NamespaceSeed<'_, '_, '_> {
+    type Value = ();
 
-impl FsStore {
-    pub fn new<P: AsRef<Path>>(root: P) -> miette::Result<Self> {
-        let root = PathBuf::from(root.as_ref());
-        std::fs::create_dir_all(&root)
-            .map_err(|e| miette!("Failed to create directory {}: {}", root.display(), e))?;
-        Ok(Self { root })
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("a namespace")
     }
-}
 
-impl Store for FsStore {
-    fn list_indices(&self) -> miette::Result<Vec<Index>> {
-        let path = self.root.join("indices.json");
-        if !path.exists() {
-            return Ok(vec![]);
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some(key) = map.next_key::<String>()? {
+            if key != "versions" {
+                map.next_value::<de::IgnoredAny>()?;
+                continue;
+            }
+            map.next_value_seed(VersionsSeed(&mut *self.0, self.1))?;
         }
-        let file = std::fs::File::open(path).unwrap();
-        let reader = std::io::BufReader::new(file);
-        let indices = serde_json::from_reader(reader).unwrap();
-        Ok(indices)
+        Ok(())
     }
+}
 
-    fn store_blob<B: AsRef<[u8]>>(&self, blob: B) -> miette::Result<Integrity> {
-        cacache::write_hash_sync(&self.root, blob)
-            .map_err(|e| miette!("Failed to store blob: {}", e))
+struct VersionsSeed<'w, 'a, 's>(&'w mut GemWalker<'a>, &'s str);
+
+impl<'de> DeserializeSeed<'de> for VersionsSeed<'_, '_, '_> {
+    type Value = ();
+
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
     }
+}
 
-    fn get_blob(&self, sha256: &Integrity) -> miette::Result<Vec<u8>> {
-        cacache::read_hash_sync(&self.root, sha256)
-            .map_err(|e| miette!("Failed to get blob: {}", e))
+impl<'de> Visitor<'de> for VersionsSeed<'_, '_, '_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("a map of versions")
     }
 
-    fn has_blob(&self, sha256: &Integrity) -> bool {
-        cacache::exists_sync(&self.root, sha256)
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some((_, gem)) = map.next_entry::<de::IgnoredAny, Gem>()? {
+            if let Err(error) = (self.0.f)(self.1, &gem) {
+                return Err(self.0.stop(error));
+            }
+        }
+        Ok(())
     }
+}
 
-    fn with_indices<F>(&mut self, f: F) -> miette::Result<Vec<Index>>
+pub fn write_indices<W: std::io::Write>(writer: W, indices: &[Index]) -> miette::Result<()> {
+    let file = IndicesFileRef {
+        version: INDICES_FORMAT_VERSION,
+        indices,
+    };
+    serde_json::to_writer(writer, &file).map_err(|e| miette!("Failed to write indices: {}", e))
+}
+
+pub trait Store {
+    /// The indices, borrowed from stores that keep them in memory so that
+    /// reading them doesn't copy every gem
+    fn list_indices(&self) -> miette::Result<Cow<'_, [Index]>>;
+    /// The store to write `index`'s blobs to, if it keeps them apart from
+    /// other indices' blobs; `None` means this one
+    fn for_index(&self, _index: &Index) -> Option<Self>
     where
-        F: FnOnce(&mut Self, &mut Vec<Index>) -> miette::Result<()>,
+        Self: Sized,
     {
-        let mut indices = self.list_indices()?;
-        f(self, &mut indices)?;
-        let path = self.root.join("indices.json");
-        let file =
-            fs::File::create(&path).map_err(|e| miette!("Failed to open indices.json: {}", e))?;
-        let writer = std::io::BufWriter::new(file);
-        serde_json::to_writer(writer, &indices)
-            .map_err(|e| miette!("Failed to write indices.json: {}", e))?;
-        Ok(indices)
+        None
+    }
+    /// Calls `f` with the source and every gem of each index, without
+    /// copying or loading every index at once where the store can avoid it
+    fn for_each_gem(
+        &self,
+        mut f: impl FnMut(&str, &Gem) -> miette::Result<()>,
+    ) -> miette::Result<()> {
+        for index in self.list_indices()?.iter() {
+            for namespace in index.gems.values() {
+                for gem in namespace.versions.values() {
+                    f(&index.source, gem)?;
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Adds an index for `source`. If it already exists, this does nothing
+    /// unless `clobber` is set, in which case its configuration is replaced
+    /// and its gems are kept.
+    fn add_index(
+        &mut self,
+        source: String,
+        config: IndexConfig,
+        clobber: bool,
+    ) -> miette::Result<()> {
+        self.with_indices(|_, indices| {
+            if let Some(existing) = indices.iter_mut().find(|i| i.source == source) {
+                if !clobber {
+                    return Ok(());
+                }
+                let mut config = config;
+                match &config.blob_root {
+                    // The stored gems' blobs are in the old blob root, where
+                    // nothing would look for them any more
+                    Some(blob_root)
+                        if existing.config.blob_root.as_ref() != Some(blob_root)
+                            && existing
+                                .gems
+                                .values()
+                                .flat_map(|namespace| namespace.versions.values())
+                                .any(|gem| gem.stored) =>
+                    {
+                        bail!(
+                            "{} has stored gems, so its blob root can't change to {}",
+                            source,
+                            blob_root.display()
+                        );
+                    }
+                    Some(_) => {}
+                    None => config.blob_root = existing.config.blob_root.take(),
+                }
+                existing.config = config;
+                return Ok(());
+            }
+            indices.push(Index {
+                source,
+                config,
+                versions_cursor: None,
+                failed_namespaces: Vec::new(),
+                purged: Vec::new(),
+                gems: Default::default(),
+                dropped_namespaces: Vec::new(),
+            });
+            Ok(())
+        })?;
+        Ok(())
+    }
+    fn store_blob<B: AsRef<[u8]>>(&self, blob: B) -> miette::Result<Integrity>;
+    /// Like `store_blob`, but first checks `blob` against `expected` and
+    /// fails with an `IntegrityMismatch` instead of storing it if it differs
+    fn store_blob_expecting<B: AsRef<[u8]>>(
+        &self,
+        blob: B,
+        expected: &Integrity,
+    ) -> miette::Result<Integrity> {
+        let blob = blob.as_ref();
+        if expected.check(blob).is_err() {
+            let actual = IntegrityOpts::new()
+                .algorithm(expected.pick_algorithm())
+                .chain(blob)
+                .result();
+            return Err(IntegrityMismatch {
+                expected: expected.clone(),
+                actual,
+            }
+            .into());
+        }
+        self.store_blob(blob)
+    }
+    fn get_blob(&self, sha256: &Integrity) -> miette::Result<Vec<u8>>;
+    /// Reads a blob and rehashes it against `sha256`, whatever `get_blob`
+    /// skips for speed, for the commands whose job is finding corruption
+    fn check_blob(&self, sha256: &Integrity) -> miette::Result<()> {
+        let blob = self.get_blob(sha256)?;
+        sha256
+            .check(&blob)
+            .map(|_| ())
+            .map_err(|e| miette!("Blob {} is corrupt: {}", sha256, e))
+    }
+    /// Streams a blob, for callers that may not need all of it. Unlike
+    /// `get_blob`, the content is not checked against `sha256`.
+    fn get_blob_reader(&self, sha256: &Integrity) -> miette::Result<impl Read> {
+        self.get_blob(sha256).map(std::io::Cursor::new)
+    }
+    fn has_blob(&self, sha256: &Integrity) -> bool {
+        let blobs = self.get_blob(sha256);
+        blobs.is_ok()
+    }
+    fn remove_blob(&self, sha256: &Integrity) -> miette::Result<()>;
+    /// The file a present blob is stored in, for tools that read or serve
+    /// blobs directly. This is the one place that knows the on-disk layout;
+    /// stores that don't keep blobs in files return `None`.
+    fn blob_location(&self, _sha256: &Integrity) -> Option<PathBuf> {
+        None
+    }
+    /// Where downloads are written until they are complete and stored, so
+    /// they aren't held in memory
+    fn download_dir(&self) -> PathBuf {
+        std::env::temp_dir()
+    }
+    /// Makes the stored blob of `gem` available as `gems/<full_name>.gem`,
+    /// for browsing the store as files. An existing file is only replaced
+    /// with `replace`. Stores that don't keep blobs in files do nothing.
+    fn link_by_name(&self, _gem: &Gem, _replace: bool) -> miette::Result<()> {
+        Ok(())
+    }
+    /// Removes the `gems/<full_name>.gem` that `link_by_name` made, if any,
+    /// once its version or blob is gone, so the link can't outlive it
+    fn unlink_by_name(&self, _full_name: &str) -> miette::Result<()> {
+        Ok(())
+    }
+    /// An identifier unique to this store, if it has one, so that `serve`
+    /// can advertise it and `update` can refuse to mirror the store into
+    /// itself
+    fn store_id(&self) -> miette::Result<Option<String>> {
+        Ok(None)
+    }
+    /// When each blob, by integrity, last passed a deep `verify`, in seconds
+    /// since the Unix epoch
+    fn verified_blobs(&self) -> miette::Result<HashMap<String, u64>> {
+        Ok(HashMap::new())
+    }
+    fn set_verified_blobs(&self, _verified: &HashMap<String, u64>) -> miette::Result<()> {
+        Ok(())
+    }
+    /// Calls `f` with the indices and saves what it leaves them as, returning
+    /// what `f` does once they are saved
+    fn with_indices<F, R>(&mut self, f: F) -> miette::Result<R>
+    where
+        F: FnOnce(&mut Self, &mut Vec<Index>) -> miette::Result<R>;
+}
+
+/// A blob whose content doesn't hash to the integrity it was expected to have
+#[derive(Debug)]
+pub struct IntegrityMismatch {
+    pub expected: Integrity,
+    pub actual: Integrity,
+}
+
+impl std::fmt::Display for IntegrityMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Integrity mismatch: expected {}, got {}",
+            self.expected, self.actual
+        )
+    }
+}
+
+impl std::error::Error for IntegrityMismatch {}
+
+impl miette::Diagnostic for IntegrityMismatch {}
+
+/// Whose copy of a version wins when upstream lists it differently from the
+/// mirror
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictPolicy {
+    /// Keep stored versions as they are
+    #[default]
+    KeepLocal,
+    /// Take upstream's listing even for stored versions, refetching those
+    /// whose contents changed
+    PreferRemote,
+    /// Abort the update when a stored version's contents changed upstream
+    Fail,
+}
+
+/// Options controlling how `update_store` syncs the store
+#[derive(Debug, Clone, Default, Serialize, clap::Args)]
+pub struct UpdateOptions {
+    /// Only store the `.gem` blobs, skipping `metadata.gz` extraction
+    #[clap(long)]
+    pub no_metadata: bool,
+
+    /// Keep each extracted `metadata.gz` smaller than this many bytes in
+    /// `indices.json`, base64-encoded, instead of as a file of its own, for
+    /// filesystems that handle many small files badly
+    #[clap(long, value_name = "BYTES", conflicts_with = "no_metadata")]
+    pub store_blob_threshold: Option<usize>,
+
+    /// Send a `HEAD` request before downloading each gem and check that the
+    /// download is as long as its `Content-Length` said, to catch proxies
+    /// that answer with a short error body; doubles the requests for blobs
+    #[clap(long)]
+    pub head_check: bool,
+
+    /// Check each version's full name before building its `/gems` URL:
+    /// refuse names with slashes, whitespace or control characters, such as
+    /// those from a malformed platform, and percent-encode any other
+    /// character that isn't safe in a URL, logging either
+    #[clap(long)]
+    pub validate_full_name_against_url: bool,
+
+    /// Also make every stored gem available as `gems/<full_name>.gem` in the
+    /// store directory, hard-linked to its blob where possible so no bytes
+    /// are duplicated
+    #[clap(long)]
+    pub also_link_by_name: bool,
+
+    /// How to treat a version whose upstream listing differs from the one
+    /// already mirrored, above all a stored gem republished with different
+    /// contents
+    #[clap(long, value_enum, default_value_t)]
+    pub on_conflict: ConflictPolicy,
+
+    /// When a version is listed upstream with a different checksum than the
+    /// one stored, fetch the new content instead of keeping the stored copy.
+    /// The old blob is kept and its integrity recorded. The same as
+    /// `--on-conflict prefer-remote`.
+    #[clap(long, conflicts_with = "on_conflict")]
+    pub refetch_changed_blobs: bool,
+
+    /// Decompress each extracted `metadata.gz` and check that it holds the
+    /// gemspec of the right gem, so corrupt metadata fails the update
+    /// instead of the client that reads it
+    #[clap(long, conflicts_with = "no_metadata")]
+    pub validate_metadata: bool,
+
+    /// Re-extract the metadata of already stored gems from their blobs,
+    /// instead of syncing with the indices
+    #[clap(long, conflicts_with_all = ["no_metadata", "reconcile"])]
+    pub refresh_metadata: bool,
+
+    /// Only mirror the gems declared in this Gemfile, plus their dependencies
+    #[clap(long, value_name = "PATH")]
+    pub only_from_gemfile: Option<PathBuf>,
+
+    /// Only mirror the gems pinned by this `Gemfile.lock`, plus their
+    /// dependencies, and only download gems built for the platforms in its
+    /// `PLATFORMS` section, so the mirror holds what `bundle install` needs
+    /// on each of them. Pure Ruby gems are always downloaded
+    #[clap(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["only_from_gemfile", "platforms"]
+    )]
+    pub only_from_gemfile_lock: Option<PathBuf>,
+
+    /// Only retry the namespaces that failed to sync in the last update
+    #[clap(long, conflicts_with_all = ["only_from_gemfile", "only_from_gemfile_lock"])]
+    pub resume_failed: bool,
+
+    /// Only mirror the gem names or full names listed at this URL, fetched
+    /// afresh at the start of each update
+    #[clap(long, value_name = "URL")]
+    pub only_from_url: Option<String>,
+
+    /// Only download gems built for these platforms, e.g. `x86_64-linux`,
+    /// which also covers `x86_64-linux-gnu` and `x86_64-linux-musl`. Pure
+    /// Ruby gems are always downloaded. Defaults to every platform.
+    #[clap(long, value_delimiter = ',', value_name = "PLATFORM")]
+    pub platforms: Vec<Platform>,
+
+    /// Only store versions published on or after this `YYYY-MM-DD` date
+    ///
+    /// The date is read from each gem's metadata, so versions outside the
+    /// window are downloaded once, then discarded instead of stored. Their
+    /// date is kept in the index, so later updates skip them outright.
+    #[clap(long, value_name = "DATE", value_parser = parse_date)]
+    pub published_after: Option<String>,
+
+    /// Only store versions published on or before this `YYYY-MM-DD` date,
+    /// with the same caveat as `--published-after`
+    #[clap(long, value_name = "DATE", value_parser = parse_date)]
+    pub published_before: Option<String>,
+
+    /// Only fetch namespaces that aren't in the index yet, leaving known
+    /// ones alone even if they changed upstream, for a quick additive sync
+    #[clap(long)]
+    pub only_new: bool,
+
+    /// Stop after syncing this many namespaces, taken in name order, e.g. to
+    /// build a small sample mirror
+    #[clap(long, value_name = "N")]
+    pub max_namespaces: Option<usize>,
+
+    /// Stop starting new work once the update has run this long, e.g. `45m`
+    /// or `1h30m`, saving progress and exiting with status 75
+    #[clap(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub timeout_total: Option<Duration>,
+
+    /// The number of gems to download in parallel
+    #[clap(long, default_value_t = 8)]
+    pub concurrency: usize,
+
+    /// Show how many gems of each index have been downloaded, and how many
+    /// bytes, as progress bars on stderr with one for each download worker.
+    /// When stderr isn't a terminal, a line is logged every 10 seconds
+    /// instead
+    #[clap(long)]
+    pub progress: bool,
+
+    /// The number of threads writing downloaded blobs to the store
+    #[clap(long, default_value_t = 4)]
+    pub store_threads: usize,
+
+    /// The number of indices to sync in parallel
+    #[clap(long, default_value_t = 1)]
+    pub parallel_indices: usize,
+
+    /// The number of `/info` files to fetch in parallel
+    #[clap(long, default_value_t = 16)]
+    pub info_concurrency: usize,
+
+    /// The maximum number of parallel downloads from any single host
+    #[clap(long)]
+    pub workers_per_host: Option<usize>,
+
+    /// Abort the update on the first error
+    #[clap(long, conflicts_with = "keep_going")]
+    pub fail_fast: bool,
+
+    /// Report all errors at the end of the update instead of aborting (default)
+    #[clap(long)]
+    pub keep_going: bool,
+
+    /// How many times to retry a request that failed transiently
+    #[clap(long, default_value_t = 3)]
+    pub max_retries: u32,
+
+    /// Stop sending requests to a host for a while after this many
+    /// consecutive failed attempts, so a dead source fails fast; 0 disables
+    #[clap(long, default_value_t = 10)]
+    pub circuit_breaker_threshold: u32,
+
+    /// How long to pause requests to a host that tripped its circuit
+    /// breaker before probing it with a single request
+    #[clap(long, value_parser = parse_duration, default_value = "60s")]
+    pub circuit_breaker_cooldown: Duration,
+
+    /// Log each retry and other debugging details
+    #[clap(long, short)]
+    pub verbose: bool,
+
+    /// Write a JSON summary of the run to this path
+    #[clap(long, value_name = "PATH")]
+    pub summary_json: Option<PathBuf>,
+
+    /// Append a JSON line to this file for every namespace the update
+    /// adds, changes or drops, listing the versions added, removed, yanked
+    /// and republished with a different checksum
+    #[clap(long, value_name = "PATH")]
+    pub merge_log: Option<PathBuf>,
+
+    /// Only fetch and diff what was appended to `/versions` since the last
+    /// update, instead of the whole file
+    #[clap(long)]
+    pub incremental_versions: bool,
+
+    /// Discover gems from `/names` instead of `/versions`
+    #[clap(long)]
+    pub use_names: bool,
+
+    /// Drop versions and gems that are no longer listed upstream
+    #[clap(long)]
+    pub reconcile: bool,
+
+    /// Remove namespaces that list no versions upstream, instead of keeping
+    /// them as empty placeholders
+    #[clap(long)]
+    pub drop_empty_namespaces: bool,
+
+    /// When reconciling, keep versions that disappeared upstream but mark
+    /// them as yanked, instead of removing them
+    #[clap(long, requires = "reconcile")]
+    pub mark_yanked: bool,
+
+    /// After a reconciling update without errors, delete unreferenced blobs
+    #[clap(long, requires = "reconcile")]
+    pub prune_after_update: bool,
+
+    /// When reconciling, also delete the blobs of versions that disappeared
+    /// upstream, unless a version still in any index shares them, so yanked
+    /// gems are gone from disk as well as from the index. Namespaces with
+    /// skipped `/info` lines or dropped as empty keep theirs
+    #[clap(long, requires = "reconcile", conflicts_with = "mark_yanked")]
+    pub exclude_yanked: bool,
+
+    /// Send StatsD metrics for the run to this UDP endpoint
+    #[clap(long, value_name = "HOST:PORT")]
+    pub statsd: Option<String>,
+
+    /// The prefix for StatsD metric names
+    #[clap(long, default_value = "gem_mirror")]
+    pub statsd_prefix: String,
+
+    /// Treat suspicious upstream data, such as a version listed twice in an
+    /// `/info` file, as an error instead of a warning, and abort the update
+    /// when any namespace fails to sync
+    #[clap(long)]
+    pub strict: bool,
+
+    /// Fail a namespace whose `/info` file has a malformed line instead of
+    /// skipping the line with a warning; implied by `--strict`
+    #[clap(long)]
+    pub strict_format: bool,
+
+    /// Skip versions whose `/info` checksum is weaker than SHA-256, such as a
+    /// SHA-1 or MD5 digest, with a warning for each, instead of mirroring
+    /// them. `/info` ETags are MD5 by design, but are only used to tell when
+    /// a namespace changed, never to check a download
+    #[clap(long)]
+    pub strict_integrity: bool,
+
+    /// Never negotiate HTTP/2, for servers that misbehave under it
+    #[clap(long)]
+    pub http1_only: bool,
+
+    /// Resolve each host only once per run
+    #[clap(long)]
+    pub dns_cache: bool,
+
+    /// Try a host's IPv4 addresses before its IPv6 ones, for networks where
+    /// IPv6 is broken and connections hang before falling back
+    #[clap(long, conflicts_with = "prefer_ipv6")]
+    pub prefer_ipv4: bool,
+
+    /// Try a host's IPv6 addresses before its IPv4 ones
+    #[clap(long)]
+    pub prefer_ipv6: bool,
+
+    /// Connect to IP instead of resolving HOST, like curl's `--resolve`
+    #[clap(long, value_name = "HOST:IP")]
+    pub resolve: Vec<ResolveOverride>,
+
+    /// Send a source's credentials along when it redirects to HOST, such as
+    /// the CDN a private source serves its `.gem` files from, over `https`
+    /// or the source's own scheme. Redirects to any other host, or from
+    /// `https` down to `http`, drop them
+    #[clap(long, value_name = "HOST")]
+    pub auth_redirect_host: Vec<String>,
+
+    /// Send the `:rubygems_api_key` from `~/.gem/credentials` to
+    /// `$RUBYGEMS_HOST`. It is a push key, so by default it isn't sent on
+    /// reads
+    #[clap(long)]
+    pub send_rubygems_api_key: bool,
+}
+
+impl UpdateOptions {
+    /// Whether a gem published on `date` falls within the
+    /// `--published-after`/`--published-before` window. Dates are ISO 8601,
+    /// so comparing them as strings orders them chronologically.
+    fn allows_date(&self, date: &str) -> bool {
+        self.published_after
+            .as_deref()
+            .is_none_or(|after| date >= after)
+            && self
+                .published_before
+                .as_deref()
+                .is_none_or(|before| date <= before)
+    }
+
+    /// Whether `--platforms` lets through gems built for `platform`
+    fn allows_platform(&self, platform: &str) -> bool {
+        let platform = Platform::parse(platform);
+        self.platforms.is_empty()
+            || platform.is_ruby()
+            || self
+                .platforms
+                .iter()
+                .any(|wanted| wanted.matches(&platform))
+    }
+
+    /// The gem names to seed a filtered mirror from, if any filter is set
+    fn seed_names(&self) -> miette::Result<Option<Vec<String>>> {
+        if let Some(path) = &self.only_from_gemfile_lock {
+            let mut names = crate::gemfile::parse_lockfile(&read_to_string(path)?)
+                .into_iter()
+                .map(|spec| spec.name)
+                .collect::<Vec<_>>();
+            names.sort();
+            names.dedup();
+            return Ok(Some(names));
+        }
+        let Some(path) = &self.only_from_gemfile else {
+            return Ok(None);
+        };
+        Ok(Some(crate::gemfile::parse_gemfile(&read_to_string(path)?)))
+    }
+
+    /// These options with `platforms` taken from the `PLATFORMS` section of
+    /// `--only-from-gemfile-lock`, read afresh for every update. A lockfile
+    /// without one, from before Bundler recorded platforms, allows them all.
+    fn with_lockfile_platforms(&self) -> miette::Result<Cow<'_, Self>> {
+        let Some(path) = &self.only_from_gemfile_lock else {
+            return Ok(Cow::Borrowed(self));
+        };
+        let platforms = crate::gemfile::parse_lockfile_platforms(&read_to_string(path)?)
+            .iter()
+            .map(|platform| Platform::parse(platform))
+            .collect();
+        Ok(Cow::Owned(Self {
+            platforms,
+            ..self.clone()
+        }))
+    }
+}
+
+fn read_to_string(path: &Path) -> miette::Result<String> {
+    fs::read_to_string(path).map_err(|e| miette!("Failed to read {}: {}", path.display(), e))
+}
+
+/// Parses a `YYYY-MM-DD` date for `--published-after`/`--published-before`
+fn parse_date(s: &str) -> Result<String, String> {
+    let parts = s.split('-').collect::<Vec<_>>();
+    let valid = matches!(parts.as_slice(), [year, month, day]
+        if year.len() == 4 && month.len() == 2 && day.len() == 2
+            && parts.iter().all(|part| part.chars().all(|c| c.is_ascii_digit()))
+            && (1..=12).contains(&month.parse::<u8>().unwrap_or(0))
+            && (1..=31).contains(&day.parse::<u8>().unwrap_or(0)));
+    if !valid {
+        return Err(format!("expected a YYYY-MM-DD date, got {:?}", s));
+    }
+    Ok(s.to_string())
+}
+
+/// The current time in seconds since the Unix epoch
+pub(crate) fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Parses a duration like `90s`, `15m`, `2h`, `1d` or `1h30m`; a bare number
+/// is in seconds
+pub(crate) fn parse_duration(s: &str) -> Result<Duration, String> {
+    let invalid = || format!("expected a duration like 30s, 15m or 1h30m, got {:?}", s);
+    if let Ok(secs) = s.parse::<u64>() {
+        return Ok(Duration::from_secs(secs));
+    }
+    let mut total = 0u64;
+    let mut rest = s;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .ok_or_else(invalid)?;
+        let value = rest[..digits].parse::<u64>().map_err(|_| invalid())?;
+        let unit = match rest.as_bytes()[digits] {
+            b's' => 1,
+            b'm' => 60,
+            b'h' => 60 * 60,
+            b'd' => 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        total += value * unit;
+        rest = &rest[digits + 1..];
+    }
+    if total == 0 && s.is_empty() {
+        return Err(invalid());
+    }
+    Ok(Duration::from_secs(total))
+}
+
+/// Whether the store can serve a gem pinned by a lockfile
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockStatus {
+    /// Stored, with its blob present
+    Present,
+    /// Listed in an index, but never downloaded
+    NotStored,
+    /// Marked as stored, but its blob is gone
+    BlobMissing,
+    /// Not listed in any index
+    NotInIndex,
+}
+
+/// Checks each of `full_names` against every index and the blob store
+pub fn check_lock<T: Store>(
+    store: &T,
+    full_names: &[String],
+) -> miette::Result<Vec<(String, LockStatus)>> {
+    let indices = store.list_indices()?;
+    Ok(full_names
+        .iter()
+        .map(|full_name| {
+            let gems = indices
+                .iter()
+                .flat_map(|index| index.gems.values())
+                .filter_map(|namespace| namespace.versions.get(full_name))
+                .collect::<Vec<_>>();
+            let status = if gems.is_empty() {
+                LockStatus::NotInIndex
+            } else if !gems.iter().any(|gem| gem.stored) {
+                LockStatus::NotStored
+            } else if gems
+                .iter()
+                .any(|gem| gem.stored && store.has_blob(&gem.package_integrity))
+            {
+                LockStatus::Present
+            } else {
+                LockStatus::BlobMissing
+            };
+            (full_name.clone(), status)
+        })
+        .collect())
+}
+
+/// Marks the matching versions of `name` as not stored, so the next update
+/// downloads and verifies them again. With `delete_blob`, their blobs are
+/// removed too, along with the `stored` flag of every other version sharing
+/// those blobs. Returns the number of versions invalidated.
+pub fn invalidate<T: Store>(
+    store: &mut T,
+    name: &str,
+    version: Option<&str>,
+    delete_blob: bool,
+) -> miette::Result<usize> {
+    let mut invalidated = 0;
+    store.with_indices(|store, indices| {
+        let mut deleted = HashSet::new();
+        for index in indices.iter_mut() {
+            let Some(namespace) = index.gems.get_mut(name) else {
+                continue;
+            };
+            for gem in namespace.versions.values_mut() {
+                if version.is_some_and(|v| v != gem.version) {
+                    continue;
+                }
+                println!("Invalidating {} in {}", gem.full_name, index.source);
+                gem.stored = false;
+                gem.set_metadata(None);
+                invalidated += 1;
+                if delete_blob {
+                    store.unlink_by_name(&gem.full_name)?;
+                    if deleted.insert(gem.package_integrity.to_string()) {
+                        store.remove_blob(&gem.package_integrity)?;
+                    }
+                }
+            }
+        }
+
+        for index in indices.iter_mut() {
+            for namespace in index.gems.values_mut() {
+                for gem in namespace.versions.values_mut() {
+                    if gem.stored && deleted.contains(&gem.package_integrity.to_string()) {
+                        println!("Invalidating {} in {}", gem.full_name, index.source);
+                        store.unlink_by_name(&gem.full_name)?;
+                        gem.stored = false;
+                        gem.set_metadata(None);
+                        invalidated += 1;
+                    }
+                }
+            }
+        }
+        Ok(())
+    })?;
+    Ok(invalidated)
+}
+
+/// Every blob `gem` keeps: its package, its `metadata.gz` and the packages
+/// it had before a republish
+fn gem_blobs(gem: &Gem) -> impl Iterator<Item = &Integrity> {
+    [Some(&gem.package_integrity), gem.metadata_gz_blob()]
+        .into_iter()
+        .flatten()
+        .chain(&gem.previous_integrities)
+}
+
+/// Removes the version `full_name` from every index and keeps updates from
+/// adding it back, for takedowns of gems that must no longer be served.
+///
+/// With `delete_blob`, its blobs are deleted too unless another version in
+/// any index still refers to them. Returns the number of indices it was
+/// removed from.
+pub fn purge<T: Store>(store: &mut T, full_name: &str, delete_blob: bool) -> miette::Result<usize> {
+    let mut purged = 0;
+    store.with_indices(|store, indices| {
+        let mut removed = Vec::new();
+        for index in indices.iter_mut() {
+            let gem = index
+                .gems
+                .values_mut()
+                .find_map(|namespace| namespace.versions.remove(full_name));
+            if !index.purged.iter().any(|name| name == full_name) {
+                index.purged.push(full_name.to_string());
+            }
+            if let Some(gem) = gem {
+                println!("Purged {} from {}", full_name, index.source);
+                purged += 1;
+                removed.push(gem);
+            }
+        }
+        if purged == 0 {
+            bail!("{} is not in any index", full_name);
+        }
+        store.unlink_by_name(full_name)?;
+        if !delete_blob {
+            return Ok(());
+        }
+
+        let referenced = indices
+            .iter()
+            .flat_map(|index| index.gems.values())
+            .flat_map(|namespace| namespace.versions.values())
+            .flat_map(gem_blobs)
+            .map(|integrity| integrity.to_string())
+            .collect::<HashSet<_>>();
+        let mut deleted = HashSet::new();
+        for gem in &removed {
+            for integrity in gem_blobs(gem) {
+                let key = integrity.to_string();
+                if referenced.contains(&key) || !deleted.insert(key) {
+                    continue;
+                }
+                if store.has_blob(integrity) {
+                    println!("Deleted blob {}", integrity);
+                    store.remove_blob(integrity)?;
+                }
+            }
+        }
+        Ok(())
+    })?;
+    Ok(purged)
+}
+
+/// Two indices that appear to serve the same content
+#[derive(Debug, Serialize)]
+pub struct DuplicateSources {
+    /// The earlier index, which a merge keeps
+    pub kept: String,
+    pub duplicate: String,
+    /// Namespaces whose `/info` checksums are identical in both
+    pub shared: usize,
+    /// Namespaces in the larger of the two
+    pub total: usize,
+}
+
+/// Parses a `dedupe-sources --threshold`, a fraction from 0 to 1
+pub(crate) fn parse_threshold(s: &str) -> Result<f64, String> {
+    s.parse::<f64>()
+        .ok()
+        .filter(|threshold| (0.0..=1.0).contains(threshold))
+        .ok_or_else(|| format!("expected a fraction from 0 to 1, got {:?}", s))
+}
+
+/// Finds pairs of indices where at least `threshold` of the namespaces of
+/// the larger one have identical `/info` checksums in the other, which
+/// means they mirror the same upstream. With `merge`, each duplicate is
+/// folded into the earliest index it duplicates and removed, preferring
+/// stored versions and keeping the earlier index's configuration.
+pub fn dedupe_sources<T: Store>(
+    store: &mut T,
+    threshold: f64,
+    merge: bool,
+) -> miette::Result<Vec<DuplicateSources>> {
+    let mut duplicates = Vec::new();
+    store.with_indices(|_, indices| {
+        let mut duplicate_of = HashMap::<usize, usize>::new();
+        for (i, a) in indices.iter().enumerate() {
+            for (j, b) in indices.iter().enumerate().skip(i + 1) {
+                if duplicate_of.contains_key(&i) || duplicate_of.contains_key(&j) {
+                    continue;
+                }
+                let total = a.gems.len().max(b.gems.len());
+                if total == 0 {
+                    continue;
+                }
+                let shared = a
+                    .gems
+                    .iter()
+                    .filter(|(name, namespace)| {
+                        b.gems
+                            .get(*name)
+                            .is_some_and(|other| other.info_checksum == namespace.info_checksum)
+                    })
+                    .count();
+                if (shared as f64) < threshold * total as f64 {
+                    continue;
+                }
+                duplicate_of.insert(j, i);
+                duplicates.push(DuplicateSources {
+                    kept: a.source.clone(),
+                    duplicate: b.source.clone(),
+                    shared,
+                    total,
+                });
+            }
+        }
+        if !merge {
+            return Ok(());
+        }
+
+        // Remove from the back so earlier positions stay valid
+        let mut merges = duplicate_of.into_iter().collect::<Vec<_>>();
+        merges.sort_by_key(|&(duplicate, _)| std::cmp::Reverse(duplicate));
+        for (duplicate, kept) in merges {
+            let duplicate = indices.remove(duplicate);
+            let kept = &mut indices[kept];
+            for (name, mut namespace) in duplicate.gems {
+                if let Some(existing) = kept.gems.get(&name) {
+                    namespace.merge(existing);
+                    namespace.info_checksum = existing.info_checksum.clone();
+                    // `merge` takes versions it sees as listed upstream again,
+                    // but the duplicate says nothing the kept index doesn't
+                    for (key, version) in &existing.versions {
+                        if let Some(merged) = namespace.versions.get_mut(key) {
+                            merged.yanked = version.yanked;
+                        }
+                    }
+                }
+                kept.gems.insert(name, namespace);
+            }
+            for full_name in duplicate.purged {
+                if !kept.purged.contains(&full_name) {
+                    kept.purged.push(full_name);
+                }
+            }
+            println!("Merged {} into {}", duplicate.source, kept.source);
+        }
+        Ok(())
+    })?;
+    Ok(duplicates)
+}
+
+/// Points the index for `from` at `to`, e.g. after a mirror moves hosts,
+/// keeping its gems, credentials and filters. Blobs are addressed by hash, so
+/// stored gems stay stored. The `/versions` cursor is dropped, since it
+/// describes the old host's copy of the file.
+pub fn rewrite_source<T: Store>(store: &mut T, from: &str, to: &str) -> miette::Result<()> {
+    store.with_indices(|_, indices| {
+        if indices.iter().any(|index| index.source == to) {
+            bail!("An index for {} already exists", to);
+        }
+        let Some(index) = indices.iter_mut().find(|index| index.source == from) else {
+            bail!("No index for {}", from);
+        };
+        index.source = to.to_string();
+        index.versions_cursor = None;
+        Ok(())
+    })
+}
+
+/// The outcome of `verify`
+#[derive(Debug, Default, Serialize)]
+pub struct VerifyReport {
+    /// The number of distinct blobs checked
+    pub checked: usize,
+    /// Full names of stored gems whose blobs are missing
+    pub missing: Vec<String>,
+    /// Full names of stored gems whose blobs don't match their integrity
+    pub corrupt: Vec<String>,
+    /// The number of gems marked as not stored so the next update refetches them
+    pub repaired: usize,
+    /// With a sample, the number of distinct blobs it was drawn from
+    pub sampled_from: Option<usize>,
+    /// The number of blobs only checked for presence, because a deep verify
+    /// rehashed them within `--skip-recent`
+    pub skipped_recent: usize,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.corrupt.is_empty()
+    }
+
+    /// How far a sample can be trusted. With no bad blobs among n sampled,
+    /// the rule of three puts the bad fraction of the whole store below
+    /// 3/n with 95% confidence.
+    pub fn confidence(&self) -> Option<String> {
+        let population = self.sampled_from?;
+        if self.checked == 0 {
+            return Some(format!("Sampled none of {} blobs", population));
+        }
+        if self.checked >= population {
+            return Some(format!("Sampled all {} blobs", population));
+        }
+        let bad = self.missing.len() + self.corrupt.len();
+        Some(if bad == 0 && self.checked <= 3 {
+            format!(
+                "Sampled {} of {} blobs; too few to say much about the rest",
+                self.checked, population
+            )
+        } else if bad == 0 {
+            format!(
+                "Sampled {} of {} blobs; with 95% confidence fewer than {:.2}% of blobs are bad",
+                self.checked,
+                population,
+                300.0 / self.checked as f64
+            )
+        } else {
+            format!(
+                "Sampled {} of {} blobs and found {} bad; a full verify is recommended",
+                self.checked, population, bad
+            )
+        })
+    }
+}
+
+/// A random subset of blobs for `verify` to check instead of all of them
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    pub size: usize,
+    /// Seeds the choice of blobs, so a sample can be repeated
+    pub seed: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BlobStatus {
+    Present,
+    Missing,
+    Corrupt,
+}
+
+/// Checks that every stored gem's blobs are present in the store.
+///
+/// This only consults the store's index of what it holds unless `deep` is
+/// set, in which case every blob is read back and rehashed. With `repair`,
+/// gems with missing or corrupt blobs are marked as not stored. With a
+/// `sample`, only that many randomly chosen blobs are read back and
+/// rehashed, and gems with none of their blobs in the sample are skipped.
+///
+/// Every blob a deep check finds intact is recorded as verified. Blobs never
+/// change once stored, so with `skip_recent`, those verified within that long
+/// are only checked for presence, and the rest are rehashed.
+pub fn verify<T: Store>(
+    store: &mut T,
+    deep: bool,
+    repair: bool,
+    sample: Option<Sample>,
+    skip_recent: Option<Duration>,
+) -> miette::Result<VerifyReport> {
+    let mut report = VerifyReport::default();
+    let deep = deep || sample.is_some() || skip_recent.is_some();
+    let mut verified = store.verified_blobs()?;
+    let now = unix_now();
+    let recent_after = skip_recent.map(|window| now.saturating_sub(window.as_secs()));
+    let mut referenced = HashSet::new();
+    store.with_indices(|store, indices| {
+        let sampled = sample.map(|sample| {
+            let mut blobs = indices
+                .iter()
+                .flat_map(|index| index.gems.values())
+                .flat_map(|namespace| namespace.versions.values())
+                .filter(|gem| gem.stored)
+                .flat_map(|gem| [Some(&gem.package_integrity), gem.metadata_gz_blob()])
+                .flatten()
+                .map(|integrity| integrity.to_string())
+                .collect::<Vec<_>>();
+            // Sort first so that the same seed picks the same blobs
+            blobs.sort();
+            blobs.dedup();
+            report.sampled_from = Some(blobs.len());
+            let mut rng = match sample.seed {
+                Some(seed) => fastrand::Rng::with_seed(seed),
+                None => fastrand::Rng::new(),
+            };
+            rng.shuffle(&mut blobs);
+            blobs.truncate(sample.size);
+            blobs.into_iter().collect::<HashSet<_>>()
+        });
+        let mut results = HashMap::<String, BlobStatus>::new();
+        for index in indices.iter_mut() {
+            for namespace in index.gems.values_mut() {
+                for gem in namespace.versions.values_mut().filter(|gem| gem.stored) {
+                    referenced.extend(
+                        [Some(&gem.package_integrity), gem.metadata_gz_blob()]
+                            .into_iter()
+                            .flatten()
+                            .map(|integrity| integrity.to_string()),
+                    );
+                    let mut status = BlobStatus::Present;
+                    for integrity in [Some(&gem.package_integrity), gem.metadata_gz_blob()]
+                        .into_iter()
+                        .flatten()
+                        .filter(|integrity| {
+                            sampled
+                                .as_ref()
+                                .is_none_or(|sampled| sampled.contains(&integrity.to_string()))
+                        })
+                    {
+                        let key = integrity.to_string();
+                        let result = *results.entry(key.clone()).or_insert_with(|| {
+                            report.checked += 1;
+                            let status = if !store.has_blob(integrity) {
+                                BlobStatus::Missing
+                            } else if !deep {
+                                return BlobStatus::Present;
+                            } else if let Some(after) = recent_after
+                                && verified.get(&key).is_some_and(|at| *at >= after)
+                            {
+                                report.skipped_recent += 1;
+                                return BlobStatus::Present;
+                            } else if store.check_blob(integrity).is_err() {
+                                BlobStatus::Corrupt
+                            } else {
+                                BlobStatus::Present
+                            };
+                            if status == BlobStatus::Present {
+                                verified.insert(key, now);
+                            } else {
+                                verified.remove(&key);
+                            }
+                            status
+                        });
+                        if status == BlobStatus::Present {
+                            status = result;
+                        }
+                    }
+                    // Inline metadata is in the index rather than a blob, so
+                    // only a full deep check looks at it
+                    if deep
+                        && sampled.is_none()
+                        && status == BlobStatus::Present
+                        && gem.has_inline_metadata()
+                        && gem.metadata_gz(&*store).is_err()
+                    {
+                        status = BlobStatus::Corrupt;
+                    }
+
+                    match status {
+                        BlobStatus::Present => continue,
+                        BlobStatus::Missing => report.missing.push(gem.full_name.clone()),
+                        BlobStatus::Corrupt => report.corrupt.push(gem.full_name.clone()),
+                    }
+                    if repair {
+                        if status == BlobStatus::Corrupt {
+                            store.remove_blob(&gem.package_integrity)?;
+                        }
+                        store.unlink_by_name(&gem.full_name)?;
+                        gem.stored = false;
+                        gem.set_metadata(None);
+                        report.repaired += 1;
+                    }
+                }
+            }
+        }
+        Ok(())
+    })?;
+    if deep {
+        // Forget blobs that are gone or no longer stored for any gem
+        verified.retain(|key, _| referenced.contains(key));
+        store.set_verified_blobs(&verified)?;
+    }
+    Ok(report)
+}
+
+/// The blobs of every version in `indices`, by source, namespace and full
+/// name, so `--exclude-yanked` can tell which versions an update removed
+fn listed_blobs(indices: &[Index]) -> HashMap<(String, String, String), Vec<Integrity>> {
+    let mut listed = HashMap::new();
+    for index in indices {
+        for (name, namespace) in &index.gems {
+            for gem in namespace.versions.values() {
+                let blobs = gem_blobs(gem).cloned().collect();
+                listed.insert(
+                    (index.source.clone(), name.clone(), gem.full_name.clone()),
+                    blobs,
+                );
+            }
+        }
+    }
+    listed
+}
+
+/// The blobs of the versions in `listed_before` that are no longer in their
+/// index, with the source and full name of the version each belonged to,
+/// unless a version still in any index refers to them. Versions of a
+/// namespace `--drop-empty-namespaces` removed are left alone: dropping a
+/// namespace isn't upstream removing its versions.
+fn unlisted_blobs(
+    indices: &[Index],
+    listed_before: HashMap<(String, String, String), Vec<Integrity>>,
+) -> Vec<(String, String, Integrity)> {
+    let listed = listed_blobs(indices);
+    let referenced = listed
+        .values()
+        .flatten()
+        .map(|integrity| content_address(integrity).to_string())
+        .collect::<HashSet<_>>();
+    let dropped = indices
+        .iter()
+        .flat_map(|index| {
+            index
+                .dropped_namespaces
+                .iter()
+                .map(|name| (index.source.as_str(), name.as_str()))
+        })
+        .collect::<HashSet<_>>();
+    let mut removed = listed_before
+        .into_iter()
+        .filter(|(version, _)| !listed.contains_key(version))
+        .filter(|((source, name, _), _)| !dropped.contains(&(source.as_str(), name.as_str())))
+        .collect::<Vec<_>>();
+    removed.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let mut seen = HashSet::new();
+    let mut unlisted = Vec::new();
+    for ((source, _, full_name), blobs) in removed {
+        for integrity in blobs {
+            let key = content_address(&integrity).to_string();
+            if referenced.contains(&key) || !seen.insert(key) {
+                continue;
+            }
+            unlisted.push((source.clone(), full_name.clone(), integrity));
+        }
+    }
+    unlisted
+}
+
+/// Deletes the blobs `unlisted_blobs` found, logging each. Only called once
+/// the indices that no longer refer to them are saved, so a failed save
+/// can't leave an index pointing at deleted blobs.
+fn delete_unlisted_blobs<T: Store>(
+    store: &T,
+    unlisted: Vec<(String, String, Integrity)>,
+) -> miette::Result<()> {
+    for (source, full_name, integrity) in unlisted {
+        store.unlink_by_name(&full_name)?;
+        if !store.has_blob(&integrity) {
+            continue;
+        }
+        store.remove_blob(&integrity)?;
+        println!(
+            "Deleted blob {} of {}, which {} no longer lists",
+            integrity, full_name, source
+        );
+    }
+    Ok(())
+}
+
+pub fn update_store<T: Store + Sync>(store: &mut T, options: &UpdateOptions) -> miette::Result<()> {
+    if options.refresh_metadata {
+        return refresh_metadata(store, options);
+    }
+    let options = &*options.with_lockfile_platforms()?;
+    let fetcher = Fetcher::new(options)?.with_store_id(store.store_id()?);
+    let seed = options.seed_names()?;
+    let allowlist = match &options.only_from_url {
+        Some(url) => Some(fetch_allowlist(&fetcher, url)?),
+        None => None,
+    };
+    let failures = Mutex::new(Failures::new(options.fail_fast));
+    let unlisted = store.with_indices(|store, indices| {
+        let store = &*store;
+        let listed_before = options.exclude_yanked.then(|| listed_blobs(indices));
+        let remaining = Mutex::new(indices.iter_mut());
+        std::thread::scope(|scope| -> miette::Result<()> {
+            let workers = (0..options.parallel_indices.max(1))
+                .map(|_| {
+                    scope.spawn(|| {
+                        loop {
+                            if fetcher.out_of_time() {
+                                return Ok(());
+                            }
+                            let Some(index) = remaining.lock().unwrap().next() else {
+                                return Ok(());
+                            };
+                            let mut index_failures = Failures::new(options.fail_fast);
+                            let result = update_index(
+                                &fetcher,
+                                store,
+                                index,
+                                seed.as_deref(),
+                                allowlist.as_deref(),
+                                &mut index_failures,
+                                options,
+                            );
+                            println!(
+                                "Finished {} with {} errors",
+                                index.source,
+                                index_failures.errors.len() + usize::from(result.is_err())
+                            );
+                            failures
+                                .lock()
+                                .unwrap()
+                                .errors
+                                .extend(index_failures.errors);
+                            result?;
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            workers
+                .into_iter()
+                .try_for_each(|worker| worker.join().expect("index worker panicked"))
+        })?;
+        Ok(listed_before
+            .map(|listed_before| unlisted_blobs(indices, listed_before))
+            .unwrap_or_default())
+    })?;
+    delete_unlisted_blobs(&*store, unlisted)?;
+    let failures = failures.into_inner().unwrap();
+
+    let summary = UpdateSummary {
+        errors: failures.errors.len(),
+        retries: fetcher.retry_summary(),
+        timed_out: fetcher.out_of_time(),
+        statuses: fetcher.status_counts(),
+        unreachable: fetcher.unreachable_hosts(),
+    };
+    summary.report();
+    fetcher.statsd().count("errors", summary.errors as u64);
+    fetcher
+        .statsd()
+        .count("retries", summary.retries.retries as u64);
+    if let Some(path) = &options.summary_json {
+        let json = serde_json::to_vec_pretty(&summary)
+            .map_err(|e| miette!("Failed to serialize summary: {}", e))?;
+        fs::write(path, json).map_err(|e| miette!("Failed to write {}: {}", path.display(), e))?;
+    }
+
+    let result = failures.finish();
+    if let Some(limit) = options.timeout_total
+        && summary.timed_out
+    {
+        return Err(TimedOut { limit }.into());
+    }
+    result
+}
+
+/// An update stopped by `--timeout-total`. Everything synced up to that point
+/// was saved, and the next update picks up where it left off.
+#[derive(Debug)]
+pub struct TimedOut {
+    pub limit: Duration,
+}
+
+impl TimedOut {
+    /// `EX_TEMPFAIL`, so schedulers can tell a partial run from a failed one
+    pub const EXIT_CODE: i32 = 75;
+}
+
+impl std::fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Timed out after {:?}, partial progress saved",
+            self.limit
+        )
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+impl miette::Diagnostic for TimedOut {}
+
+/// Syncs one index: fetches `/versions`, the `/info` of every namespace that
+/// changed (and is allowed), and then the blobs of every unstored version.
+fn update_index<T: Store + Sync>(
+    fetcher: &Fetcher,
+    store: &T,
+    index: &mut Index,
+    seed: Option<&[String]>,
+    allowlist: Option<&[String]>,
+    failures: &mut Failures,
+    options: &UpdateOptions,
+) -> miette::Result<()> {
+    println!("Index source: {}", index.source);
+    let scoped = store.for_index(index);
+    let store = scoped.as_ref().unwrap_or(store);
+    fetcher.authenticate(&index.source, index.config.auth.as_ref());
+    let versions_url = if options.use_names {
+        format!("{}/names", index.source)
+    } else {
+        format!("{}/versions", index.source)
+    };
+    // Names are seeded from outside `/versions` and `/names` has no
+    // checksums, so both need the whole file.
+    // Only some namespaces are looked at when seeded, limited to an
+    // `--only-from-url` allowlist, resuming failures, capped or only fetching
+    // new ones
+    let partial = seed.is_some()
+        || allowlist.is_some()
+        || options.resume_failed
+        || options.max_namespaces.is_some()
+        || options.only_new;
+    let incremental = options.incremental_versions && !partial && !options.use_names;
+    let cursor = index.versions_cursor.as_ref().filter(|_| incremental);
+    let (listing, new_cursor) = match fetch_versions(fetcher, &versions_url, cursor) {
+        Ok(fetched) => fetched,
+        Err(e) => return failures.record(e),
+    };
+    let (text, complete) = match &listing {
+        VersionsListing::Full(text) => (text.as_str(), true),
+        VersionsListing::Appended(text) => {
+            println!("Fetched {} new bytes of {}", text.len(), versions_url);
+            (text.as_str(), false)
+        }
+        VersionsListing::Unchanged => {
+            println!("No changes to {}", versions_url);
+            ("", false)
+        }
+    };
+    let errors_before = failures.errors.len();
+    let versions = if options.use_names {
+        parse_names(text)
+    } else if complete {
+        parse_versions(text)
+    } else {
+        Ok(parse_versions_lines(text))
+    };
+    let versions = match versions {
+        Ok(versions) => versions,
+        Err(e) => {
+            return failures.record(e.wrap_err(format!("Failed to parse {}", versions_url)));
+        }
+    };
+    let allowed = allowlist.as_ref().map(|entries| {
+        Allowlist::resolve(entries, |name| {
+            versions.contains_key(name) || index.gems.contains_key(name)
+        })
+    });
+    let mut pending: Vec<String> = match &seed {
+        _ if options.resume_failed => index.failed_namespaces.clone(),
+        Some(names) => names.to_vec(),
+        None => versions.keys().map(|name| name.to_string()).collect(),
+    };
+    if options.max_namespaces.is_some() {
+        pending.sort();
+    }
+    let mut seen = HashSet::new();
+    let mut known = HashSet::new();
+    let mut synced = 0;
+    let mut failed = Vec::new();
+    // Fetch `/info` a wave at a time: each wave's dependencies are
+    // only known once it has been merged into the index.
+    while !pending.is_empty() && !fetcher.out_of_time() {
+        let mut wave = Vec::new();
+        for name in pending.drain(..) {
+            if options.max_namespaces.is_some_and(|max| synced >= max) {
+                break;
+            }
+            if options.only_new && index.gems.contains_key(&name) {
+                known.insert(name);
+                continue;
+            }
+            if !seen.insert(name.clone())
+                || !index.allows(&name)
+                || allowed.as_ref().is_some_and(|a| !a.allows_name(&name))
+            {
+                continue;
+            }
+            match versions.get(name.as_str()) {
+                Some(info_checksum) => {
+                    wave.push((name, *info_checksum));
+                    synced += 1;
+                }
+                None => eprintln!("{} not found in {}", name, versions_url),
+            }
+        }
+
+        let mut fetched = run_parallel(
+            wave,
+            options.info_concurrency,
+            options.fail_fast,
+            || fetcher.out_of_time(),
+            |(name, info_checksum)| {
+                let mut warnings = Vec::new();
+                let namespace =
+                    fetch_namespace(fetcher, index, name, *info_checksum, options, &mut warnings)?;
+                Ok((namespace, warnings))
+            },
+        );
+        // Log and merge in name order, not the order fetches finished in,
+        // so the output of a run is readable and comparable between runs
+        fetched.sort_by(|((a, _), _), ((b, _), _)| a.cmp(b));
+        for ((name, _), result) in fetched {
+            let result = result.map(|(namespace, warnings)| {
+                for warning in warnings {
+                    eprintln!("{}", warning);
+                }
+                namespace
+            });
+            match result {
+                Ok(Some(namespace)) => apply_namespace(fetcher, index, namespace, options)?,
+                Ok(None) => {}
+                Err(e) if options.strict => return Err(e),
+                Err(e) => {
+                    failures.record(e)?;
+                    failed.push(name.clone());
+                }
+            }
+
+            if seed.is_some()
+                && let Some(namespace) = index.gems.get(&name)
+            {
+                for version in namespace.versions.values() {
+                    for dependency in &version.dependencies {
+                        if !seen.contains(&dependency.name) {
+                            pending.push(dependency.name.clone());
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    if options.only_new {
+        println!("Skipped {} namespaces already in the index", known.len());
+    }
+
+    // Remember which namespaces failed for `--resume-failed`, keeping
+    // earlier failures that this run didn't get to.
+    index
+        .failed_namespaces
+        .retain(|name| !seen.contains(name) && !failed.contains(name));
+    index.failed_namespaces.extend(failed);
+    index.failed_namespaces.sort();
+
+    // An update that ran out of time skipped some namespaces, so treat it
+    // like a filtered one from here on.
+    let partial = partial || fetcher.out_of_time();
+
+    // A filtered update only looks at some namespaces, so it can't
+    // tell which of the others have been removed upstream.
+    if options.reconcile && !partial && complete {
+        let merge_log = fetcher.merge_log();
+        let mut records = Vec::new();
+        index.gems.retain(|name, namespace| {
+            let listed = versions.contains_key(name.as_str());
+            if listed {
+                return true;
+            }
+            let before = merge_log.is_enabled().then(|| namespace.clone());
+            let after = if options.mark_yanked {
+                namespace.mark_yanked();
+                Some(&*namespace)
+            } else {
+                println!("Removed namespace {}", name);
+                None
+            };
+            if let Some(before) = before {
+                records.push(MergeRecord::new(
+                    &index.source,
+                    name,
+                    Some(&before),
+                    &HashMap::new(),
+                    after,
+                ));
+            }
+            after.is_some()
+        });
+        for record in records {
+            merge_log.record(&record)?;
+        }
+    }
+
+    // A namespace that failed to sync won't be in the next diff, so
+    // only move the cursor past it once everything succeeded, and past
+    // the namespaces a partial update skipped never.
+    if failures.errors.len() == errors_before && !partial {
+        index.versions_cursor = Some(new_cursor);
+    }
+
+    // A full run that got through `/versions` has confirmed every
+    // namespace it didn't fail on, even those it had no reason to refetch.
+    // With the whole listing in hand, one missing from it wasn't confirmed.
+    if !partial {
+        let now = unix_now();
+        let confirmed = index
+            .gems
+            .keys()
+            .filter(|name| index.allows(name) && !index.failed_namespaces.contains(name))
+            .filter(|name| !complete || versions.contains_key(name.as_str()))
+            .cloned()
+            .collect::<Vec<_>>();
+        for name in confirmed {
+            if let Some(namespace) = index.gems.get_mut(&name) {
+                namespace.last_updated = Some(now);
+            }
+        }
+    }
+
+    let mut jobs = Vec::new();
+    for (name, gem) in index.gems.iter() {
+        if (partial && !seen.contains(name)) || !index.allows(name) {
+            continue;
+        }
+        for version in gem.versions.values() {
+            if version.yanked {
+                continue;
+            }
+            if allowed.as_ref().is_some_and(|a| !a.allows_version(version)) {
+                continue;
+            }
+            if !options.allows_platform(&version.platform) {
+                continue;
+            }
+            if version.stored {
+                println!("Already stored {}", version.full_name);
+                if options.also_link_by_name
+                    && let Err(e) = store.link_by_name(version, false)
+                {
+                    failures.record(e)?;
+                }
+                continue;
+            }
+            jobs.push(version.clone());
+        }
+    }
+
+    let results = store_gems(fetcher, store, &index.source, jobs, options);
+    mark_stored(index, results, failures)
+}
+
+/// Mirrors exactly the gems whose full names (`name-version[-platform]`) are
+/// listed one per line in `file`, from every index that has them.
+///
+/// Blank lines and `#` comments are ignored. Full names that no index knows
+/// about are reported as errors. Namespaces are always merged, never
+/// reconciled or dropped, since only a few versions are of interest.
+pub fn fetch_list<T: Store + Sync>(
+    mut store: T,
+    file: &Path,
+    options: &UpdateOptions,
+) -> miette::Result<()> {
+    let contents = fs::read_to_string(file)
+        .map_err(|e| miette!("Failed to read {}: {}", file.display(), e))?;
+    let wanted = contents
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .collect::<HashSet<_>>();
+
+    let fetcher = Fetcher::new(options)?;
+    let mut failures = Failures::new(options.fail_fast);
+    let mut found = HashSet::new();
+    let merge_only = UpdateOptions {
+        reconcile: false,
+        drop_empty_namespaces: false,
+        ..options.clone()
+    };
+    store.with_indices(|store, indices| {
+        for index in indices {
+            println!("Index source: {}", index.source);
+            fetcher.authenticate(&index.source, index.config.auth.as_ref());
+            let versions_url = format!("{}/versions", index.source);
+            let text = match fetch_compact_index(&fetcher, &versions_url) {
+                Ok(text) => text,
+                Err(e) => {
+                    failures.record(e)?;
+                    continue;
+                }
+            };
+            let versions = match parse_versions(&text) {
+                Ok(versions) => versions,
+                Err(e) => {
+                    failures.record(e.wrap_err(format!("Failed to parse {}", versions_url)))?;
+                    continue;
+                }
+            };
+
+            let mut by_namespace = HashMap::<&str, Vec<&str>>::new();
+            for full_name in &wanted {
+                if let Some(name) = namespace_of(full_name, |name| versions.contains_key(name)) {
+                    by_namespace.entry(name).or_default().push(full_name);
+                }
+            }
+
+            let mut jobs = Vec::new();
+            for (name, full_names) in by_namespace {
+                if let Err(e) = sync_namespace(&fetcher, index, name, versions[name], &merge_only) {
+                    failures.record(e)?;
+                    continue;
+                }
+                let Some(namespace) = index.gems.get(name) else {
+                    continue;
+                };
+                for full_name in full_names {
+                    let Some(version) = namespace.versions.get(full_name) else {
+                        continue;
+                    };
+                    found.insert(full_name);
+                    if version.stored {
+                        println!("Already stored {}", version.full_name);
+                    } else {
+                        jobs.push(version.clone());
+                    }
+                }
+            }
+
+            let results = store_gems(&fetcher, &*store, &index.source, jobs, options);
+            mark_stored(index, results, &mut failures)?;
+        }
+
+        Ok(())
+    })?;
+
+    let mut missing = wanted.difference(&found).collect::<Vec<_>>();
+    missing.sort();
+    for full_name in missing {
+        failures.record(miette!("{} was not found in any index", full_name))?;
+    }
+
+    failures.finish()
+}
+
+/// Ensures the blob with `integrity` is stored, downloading it from the
+/// first index (or only `hint_source`) with a version whose package matches.
+/// Returns the full name of that version.
+///
+/// The indices must already list the version, so run `update` first.
+pub fn fetch_by_integrity<T: Store + Sync>(
+    store: &mut T,
+    integrity: &Integrity,
+    hint_source: Option<&str>,
+    options: &UpdateOptions,
+) -> miette::Result<String> {
+    let fetcher = Fetcher::new(options)?;
+    let mut failures = Failures::new(true);
+    let mut found = None;
+    store.with_indices(|store, indices| {
+        for index in indices
+            .iter_mut()
+            .filter(|index| hint_source.is_none_or(|source| index.source == source))
+        {
+            let Some(gem) = index
+                .gems
+                .values()
+                .flat_map(|namespace| namespace.versions.values())
+                .find(|gem| gem.package_integrity.matches(integrity).is_some())
+                .cloned()
+            else {
+                continue;
+            };
+            fetcher.authenticate(&index.source, index.config.auth.as_ref());
+            let results = store_gems(&fetcher, &*store, &index.source, vec![gem.clone()], options);
+            mark_stored(index, results, &mut failures)?;
+            found = Some(gem.full_name);
+            break;
+        }
+        Ok(())
+    })?;
+    found.ok_or_else(|| match hint_source {
+        Some(source) => miette!("No gem in {} has integrity {}", source, integrity),
+        None => miette!("No gem in any index has integrity {}", integrity),
+    })
+}
+
+/// Parses a package checksum as written in a lockfile's `CHECKSUMS` section
+/// (`sha256=<hex>`), as bare SHA-256 hex, or as an SRI string
+pub fn parse_checksum(checksum: &str) -> miette::Result<Integrity> {
+    if let Some((algorithm, hex)) = checksum.split_once('=')
+        && let Ok(algorithm) = algorithm.parse::<Algorithm>()
+    {
+        return Integrity::from_hex(hex, algorithm)
+            .map_err(|e| miette!("Invalid checksum {}: {}", checksum, e));
+    }
+    if checksum.len() == 64 && checksum.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Integrity::from_hex(checksum, Algorithm::Sha256)
+            .map_err(|e| miette!("Invalid checksum {}: {}", checksum, e));
+    }
+    checksum
+        .parse()
+        .map_err(|e| miette!("Invalid checksum {}: {}", checksum, e))
+}
+
+/// Finds the gem name a full name belongs to, by trying each `-` that could
+/// separate the name from the version against the names in `versions`.
+/// The longest match wins, so `foo-bar-1.0` is `foo-bar`, not `foo`.
+fn namespace_of(full_name: &str, is_name: impl Fn(&str) -> bool) -> Option<&str> {
+    full_name
+        .match_indices('-')
+        .rev()
+        .map(|(idx, _)| &full_name[..idx])
+        .find(|name| is_name(name))
+}
+
+/// Which gems `--only-from-url` allows, for one index: each allowed name maps
+/// to either every version (`None`) or only the listed full names.
+struct Allowlist(HashMap<String, Option<HashSet<String>>>);
+
+impl Allowlist {
+    /// Sorts the entries of the list into names and full names, using the
+    /// names the index knows about
+    fn resolve(entries: &[String], is_name: impl Fn(&str) -> bool) -> Self {
+        let mut allowed = HashMap::<String, Option<HashSet<String>>>::new();
+        for entry in entries {
+            if is_name(entry) {
+                allowed.insert(entry.clone(), None);
+            } else if let Some(name) = namespace_of(entry, &is_name)
+                // Otherwise the whole gem is already allowed
+                && let Some(full_names) = allowed
+                    .entry(name.to_string())
+                    .or_insert_with(|| Some(HashSet::new()))
+            {
+                full_names.insert(entry.clone());
+            }
+        }
+        Self(allowed)
+    }
+
+    fn allows_name(&self, name: &str) -> bool {
+        self.0.contains_key(name)
+    }
+
+    fn allows_version(&self, gem: &Gem) -> bool {
+        match self.0.get(&gem.name) {
+            Some(Some(full_names)) => full_names.contains(&gem.full_name),
+            Some(None) => true,
+            None => false,
+        }
+    }
+}
+
+/// What became of a gem handed to `store_gems`
+enum Stored {
+    /// Its blob is in the store, with its extracted metadata
+    Written(Option<Metadata>),
+    /// It was published outside `--published-after`/`--published-before`
+    OutOfWindow,
+}
+
+/// Marks every successfully downloaded gem as stored in `index`
+fn mark_stored(
+    index: &mut Index,
+    results: Vec<(Gem, miette::Result<Stored>)>,
+    failures: &mut Failures,
+) -> miette::Result<()> {
+    for (job, result) in results {
+        let stored = match result {
+            Ok(stored) => stored,
+            Err(e) => {
+                failures.record(e)?;
+                continue;
+            }
+        };
+        let version = index
+            .gems
+            .get_mut(&job.name)
+            .and_then(|namespace| namespace.versions.get_mut(&job.full_name))
+            .expect("downloaded gem is in the index");
+        if job.published.is_some() {
+            version.published = job.published;
+        }
+        if let Stored::Written(metadata) = stored {
+            version.set_metadata(metadata);
+            version.stored = true;
+        }
+    }
+    Ok(())
+}
+
+/// What happened during an update run
+#[derive(Debug, Default, Serialize)]
+pub struct UpdateSummary {
+    pub errors: usize,
+    pub retries: RetrySummary,
+    /// Whether `--timeout-total` stopped the run before it finished
+    pub timed_out: bool,
+    /// How many responses had each HTTP status code, counting every retry,
+    /// with connection failures as `timeout` or `error`
+    pub statuses: BTreeMap<String, usize>,
+    /// Hosts that were still failing at the end of the run, with how many
+    /// requests to each were skipped
+    pub unreachable: Vec<(String, usize)>,
+}
+
+impl UpdateSummary {
+    fn report(&self) {
+        if self.retries.requests_retried > 0 {
+            println!(
+                "Retried {} requests ({} retries), {} still failed",
+                self.retries.requests_retried, self.retries.retries, self.retries.requests_failed
+            );
+        }
+        for (host, skipped) in &self.unreachable {
+            println!(
+                "{} was unreachable; skipped {} requests to it",
+                host, skipped
+            );
+        }
+        if !self.statuses.is_empty() {
+            let statuses = self
+                .statuses
+                .iter()
+                .map(|(status, count)| format!("{}: {}", status, count))
+                .collect::<Vec<_>>()
+                .join(", ");
+            println!("HTTP responses: {}", statuses);
+        }
+    }
+}
+
+/// Collects the failures of an update so a single bad gem doesn't abort the
+/// whole run, unless `--fail-fast` was given.
+struct Failures {
+    fail_fast: bool,
+    errors: Vec<miette::Report>,
+}
+
+impl Failures {
+    fn new(fail_fast: bool) -> Self {
+        Self {
+            fail_fast,
+            errors: Vec::new(),
+        }
+    }
+
+    /// Returns the error in fail-fast mode, otherwise reports and keeps it
+    fn record(&mut self, error: miette::Report) -> miette::Result<()> {
+        if self.fail_fast {
+            return Err(error);
+        }
+        eprintln!("{:?}", error);
+        self.errors.push(error);
+        Ok(())
+    }
+
+    fn finish(self) -> miette::Result<()> {
+        if self.errors.is_empty() {
+            return Ok(());
+        }
+        bail!("{} errors occurred during update", self.errors.len())
+    }
+}
+
+fn fetch_text(fetcher: &Fetcher, url: &str) -> miette::Result<String> {
+    fetcher.get(url, |resp| {
+        if resp.status() != reqwest::StatusCode::OK {
+            return Err(ReadError::Fail(miette!(
+                "Failed to fetch {}: {}",
+                url,
+                resp.status()
+            )));
+        }
+        read_text(url, resp)
+    })
+}
+
+/// What `/versions`, `/names` and `/info` are requested as. Some servers only
+/// speak the compact index when asked for it, and otherwise serve an HTML
+/// index for people.
+const COMPACT_INDEX_ACCEPT: &str = "application/x-compact-index, text/plain;q=0.9";
+
+fn compact_index_headers() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        ACCEPT,
+        COMPACT_INDEX_ACCEPT
+            .parse()
+            .expect("accept is a valid header"),
+    );
+    headers
+}
+
+/// Reads a compact index file, failing clearly if the server answered with
+/// an HTML page instead
+fn read_compact_index(url: &str, resp: reqwest::blocking::Response) -> Result<String, ReadError> {
+    let is_html = resp
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("text/html"));
+    let text = read_text(url, resp)?;
+    if is_html || text.trim_start().starts_with('<') {
+        return Err(ReadError::Fail(miette!(
+            "{} returned HTML rather than a compact index; the source may not support the compact index protocol",
+            url
+        )));
+    }
+    Ok(text)
+}
+
+fn fetch_compact_index(fetcher: &Fetcher, url: &str) -> miette::Result<String> {
+    fetcher.get_with_headers(url, compact_index_headers(), |resp| {
+        if resp.status() != reqwest::StatusCode::OK {
+            return Err(ReadError::Fail(miette!(
+                "Failed to fetch {}: {}",
+                url,
+                resp.status()
+            )));
+        }
+        read_compact_index(url, resp)
+    })
+}
+
+/// Fetches a list of gem names or full names, one per line, ignoring blank
+/// lines and `#` comments
+fn fetch_allowlist(fetcher: &Fetcher, url: &str) -> miette::Result<Vec<String>> {
+    let text = fetch_text(fetcher, url)?;
+    Ok(text
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+/// Maps each gem name in a `/versions` file to its latest info checksum
+fn parse_versions(text: &str) -> miette::Result<HashMap<&str, Option<&str>>> {
+    let (_, versions) = text
+        .split_once("---\n")
+        .ok_or_else(|| miette!("Failed to find separator in versions"))?;
+    Ok(parse_versions_lines(versions))
+}
+
+/// Parses the gem lines of a `/versions` file, after the separator. A gem
+/// listed more than once takes its last checksum.
+fn parse_versions_lines(text: &str) -> HashMap<&str, Option<&str>> {
+    let mut h = HashMap::<&str, Option<&str>>::new();
+    for line in text.lines().filter(|line| !line.is_empty()) {
+        let parts = line.split(" ").collect::<Vec<_>>();
+        let name = parts[0];
+        let info_checksum = parts[parts.len() - 1];
+        h.insert(name, Some(info_checksum));
+    }
+    h
+}
+
+/// Fetches `url`, or with a cursor, only what was appended to it since.
+///
+/// The range starts one byte early: if that byte is no longer the newline
+/// that ended the synced part, the file was rewritten and is fetched whole.
+fn fetch_versions(
+    fetcher: &Fetcher,
+    url: &str,
+    cursor: Option<&VersionsCursor>,
+) -> miette::Result<(VersionsListing, VersionsCursor)> {
+    let mut headers = compact_index_headers();
+    if let Some(cursor) = cursor.filter(|cursor| cursor.len > 0) {
+        let range = format!("bytes={}-", cursor.len - 1);
+        headers.insert(RANGE, range.parse().expect("range is a valid header"));
+        if let Some(etag) = &cursor.etag
+            && let Ok(etag) = etag.parse()
+        {
+            headers.insert(IF_NONE_MATCH, etag);
+        }
+    }
+
+    let fetched = fetcher.get_with_headers(url, headers, |resp| {
+        check_not_self(fetcher, url, &resp)?;
+        let etag = resp
+            .headers()
+            .get(ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .map(str::to_string);
+        match (resp.status(), cursor) {
+            (reqwest::StatusCode::NOT_MODIFIED, Some(cursor)) => {
+                Ok(Some((VersionsListing::Unchanged, cursor.clone())))
+            }
+            (reqwest::StatusCode::PARTIAL_CONTENT, Some(cursor)) => {
+                let text = read_compact_index(url, resp)?;
+                let Some(appended) = text.strip_prefix('\n') else {
+                    return Ok(None);
+                };
+                let len = cursor.len + appended.len() as u64;
+                Ok(Some((
+                    VersionsListing::Appended(appended.to_string()),
+                    VersionsCursor { etag, len },
+                )))
+            }
+            // The file shrank, so it must have been rewritten
+            (reqwest::StatusCode::RANGE_NOT_SATISFIABLE, Some(_)) => Ok(None),
+            (reqwest::StatusCode::OK, _) => {
+                let text = read_compact_index(url, resp)?;
+                let len = text.len() as u64;
+                Ok(Some((
+                    VersionsListing::Full(text),
+                    VersionsCursor { etag, len },
+                )))
+            }
+            (status, _) => Err(ReadError::Fail(miette!(
+                "Failed to fetch {}: {}",
+                url,
+                status
+            ))),
+        }
+    })?;
+    match fetched {
+        Some(fetched) => Ok(fetched),
+        None => fetch_versions(fetcher, url, None),
+    }
+}
+
+/// Refuses a source that is a `serve` of the store being updated, which
+/// would only feed the store back into itself, and warns about one that is
+/// some other gem-mirror
+fn check_not_self(
+    fetcher: &Fetcher,
+    url: &str,
+    resp: &reqwest::blocking::Response,
+) -> Result<(), ReadError> {
+    let header = |name: &str| {
+        resp.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+    let Some(server) = header(SERVER.as_str()).filter(|server| server.starts_with("gem-mirror/"))
+    else {
+        return Ok(());
+    };
+    if let Some(store_id) = header(STORE_ID_HEADER)
+        && fetcher.store_id() == Some(store_id)
+    {
+        return Err(ReadError::Fail(miette!(
+            "{} is served from this store, so mirroring it would loop; remove the index or point it at the upstream",
+            url
+        )));
+    }
+    eprintln!(
+        "{} is served by {}, so this index mirrors another mirror",
+        url, server
+    );
+    Ok(())
+}
+
+/// Lists the gem names in a `/names` file, which carries no checksums, so
+/// every namespace has to be refetched
+fn parse_names(text: &str) -> miette::Result<HashMap<&str, Option<&str>>> {
+    let (_, names) = text
+        .split_once("---\n")
+        .ok_or_else(|| miette!("Failed to find separator in names"))?;
+    Ok(names
+        .lines()
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| (name, None))
+        .collect())
+}
+
+/// Downloads each gem using up to `options.concurrency` workers and hands
+/// the blobs to `options.store_threads` writers, so slow synchronous store
+/// writes don't hold up downloads.
+fn store_gems<T: Store + Sync>(
+    fetcher: &Fetcher,
+    store: &T,
+    source: &str,
+    jobs: Vec<Gem>,
+    options: &UpdateOptions,
+) -> Vec<(Gem, miette::Result<Stored>)> {
+    let store_threads = options.store_threads.max(1);
+    // Bounded, so downloads can't run far ahead of writes and pile up temp
+    // files.
+    let (downloaded, to_write) =
+        mpsc::sync_channel::<(Gem, Option<DownloadedBlob>)>(store_threads * 2);
+    let to_write = Mutex::new(to_write);
+    let results = Mutex::new(Vec::new());
+    let skipped_by_date = AtomicUsize::new(0);
+    let write_failed = AtomicBool::new(false);
+    let date_filtered = options.published_after.is_some() || options.published_before.is_some();
+    let progress_total = jobs.len();
+    let progress = Progress::new(source, progress_total, options.concurrency);
+    std::thread::scope(|scope| {
+        let reporter =
+            (options.progress && progress_total > 0).then(|| scope.spawn(|| progress.report()));
+        for _ in 0..store_threads {
+            scope.spawn(|| {
+                loop {
+                    let Ok((gem, blob)) = to_write.lock().unwrap().recv() else {
+                        break;
+                    };
+                    let result = write_gem(fetcher, store, &gem, blob.as_ref(), options)
+                        .map(Stored::Written);
+                    if result.is_err() && options.fail_fast {
+                        write_failed.store(true, Ordering::Relaxed);
+                    }
+                    results.lock().unwrap().push((gem, result));
+                }
+            });
+        }
+
+        // A failed write stops downloads under `--fail-fast` as a failed
+        // download does
+        let stop = || fetcher.out_of_time() || write_failed.load(Ordering::Relaxed);
+        let failed_downloads =
+            run_parallel(jobs, options.concurrency, options.fail_fast, stop, |gem| {
+                let skip = |gem: Gem, date: &str| {
+                    if options.verbose {
+                        println!("Skipping {} published on {}", gem.full_name, date);
+                    }
+                    skipped_by_date.fetch_add(1, Ordering::Relaxed);
+                    results.lock().unwrap().push((gem, Ok(Stored::OutOfWindow)));
+                };
+                if date_filtered
+                    && let Some(date) = &gem.published
+                    && !options.allows_date(date)
+                {
+                    progress.finish(None, true);
+                    skip(gem.clone(), date);
+                    return Ok(());
+                }
+                let active = progress.start();
+                let blob = download_gem(fetcher, store, source, gem, options);
+                active.finish(
+                    blob.as_ref()
+                        .ok()
+                        .and_then(|blob| blob.as_ref().map(|blob| blob.len as usize)),
+                    blob.is_ok(),
+                );
+                let blob = blob?;
+                let mut gem = gem.clone();
+                if date_filtered && gem.published.is_none() {
+                    // A blob already present, e.g. from another index, was
+                    // not downloaded, so read its date from the store
+                    let date = match &blob {
+                        Some(blob) => blob.reader().and_then(published_date),
+                        None => store
+                            .get_blob_reader(&gem.package_integrity)
+                            .and_then(published_date),
+                    }
+                    .map_err(|e| miette!("Failed to read the date of {}: {}", gem.full_name, e))?;
+                    gem.published = date;
+                }
+                if date_filtered
+                    && let Some(date) = gem.published.clone()
+                    && !options.allows_date(&date)
+                {
+                    skip(gem, &date);
+                    return Ok(());
+                }
+                downloaded
+                    .send((gem, blob))
+                    .map_err(|_| miette!("Store writers exited early"))
+            });
+        progress.stop();
+        if let Some(reporter) = &reporter {
+            reporter.thread().unpark();
+        }
+        // Let the writers finish once everything downloaded has been written
+        drop(downloaded);
+        let mut results = results.lock().unwrap();
+        for (gem, result) in failed_downloads {
+            if let Err(e) = result {
+                results.push((gem, Err(e)));
+            }
+        }
+    });
+    let skipped_by_date = skipped_by_date.into_inner();
+    if skipped_by_date > 0 {
+        println!(
+            "Skipped {} versions of {} published outside the date window",
+            skipped_by_date, source
+        );
+        fetcher
+            .statsd()
+            .count("blobs.skipped_by_date", skipped_by_date as u64);
+    }
+    results.into_inner().unwrap()
+}
+
+/// Runs `f` over `jobs` on up to `workers` threads, stopping early once any
+/// of them fails under `--fail-fast` or `stop` returns true, e.g. once out of
+/// time. Jobs that were never started are dropped. Results are in completion
+/// order.
+fn run_parallel<J: Send, R: Send>(
+    jobs: Vec<J>,
+    workers: usize,
+    fail_fast: bool,
+    stop: impl Fn() -> bool + Sync,
+    f: impl Fn(&J) -> miette::Result<R> + Sync,
+) -> Vec<(J, miette::Result<R>)> {
+    let jobs = Mutex::new(jobs.into_iter());
+    let results = Mutex::new(Vec::new());
+    let failed = AtomicBool::new(false);
+    std::thread::scope(|scope| {
+        for _ in 0..workers.max(1) {
+            scope.spawn(|| {
+                while !failed.load(Ordering::Relaxed) && !stop() {
+                    let Some(job) = jobs.lock().unwrap().next() else {
+                        break;
+                    };
+                    let result = f(&job);
+                    if result.is_err() && fail_fast {
+                        failed.store(true, Ordering::Relaxed);
+                    }
+                    results.lock().unwrap().push((job, result));
+                }
+            });
+        }
+    });
+    results.into_inner().unwrap()
+}
+
+/// Counts downloads, to name each one's temp file uniquely
+static DOWNLOADS: AtomicU64 = AtomicU64::new(0);
+
+/// A downloaded `.gem` in a temp file under `Store::download_dir`, removed
+/// when dropped
+struct DownloadedBlob {
+    path: PathBuf,
+    file: fs::File,
+    len: u64,
+}
+
+impl DownloadedBlob {
+    fn create(dir: &Path) -> miette::Result<Self> {
+        let path = dir.join(format!(
+            "download-{}-{}",
+            std::process::id(),
+            DOWNLOADS.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = fs::create_dir_all(dir)
+            .and_then(|()| {
+                fs::OpenOptions::new()
+                    .read(true)
+                    .append(true)
+                    .create_new(true)
+                    .open(&path)
+            })
+            .map_err(|e| miette!("Failed to create {}: {}", path.display(), e))?;
+        Ok(Self { path, file, len: 0 })
+    }
+
+    /// Appends `from` to the file until it ends or fails. What was written
+    /// is counted either way, for a later attempt to carry on from.
+    fn append(&mut self, from: &mut impl Read) -> std::io::Result<()> {
+        let result = std::io::copy(from, &mut self.file);
+        self.len = self.file.metadata()?.len();
+        result.map(|_| ())
+    }
+
+    fn truncate(&mut self) -> std::io::Result<()> {
+        self.file.set_len(0)?;
+        self.len = 0;
+        Ok(())
+    }
+
+    /// The file, from its first byte
+    fn reader(&self) -> miette::Result<fs::File> {
+        fs::File::open(&self.path)
+            .map_err(|e| miette!("Failed to open {}: {}", self.path.display(), e))
+    }
+
+    fn read(&self) -> miette::Result<Vec<u8>> {
+        fs::read(&self.path).map_err(|e| miette!("Failed to read {}: {}", self.path.display(), e))
+    }
+}
+
+impl Drop for DownloadedBlob {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// What a blob download got before its connection dropped, so the next
+/// attempt can ask for only the rest with a `Range` request
+#[derive(Default)]
+struct PartialDownload {
+    blob: Option<DownloadedBlob>,
+    /// The `ETag` or `Last-Modified` of the response `blob` came from, sent
+    /// as `If-Range` so a gem that changed in the meantime is sent whole
+    validator: Option<HeaderValue>,
+}
+
+impl PartialDownload {
+    /// The bytes downloaded so far
+    fn len(&self) -> u64 {
+        self.blob.as_ref().map_or(0, |blob| blob.len)
+    }
+}
+
+/// The first byte of a `206` response, from its `Content-Range`
+fn content_range_start(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(CONTENT_RANGE)?
+        .to_str()
+        .ok()?
+        .strip_prefix("bytes ")?
+        .split_once('-')?
+        .0
+        .parse()
+        .ok()
+}
+
+/// What must be percent-encoded in a URL path segment: the WHATWG path
+/// percent-encode set, plus `/` and `%` themselves
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}')
+    .add(b'/')
+    .add(b'%');
+
+/// The URL of the `.gem` for `full_name` on `source`. With `validate`, names
+/// that can't be a single path segment are refused rather than requested,
+/// and other unsafe characters are percent-encoded.
+fn gem_url(source: &str, full_name: &str, validate: bool) -> miette::Result<String> {
+    if !validate {
+        return Ok(format!("{}/gems/{}.gem", source, full_name));
+    }
+    if full_name.is_empty()
+        || full_name.starts_with('.')
+        || full_name
+            .chars()
+            .any(|c| c == '/' || c == '\\' || c.is_whitespace() || c.is_control())
+    {
+        println!(
+            "Refusing to fetch {:?}: it can't be part of a URL",
+            full_name
+        );
+        bail!("{:?} is not a valid full name for a /gems URL", full_name);
+    }
+    let encoded = utf8_percent_encode(full_name, PATH_SEGMENT).to_string();
+    if encoded != full_name {
+        println!("Percent-encoding {:?} as {} in its URL", full_name, encoded);
+    }
+    let url = format!("{}/gems/{}.gem", source, encoded);
+    url::Url::parse(&url).map_err(|e| miette!("Invalid URL {} for {}: {}", url, full_name, e))?;
+    Ok(url)
+}
+
+/// Downloads the `.gem` blob for `version`, unless it is already stored.
+/// With `options.head_check`, its length is first looked up with a `HEAD`
+/// request. A download that fails partway is resumed from where it stopped,
+/// where the source supports `Range` requests, and restarted where it
+/// doesn't. The download is streamed to a temp file rather than held in
+/// memory.
+fn download_gem<T: Store>(
+    fetcher: &Fetcher,
+    store: &T,
+    source: &str,
+    version: &Gem,
+    options: &UpdateOptions,
+) -> miette::Result<Option<DownloadedBlob>> {
+    if store.has_blob(&version.package_integrity) {
+        return Ok(None);
+    }
+    let blob_url = gem_url(
+        source,
+        &version.full_name,
+        options.validate_full_name_against_url,
+    )?;
+    println!("Fetching blob for {}", version.full_name);
+    let expected_len = if options.head_check {
+        fetcher.head(&blob_url, |resp| {
+            if resp.status() != reqwest::StatusCode::OK {
+                return Err(ReadError::Fail(miette!(
+                    "Failed to fetch {}: {}",
+                    blob_url,
+                    resp.status()
+                )));
+            }
+            // A response to `HEAD` has no body, so take the header as is
+            // rather than `content_length`, which measures the body
+            Ok(resp
+                .headers()
+                .get(CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse::<u64>().ok()))
+        })?
+    } else {
+        None
+    };
+    let partial = RefCell::new(PartialDownload::default());
+    let resume_headers = || {
+        let partial = partial.borrow();
+        let mut headers = HeaderMap::new();
+        if partial.len() > 0 {
+            let range = format!("bytes={}-", partial.len());
+            headers.insert(RANGE, range.parse().expect("range is a valid header"));
+            if let Some(validator) = &partial.validator {
+                headers.insert(IF_RANGE, validator.clone());
+            }
+        }
+        headers
+    };
+    let blob = fetcher.get_with_headers_from(&blob_url, resume_headers, |mut resp| {
+        let mut partial = partial.borrow_mut();
+        let file_error = |e| ReadError::Fail(miette!("Failed to write {}: {}", blob_url, e));
+        let resumed_from = match resp.status() {
+            reqwest::StatusCode::PARTIAL_CONTENT
+                if content_range_start(resp.headers()) == Some(partial.len()) =>
+            {
+                partial.len()
+            }
+            // The server ignored the range, or the gem changed since the
+            // last attempt, so this is the whole gem again
+            reqwest::StatusCode::OK => {
+                if let Some(blob) = &mut partial.blob {
+                    blob.truncate().map_err(file_error)?;
+                }
+                partial.validator = resp
+                    .headers()
+                    .get(ETAG)
+                    .or_else(|| resp.headers().get(LAST_MODIFIED))
+                    .cloned();
+                0
+            }
+            status if partial.len() > 0 => {
+                *partial = PartialDownload::default();
+                return Err(ReadError::Retry(miette!(
+                    "Failed to resume {}: {}",
+                    blob_url,
+                    status
+                )));
+            }
+            status => {
+                return Err(ReadError::Fail(miette!(
+                    "Failed to fetch {}: {}",
+                    blob_url,
+                    status
+                )));
+            }
+        };
+        if resumed_from > 0 && fetcher.verbose() {
+            println!("Resuming {} from byte {}", blob_url, resumed_from);
+        }
+        // A captive portal or failing proxy can answer with an HTML page
+        // and a 200, which is worth retrying rather than storing.
+        let content_type = resp
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        if content_type.starts_with("text/") {
+            let error = miette!("Expected a gem from {}, got {}", blob_url, content_type);
+            *partial = PartialDownload::default();
+            return Err(ReadError::Retry(error));
+        }
+        let content_length = resp.content_length().map(|len| resumed_from + len);
+        let blob = match &mut partial.blob {
+            Some(blob) => blob,
+            None => partial
+                .blob
+                .insert(DownloadedBlob::create(&store.download_dir()).map_err(ReadError::Fail)?),
+        };
+        // Whatever arrives before the connection drops is kept for the next
+        // attempt to carry on from
+        blob.append(&mut resp)
+            .map_err(|e| ReadError::Retry(miette!("Failed to read {}: {}", blob_url, e)))?;
+        // Catch a dropped connection here rather than as a confusing
+        // integrity mismatch later on.
+        if let Some(expected) = content_length
+            && expected != blob.len
+        {
+            let error = miette!(
+                "Truncated download of {}: expected {} bytes, got {}",
+                blob_url,
+                expected,
+                blob.len
+            );
+            if expected < blob.len {
+                *partial = PartialDownload::default();
+            }
+            return Err(ReadError::Retry(error));
+        }
+        let blob = std::mem::take(&mut *partial)
+            .blob
+            .expect("a blob was just downloaded");
+        if let Some(expected) = expected_len
+            && expected != blob.len
+        {
+            return Err(ReadError::Retry(miette!(
+                "Download of {} is {} bytes, but HEAD said {}",
+                blob_url,
+                blob.len,
+                expected
+            )));
+        }
+        if !is_tar(blob.reader().map_err(ReadError::Fail)?) {
+            return Err(ReadError::Retry(miette!(
+                "Expected a gem from {}, got something that isn't a tar archive",
+                blob_url
+            )));
+        }
+        Ok(blob)
+    })?;
+    fetcher.statsd().count("blobs.downloaded", 1);
+    fetcher.statsd().count("blobs.bytes", blob.len);
+    Ok(Some(blob))
+}
+
+/// Whether `blob` starts with a POSIX tar header, as every `.gem` does
+fn is_tar(blob: impl Read) -> bool {
+    let mut header = Vec::new();
+    blob.take(262).read_to_end(&mut header).is_ok() && header.get(257..262) == Some(b"ustar")
+}
+
+/// Stores and verifies a freshly downloaded `blob` for `version`, returning
+/// its extracted `metadata.gz` unless metadata is disabled.
+fn write_gem<T: Store>(
+    fetcher: &Fetcher,
+    store: &T,
+    version: &Gem,
+    blob: Option<&DownloadedBlob>,
+    options: &UpdateOptions,
+) -> miette::Result<Option<Metadata>> {
+    if let Some(blob) = blob {
+        let started = Instant::now();
+        match store.store_blob_expecting(blob.read()?, &version.package_integrity) {
+            Ok(_) => {}
+            Err(e) if e.downcast_ref::<IntegrityMismatch>().is_some() => {
+                return Err(e.wrap_err(format!("Failed to store {}", version.full_name)));
+            }
+            // Another writer stored the same content between our
+            // `has_blob` check and this write, which is fine.
+            Err(_) if store.has_blob(&version.package_integrity) => {}
+            Err(e) => return Err(e),
+        }
+        fetcher
+            .statsd()
+            .timing("blobs.store_time", started.elapsed());
+    }
+    if options.also_link_by_name {
+        // A fresh download may be a republished version, so its link is
+        // replaced rather than trusted
+        store.link_by_name(version, blob.is_some())?;
+    }
+
+    if options.no_metadata {
+        return Ok(None);
+    }
+
+    extract_metadata(store, version, options).map(Some)
+}
+
+/// Extracts `metadata.gz` from the stored `.gem` blob of `version`. With
+/// `--validate-metadata`, it is first checked with `validate_metadata`. It
+/// is kept inline if smaller than `--store-blob-threshold`, and stored as a
+/// blob otherwise.
+fn extract_metadata<T: Store>(
+    store: &T,
+    version: &Gem,
+    options: &UpdateOptions,
+) -> miette::Result<Metadata> {
+    // `metadata.gz` is usually near the front, so stream the blob rather than
+    // reading the whole `.gem` into memory.
+    let dot_gem = store.get_blob_reader(&version.package_integrity)?;
+    let mut archive = tar::Archive::new(dot_gem);
+    let mut metadata = None;
+    let tar_error = |e| miette!("Failed to read {}.gem: {}", version.full_name, e);
+    for entry in archive.entries().map_err(tar_error)? {
+        let mut entry = entry.map_err(tar_error)?;
+        if entry.path().map_err(tar_error)?.as_ref() == OsStr::new("metadata.gz") {
+            let mut buf = Vec::new();
+            entry.read_to_end(&mut buf).map_err(tar_error)?;
+            metadata = Some(buf);
+            break;
+        }
+    }
+    let Some(metadata) = metadata else {
+        bail!(
+            "Failed to find metadata.gz in blob for {}",
+            version.full_name
+        );
+    };
+    if options.validate_metadata {
+        validate_metadata(version, &metadata)
+            .map_err(|e| e.wrap_err(format!("Invalid metadata.gz in {}", version.full_name)))?;
+    }
+    if options
+        .store_blob_threshold
+        .is_some_and(|threshold| metadata.len() < threshold)
+    {
+        return Ok(Metadata {
+            integrity: Integrity::from(&metadata),
+            inline: Some(BASE64_STANDARD.encode(&metadata)),
+        });
+    }
+    Ok(Metadata {
+        integrity: store.store_blob(metadata)?,
+        inline: None,
+    })
+}
+
+/// Checks that `metadata` gunzips to the YAML of a `Gem::Specification`
+/// named after `version`. This is a sanity check on the document's header
+/// and `name:`, not a full YAML parse.
+fn validate_metadata(version: &Gem, metadata: &[u8]) -> miette::Result<()> {
+    let mut spec = String::new();
+    flate2::read::GzDecoder::new(metadata)
+        .read_to_string(&mut spec)
+        .map_err(|e| miette!("Failed to decompress metadata.gz: {}", e))?;
+    let mut lines = spec.lines();
+    let header = lines.next().unwrap_or_default();
+    if !header.starts_with("--- !ruby/object:Gem::Specification") {
+        bail!("Expected a Gem::Specification, found {:?}", header);
+    }
+    let name = lines
+        .find_map(|line| line.strip_prefix("name: "))
+        .map(|name| name.trim().trim_matches(|c| c == '"' || c == '\''));
+    match name {
+        Some(name) if name == version.name => Ok(()),
+        Some(name) => bail!("Expected the gemspec of {}, found {}", version.name, name),
+        None => bail!("The gemspec has no name"),
+    }
+}
+
+/// Reads the `date:` of the gemspec in a `.gem` blob, as `YYYY-MM-DD`
+fn published_date(dot_gem: impl Read) -> miette::Result<Option<String>> {
+    let mut archive = tar::Archive::new(dot_gem);
+    let tar_error = |e| miette!("Failed to read .gem: {}", e);
+    for entry in archive.entries().map_err(tar_error)? {
+        let entry = entry.map_err(tar_error)?;
+        if entry.path().map_err(tar_error)?.as_ref() != OsStr::new("metadata.gz") {
+            continue;
+        }
+        let mut metadata = String::new();
+        flate2::read::GzDecoder::new(entry)
+            .read_to_string(&mut metadata)
+            .map_err(|e| miette!("Failed to decompress metadata.gz: {}", e))?;
+        // e.g. `date: 2023-05-01 00:00:00.000000000 Z`
+        return Ok(metadata.lines().find_map(|line| {
+            let date = line.strip_prefix("date: ")?.trim();
+            Some(date.get(..10).unwrap_or(date).to_string())
+        }));
+    }
+    bail!("Failed to find metadata.gz")
+}
+
+/// Re-extracts the metadata of every stored gem from its blob, without any
+/// network access, so improvements to extraction reach an existing mirror.
+fn refresh_metadata<T: Store>(store: &mut T, options: &UpdateOptions) -> miette::Result<()> {
+    let mut failures = Failures::new(options.fail_fast);
+    let mut refreshed = 0;
+    store.with_indices(|store, indices| {
+        for index in indices {
+            let scoped = store.for_index(index);
+            let store = scoped.as_ref().unwrap_or(&*store);
+            for namespace in index.gems.values_mut() {
+                for version in namespace.versions.values_mut() {
+                    if !version.stored {
+                        continue;
+                    }
+                    match extract_metadata(store, version, options) {
+                        Ok(metadata) => {
+                            version.set_metadata(Some(metadata));
+                            refreshed += 1;
+                        }
+                        Err(e) => failures.record(e)?,
+                    }
+                }
+            }
+        }
+        Ok(())
+    })?;
+    println!("Refreshed metadata for {} gems", refreshed);
+    failures.finish()
+}
+
+/// Fetches `/info` for `name` into a fresh namespace and merges it into
+/// `index`
+fn sync_namespace(
+    fetcher: &Fetcher,
+    index: &mut Index,
+    name: &str,
+    info_checksum: Option<&str>,
+    options: &UpdateOptions,
+) -> miette::Result<()> {
+    let mut warnings = Vec::new();
+    let namespace = fetch_namespace(fetcher, index, name, info_checksum, options, &mut warnings);
+    for warning in warnings {
+        eprintln!("{}", warning);
+    }
+    if let Some(namespace) = namespace? {
+        apply_namespace(fetcher, index, namespace, options)?;
+    }
+    Ok(())
+}
+
+/// Strips the weak `W/` prefix and surrounding quotes from an `/info` ETag,
+/// leaving the bare checksum `/versions` lists. Stores written by older
+/// versions may hold checksums that were saved quoted.
+fn normalize_checksum(raw: &str) -> String {
+    let raw = raw.trim();
+    let raw = raw.strip_prefix("W/").unwrap_or(raw);
+    raw.trim_matches('"').to_string()
+}
+
+/// Fetches and parses `/info` for `name`, or returns `None` if the index
+/// already has the version with `info_checksum`. A version listed twice is
+/// warned about, or is an error with `--strict`, and a line that doesn't
+/// parse is skipped with a warning, or is an error with `--strict-format`.
+/// With `--strict-integrity`, versions with a checksum weaker than SHA-256
+/// are skipped with a warning.
+///
+/// Warnings are collected into `warnings` rather than printed, so callers
+/// fetching many namespaces at once can log them in a stable order.
+fn fetch_namespace(
+    fetcher: &Fetcher,
+    index: &Index,
+    name: &str,
+    info_checksum: Option<&str>,
+    options: &UpdateOptions,
+    warnings: &mut Vec<String>,
+) -> miette::Result<Option<Namespace>> {
+    let strict_format = options.strict || options.strict_format;
+    if let Some(existing) = index.gems.get(name)
+        && let Some(info_checksum) = info_checksum
+        && normalize_checksum(&existing.info_checksum) == normalize_checksum(info_checksum)
+    {
+        return Ok(None);
+    }
+
+    let gem_url = format!("{}/info/{}", index.source, name);
+    let started = Instant::now();
+    let (info_checksum, text) =
+        fetcher.get_with_headers(&gem_url, compact_index_headers(), |resp| {
+            if resp.status() != reqwest::StatusCode::OK {
+                return Err(ReadError::Fail(miette!(
+                    "Failed to fetch {}: {}",
+                    gem_url,
+                    resp.status()
+                )));
+            }
+            let etag = resp
+                .headers()
+                .get(ETAG)
+                .and_then(|etag| etag.to_str().ok())
+                .map(normalize_checksum);
+
+            let text = read_compact_index(&gem_url, resp)?;
+            // Some proxies strip the ETag, but it is only the MD5 of the
+            // body, which is what `/versions` lists as the checksum too
+            let info_checksum = etag.unwrap_or_else(|| {
+                if fetcher.verbose() {
+                    println!("No ETag for {}, using the MD5 of its body", gem_url);
+                }
+                md5::hex_digest(text.as_bytes())
+            });
+            Ok((info_checksum, text))
+        })?;
+    fetcher
+        .statsd()
+        .timing("info.fetch_time", started.elapsed());
+    fetcher.statsd().count("info.bytes", text.len() as u64);
+    let mut versions = text.lines().collect::<Vec<_>>();
+    if let Some((idx, _)) = versions
+        .iter()
+        .enumerate()
+        .find(|(_, name)| **name == "---")
+    {
+        versions = versions[idx + 1..].to_vec();
+    } else {
+        bail!("Failed to find separator in info for {}", name);
+    }
+    let mut parsed = HashMap::new();
+    let mut skipped_lines = 0;
+    for line in versions {
+        let gem = match parse_info_line(name, line) {
+            Ok(gem) => gem,
+            Err(e) if strict_format => {
+                return Err(e.wrap_err(format!("Invalid line {:?} in info for {}", line, name)));
+            }
+            Err(e) => {
+                warnings.push(format!("Skipping {:?} in info for {}: {}", line, name, e));
+                skipped_lines += 1;
+                continue;
+            }
+        };
+        // A line we can't turn back into the `.gem` URL would only 404, so
+        // skip it rather than failing the whole namespace.
+        if let Err(e) = validate_full_name(&gem) {
+            warnings.push(format!("Skipping {:?} in info for {}: {}", line, name, e));
+            skipped_lines += 1;
+            continue;
+        }
+        if options.strict_integrity
+            && let Some(bits) = weak_checksum_bits(&gem)
+        {
+            warnings.push(format!(
+                "Skipping {}: its checksum has only {} bits, weaker than SHA-256",
+                gem.full_name, bits
+            ));
+            skipped_lines += 1;
+            continue;
+        }
+        let full_name = gem.full_name.clone();
+        let integrity = gem.package_integrity.clone();
+        if let Some(previous) = parsed.insert(full_name.clone(), gem) {
+            let checksums = if previous.package_integrity == integrity {
+                "with the same checksum".to_string()
+            } else {
+                format!(
+                    "with different checksums {} and {}",
+                    previous.package_integrity, integrity
+                )
+            };
+            if options.strict {
+                bail!(
+                    "{} is listed twice in info for {}, {}",
+                    full_name,
+                    name,
+                    checksums
+                );
+            }
+            warnings.push(format!(
+                "{} is listed twice in info for {}, {}; keeping the last",
+                full_name, name, checksums
+            ));
+        }
+    }
+    Ok(Some(Namespace {
+        name: name.to_string(),
+        info_checksum,
+        versions: parsed,
+        last_updated: Some(unix_now()),
+        skipped_lines,
+    }))
+}
+
+/// The size in bits of a checksum shorter than SHA-256's, such as a SHA-1 or
+/// MD5 digest listed by a poorly behaved source. `parse_info_line` takes
+/// every checksum for SHA-256, so no download would ever match one of these.
+fn weak_checksum_bits(gem: &Gem) -> Option<usize> {
+    let (_, hex) = gem.package_integrity.to_hex();
+    (hex.len() < 64).then_some(hex.len() * 4)
+}
+
+/// Merges a freshly fetched namespace into `index`. With
+/// `--drop-empty-namespaces`, a namespace with no versions upstream is
+/// removed outright instead of being kept as a placeholder. A namespace with
+/// skipped lines is merged even under `--reconcile`, and never dropped, since
+/// the versions it lacks may be the skipped ones. Any change is written to
+/// the `--merge-log`.
+fn apply_namespace(
+    fetcher: &Fetcher,
+    index: &mut Index,
+    mut namespace: Namespace,
+    options: &UpdateOptions,
+) -> miette::Result<()> {
+    let name = namespace.name.clone();
+    let merge_log = fetcher.merge_log();
+    let upstream = match merge_log.is_enabled() {
+        true => namespace
+            .versions
+            .iter()
+            .map(|(full_name, gem)| (full_name.clone(), gem.package_integrity.clone()))
+            .collect(),
+        false => HashMap::new(),
+    };
+    let policy = match options.refetch_changed_blobs {
+        true => ConflictPolicy::PreferRemote,
+        false => options.on_conflict,
+    };
+    let remote = match policy {
+        ConflictPolicy::KeepLocal => HashMap::new(),
+        _ => namespace.versions.clone(),
+    };
+    let record = |before: Option<&Namespace>, after: Option<&Namespace>| {
+        if !merge_log.is_enabled() {
+            return Ok(());
+        }
+        merge_log.record(&MergeRecord::new(
+            &index.source,
+            &name,
+            before,
+            &upstream,
+            after,
+        ))
+    };
+    if namespace.versions.is_empty() {
+        println!("No versions listed for {}, they may all be yanked", name);
+    }
+    namespace
+        .versions
+        .retain(|full_name, _| !index.purged.contains(full_name));
+    if options.drop_empty_namespaces
+        && namespace.versions.is_empty()
+        && namespace.skipped_lines == 0
+    {
+        if let Some(existing) = index.gems.get(&name) {
+            println!("Dropped empty namespace {}", name);
+            record(Some(existing), None)?;
+            index.gems.remove(&name);
+            index.dropped_namespaces.push(name);
+        }
+        return Ok(());
+    }
+    if let Some(existing) = index.gems.get(&name) {
+        if existing.info_checksum != namespace.info_checksum {
+            println!(
+                "Checksum mismatch for {}: {} vs {}",
+                name, existing.info_checksum, namespace.info_checksum
+            );
+            if options.reconcile && namespace.skipped_lines > 0 {
+                println!(
+                    "Not reconciling {}: {} lines of its info were skipped",
+                    name, namespace.skipped_lines
+                );
+                namespace.merge(existing);
+            } else if options.reconcile {
+                namespace.reconcile(existing, options.mark_yanked);
+            } else {
+                namespace.merge(existing);
+            }
+            namespace.resolve_conflicts(remote, policy)?;
+            record(Some(existing), Some(&namespace))?;
+            index.gems.insert(name.clone(), namespace);
+        } else {
+            println!("No changes for {}", name);
+            if let Some(existing) = index.gems.get_mut(&name) {
+                existing.last_updated = namespace.last_updated;
+            }
+        }
+    } else {
+        println!("New namespace: {}", name);
+        record(None, Some(&namespace))?;
+        index.gems.insert(name.clone(), namespace);
+    }
+    Ok(())
+}
+
+pub(crate) fn parse_info_line(name: &str, line: &str) -> miette::Result<Gem> {
+    let (version, rest) = line
+        .split_once(" ")
+        .ok_or_else(|| miette!("no space after the version"))?;
+
+    let (deps, metadata) = rest
+        .split_once("|")
+        .ok_or_else(|| miette!("no `|` before the metadata"))?;
+
+    let (version, platform) = version.split_once("-").unwrap_or((version, "ruby"));
+
+    let full_name = if platform == "ruby" {
+        format!("{}-{}", name, version)
+    } else {
+        format!("{}-{}-{}", name, version, platform)
+    };
+
+    let mut sha256 = String::new();
+    let mut required_ruby = None;
+    let mut required_rubygems = None;
+    metadata
+        .split(',')
+        .for_each(|item| match item.split_once(':') {
+            Some(("checksum", value)) => sha256 = value.to_string(),
+            Some(("ruby", value)) => required_ruby = Some(value.to_string()),
+            Some(("rubygems", value)) => required_rubygems = Some(value.to_string()),
+            _ => {}
+        });
+
+    let package_integrity = Integrity::from_hex(&sha256, ssri::Algorithm::Sha256)
+        .ok()
+        .filter(|_| !sha256.is_empty())
+        .ok_or_else(|| miette!("no valid checksum"))?;
+
+    Ok(Gem {
+        full_name: full_name.to_string(),
+        name: name.to_string(),
+        version: version.to_string(),
+        platform: platform.to_string(),
+        package_integrity,
+        metadata_gz_integrity: None,
+        metadata_gz_inline: None,
+        stored: false,
+        dependencies: parse_dependencies(deps),
+        yanked: false,
+        previous_integrities: Vec::new(),
+        published: None,
+        required_ruby,
+        required_rubygems,
+    })
+}
+
+/// Checks that `gem.full_name` splits back into the same version and platform
+/// it was built from, and that neither contains anything that would produce
+/// a malformed blob URL.
+fn validate_full_name(gem: &Gem) -> miette::Result<()> {
+    if gem.version.is_empty()
+        || !gem
+            .version
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.')
+    {
+        bail!("invalid version {:?}", gem.version);
+    }
+    if gem.platform.is_empty()
+        || !gem
+            .platform
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+    {
+        bail!("invalid platform {:?}", gem.platform);
+    }
+
+    let rest = gem
+        .full_name
+        .strip_prefix(&gem.name)
+        .and_then(|rest| rest.strip_prefix('-'))
+        .ok_or_else(|| miette!("{} does not start with {}", gem.full_name, gem.name))?;
+    let (version, platform) = rest.split_once('-').unwrap_or((rest, "ruby"));
+    if version != gem.version || platform != gem.platform {
+        bail!(
+            "{} splits into version {:?} and platform {:?}, not {:?} and {:?}",
+            gem.full_name,
+            version,
+            platform,
+            gem.version,
+            gem.platform
+        );
+    }
+    Ok(())
+}
+
+fn parse_dependencies(deps: &str) -> Vec<Dependency> {
+    deps.split(',')
+        .filter_map(|dep| dep.split_once(':'))
+        .map(|(name, requirements)| Dependency {
+            name: name.to_string(),
+            requirements: requirements.split('&').map(str::to_string).collect(),
+        })
+        .collect()
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    indices: Vec<Index>,
+
+    blobs: RwLock<HashMap<String, Vec<u8>>>,
+}
+
+impl Store for MemoryStore {
+    fn list_indices(&self) -> miette::Result<Cow<'_, [Index]>> {
+        Ok(Cow::Borrowed(&self.indices))
+    }
+
+    fn store_blob<B: AsRef<[u8]>>(&self, blob: B) -> miette::Result<Integrity> {
+        let integrity = Integrity::from(blob.as_ref());
+        let mut blobs = self.blobs.write().unwrap();
+        // Content is addressed by its hash, so a concurrent write of the same
+        // bytes is harmless: keep whichever copy landed first.
+        blobs
+            .entry(integrity.to_string())
+            .or_insert_with(|| blob.as_ref().to_vec());
+        Ok(integrity)
+    }
+
+    fn get_blob(&self, sha256: &Integrity) -> miette::Result<Vec<u8>> {
+        let blobs = self.blobs.read().unwrap();
+        if let Some(blob) = blobs.get(sha256.to_string().as_str()) {
+            sha256
+                .check(blob.as_slice())
+                .map_err(|e| miette!("Blob is corrupt: {}", e))?;
+            Ok(blob.clone())
+        } else {
+            bail!("Blob not found")
+        }
+    }
+
+    fn has_blob(&self, sha256: &Integrity) -> bool {
+        let blobs = self.blobs.read().unwrap();
+        blobs.contains_key(sha256.to_string().as_str())
+    }
+
+    fn remove_blob(&self, sha256: &Integrity) -> miette::Result<()> {
+        let mut blobs = self.blobs.write().unwrap();
+        blobs.remove(sha256.to_string().as_str());
+        Ok(())
+    }
+
+    fn with_indices<F, R>(&mut self, f: F) -> miette::Result<R>
+    where
+        F: FnOnce(&mut Self, &mut Vec<Index>) -> miette::Result<R>,
+    {
+        let mut indices = std::mem::take(&mut self.indices);
+        let result = f(self, &mut indices)?;
+        self.indices = indices;
+        Ok(result)
+    }
+}
+
+/// Written into the store and blob directories when they are created, and
+/// checked before anything deletes from them
+const STORE_MARKER: &str = ".gem-mirror-store";
+
+/// Directories this run found unmarked and didn't adopt. Whatever looks
+/// like a store in them later on may be this run's own writing, so they are
+/// never adopted afterwards.
+static UNADOPTED: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Creates a store or blob directory if it doesn't exist, marking it as one
+pub(crate) fn create_store_dir(dir: &Path) -> miette::Result<()> {
+    let fresh = fs::read_dir(dir).map_or(true, |mut entries| entries.next().is_none());
+    std::fs::create_dir_all(dir)
+        .map_err(|e| miette!("Failed to create directory {}: {}", dir.display(), e))?;
+    let marker = dir.join(STORE_MARKER);
+    if marker.exists() {
+        return Ok(());
+    }
+    let dir = fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf());
+    let mut unadopted = UNADOPTED.lock().unwrap();
+    if unadopted.contains(&dir) {
+        return Ok(());
+    }
+    // Stores created before the marker existed are recognizable by their
+    // contents, so adopt them rather than locking them out.
+    if fresh || looks_like_store(&dir) {
+        fs::write(&marker, new_store_id())
+            .map_err(|e| miette!("Failed to write {}: {}", marker.display(), e))?;
+    } else {
+        unadopted.push(dir);
+    }
+    Ok(())
+}
+
+/// Whether `dir` holds what a gem-mirror from before the marker left
+/// behind: an `indices.json` that parses, or else a cacache `content-v2`
+/// with nothing but hash algorithm directories in it
+fn looks_like_store(dir: &Path) -> bool {
+    let indices = dir.join("indices.json");
+    if indices.exists() {
+        return fs::File::open(&indices)
+            .is_ok_and(|file| read_blob_roots(std::io::BufReader::new(file)).is_ok());
+    }
+    let Ok(entries) = fs::read_dir(dir.join(BLOB_FORMAT)) else {
+        return false;
+    };
+    let entries: Vec<_> = entries.collect();
+    !entries.is_empty()
+        && entries.iter().all(|entry| {
+            entry.as_ref().is_ok_and(|entry| {
+                entry.file_type().is_ok_and(|kind| kind.is_dir())
+                    && entry
+                        .file_name()
+                        .to_str()
+                        .is_some_and(|name| name.parse::<Algorithm>().is_ok())
+            })
+        })
+}
+
+/// A random identifier for a store, kept in its marker file
+fn new_store_id() -> String {
+    format!("{:032x}", fastrand::u128(..))
+}
+
+/// The directory cacache keeps blobs in, named for its content format version
+pub const BLOB_FORMAT: &str = "content-v2";
+/// The directory namespace shards are kept in, one subdirectory per index
+const SHARDS_DIR: &str = "namespaces";
+/// Written into `SHARDS_DIR` once every shard is, so a half-written set is
+/// never read
+const SHARDS_STAMP: &str = "complete";
+/// The file in an index's shard directory naming its source
+const SHARD_SOURCE: &str = "source";
+/// The directory cacache keeps its key index in
+const BLOB_INDEX: &str = "index-v5";
+/// How old a file under a blob directory's `tmp` must be before `compact`
+/// takes it for abandoned rather than a write in progress
+const TMP_FILE_MIN_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// What `info` reports about a store, read without creating or changing it
+#[derive(Debug, Serialize)]
+pub struct StoreInfo {
+    pub store_path: PathBuf,
+    pub blob_cache_dir: PathBuf,
+    /// Whether the store directory exists at all
+    pub exists: bool,
+    pub store_id: Option<String>,
+    /// The format version `indices.json` was written in, if there is one
+    pub indices_format: Option<u32>,
+    pub indices: usize,
+    pub namespaces: usize,
+    pub versions: usize,
+}
+
+impl StoreInfo {
+    pub fn read(root: &Path, blob_root: &Path) -> miette::Result<Self> {
+        let store_id = fs::read_to_string(root.join(STORE_MARKER))
+            .ok()
+            .map(|id| id.trim().to_string())
+            .filter(|id| !id.is_empty());
+        let path = root.join("indices.json");
+        let (indices_format, indices) = if path.exists() {
+            let contents =
+                fs::read(&path).map_err(|e| miette!("Failed to read {}: {}", path.display(), e))?;
+            // Unversioned files predate format versioning, which started at 1
+            let format = serde_json::from_slice::<serde_json::Value>(&contents)
+                .ok()
+                .map(|file| file["version"].as_u64().map_or(0, |v| v as u32));
+            (format, read_indices(contents.as_slice())?)
+        } else {
+            (None, vec![])
+        };
+        Ok(Self {
+            store_path: root.to_path_buf(),
+            blob_cache_dir: blob_root.to_path_buf(),
+            exists: root.is_dir(),
+            store_id,
+            indices_format,
+            indices: indices.len(),
+            namespaces: indices.iter().map(|index| index.gems.len()).sum(),
+            versions: indices
+                .iter()
+                .flat_map(|index| index.gems.values())
+                .map(|namespace| namespace.versions.len())
+                .sum(),
+        })
+    }
+}
+
+/// Counts `healthcheck` probes, to name each one's probe file uniquely
+static HEALTHCHECK_PROBES: AtomicU64 = AtomicU64::new(0);
+
+/// When `verify` last rehashed each blob, next to `indices.json`
+const VERIFIED_FILE: &str = "verified.json";
+
+/// Where `--also-link-by-name` puts `<full_name>.gem` files, under the store
+/// root
+const NAMED_GEMS_DIR: &str = "gems";
+
+#[derive(Debug, Clone)]
+pub struct FsStore {
+    /// Where `indices.json` lives
+    root: PathBuf,
+    /// The cacache directory holding the content-addressed blobs
+    blob_root: PathBuf,
+    /// Where to write blobs instead of `blob_root`, for the index this store
+    /// was scoped to by `for_index`
+    write_root: Option<PathBuf>,
+    /// The `blob_root`s of indices that keep their blobs apart, as of the
+    /// last time `indices.json` was read, or `None` until it has been
+    index_blob_roots: Arc<RwLock<Option<Vec<PathBuf>>>>,
+    /// The hashes computed for each stored blob, in addition to SHA-256
+    algorithms: Vec<Algorithm>,
+    /// Whether to keep per-namespace shards next to `indices.json`
+    shard_namespaces: bool,
+    /// Whether `get_blob` rehashes content before returning it
+    verify_on_read: bool,
+}
+
+/// Blobs are always addressed by their SHA-256 hash on disk, so that the
+/// checksums from the compact index can locate them. Any other hashes in an
+/// `Integrity` are informational.
+pub(crate) fn content_address(sri: &Integrity) -> Integrity {
+    match sri
+        .hashes
+        .iter()
+        .find(|hash| hash.algorithm == Algorithm::Sha256)
+    {
+        Some(hash) => Integrity {
+            hashes: vec![hash.clone()],
+        },
+        None => sri.clone(),
+    }
+}
+
+impl FsStore {
+    pub fn new<P: AsRef<Path>, B: AsRef<Path>>(root: P, blob_root: B) -> miette::Result<Self> {
+        let root = PathBuf::from(root.as_ref());
+        let blob_root = PathBuf::from(blob_root.as_ref());
+        for dir in [&root, &blob_root] {
+            create_store_dir(dir)?;
+        }
+        Ok(Self {
+            root,
+            blob_root,
+            write_root: None,
+            index_blob_roots: Default::default(),
+            algorithms: vec![Algorithm::Sha256],
+            shard_namespaces: false,
+            verify_on_read: true,
+        })
+    }
+
+    /// Every blob directory, the one written to first, then the store's
+    /// own, then those of indices that keep their blobs apart
+    fn blob_roots(&self) -> Vec<PathBuf> {
+        let mut roots = Vec::new();
+        let all = self
+            .write_root
+            .iter()
+            .chain([&self.blob_root])
+            .cloned()
+            .chain(self.index_blob_roots());
+        for root in all {
+            if !roots.contains(&root) {
+                roots.push(root);
+            }
+        }
+        roots
+    }
+
+    /// The `blob_root`s of the indices, read from `indices.json` if nothing
+    /// has read it yet. A missing or unreadable file is reported by whatever
+    /// reads the indices themselves, and isn't remembered, so a later read
+    /// still finds them.
+    fn index_blob_roots(&self) -> Vec<PathBuf> {
+        if let Some(roots) = &*self.index_blob_roots.read().unwrap() {
+            return roots.clone();
+        }
+        let path = self.root.join("indices.json");
+        let Some(roots) = fs::File::open(path)
+            .ok()
+            .and_then(|file| read_blob_roots(std::io::BufReader::new(file)).ok())
+        else {
+            return Vec::new();
+        };
+        *self.index_blob_roots.write().unwrap() = Some(roots.clone());
+        roots
+    }
+
+    /// Remembers the `blob_root`s of `indices`, the indices as just read or
+    /// written, so blob lookups see any added since the store was opened
+    fn set_index_blob_roots(&self, indices: &[Index]) {
+        let roots = indices
+            .iter()
+            .filter_map(|index| index.config.blob_root.clone())
+            .collect();
+        *self.index_blob_roots.write().unwrap() = Some(roots);
+    }
+
+    /// The blob directory holding `sha256`, if any does
+    fn blob_root_with(&self, sha256: &Integrity) -> Option<PathBuf> {
+        let address = content_address(sha256);
+        self.blob_roots()
+            .into_iter()
+            .find(|root| cacache::exists_sync(root, &address))
+    }
+
+    /// Refuses to go on unless the store directories carry the marker file
+    /// written when they were created, so that a typo like `--store-path /`
+    /// can't point a destructive command at an unrelated directory
+    pub fn check_marker(&self, force: bool) -> miette::Result<()> {
+        if force {
+            return Ok(());
+        }
+        for dir in [self.root.clone()].into_iter().chain(self.blob_roots()) {
+            if !dir.join(STORE_MARKER).exists() {
+                bail!(
+                    "{} doesn't look like a gem-mirror store (it has no {} file); pass --force to use it anyway",
+                    dir.display(),
+                    STORE_MARKER
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Controls whether `get_blob` checks content against its hash.
+    ///
+    /// Skipping the check saves a full rehash of every blob read, but a blob
+    /// corrupted on disk is then returned as-is, so only turn it off for
+    /// stores whose disk you trust and which `verify` checks regularly.
+    pub fn with_verify_on_read(mut self, verify_on_read: bool) -> Self {
+        self.verify_on_read = verify_on_read;
+        self
+    }
+
+    /// Keeps a small JSON file per namespace up to date alongside
+    /// `indices.json`, so single-gem lookups don't load every index.
+    pub fn with_sharding(mut self, shard_namespaces: bool) -> Self {
+        self.shard_namespaces = shard_namespaces;
+        self
+    }
+
+    /// The directory of `source`'s shards
+    fn shard_dir(&self, source: &str) -> PathBuf {
+        let (_, hex) = Integrity::from(source).to_hex();
+        self.root.join(SHARDS_DIR).join(&hex[..16])
+    }
+
+    /// The shard of namespace `name`. Names come from upstream, so they are
+    /// hashed rather than trusted as file names.
+    fn shard_path(&self, source: &str, name: &str) -> PathBuf {
+        let (_, hex) = Integrity::from(name).to_hex();
+        self.shard_dir(source).join(format!("{}.json", hex))
+    }
+
+    /// Whether the shards were written from the current `indices.json`: they
+    /// are stamped once all are written, after `indices.json` is saved, so a
+    /// save without `--shard-namespaces` leaves them older and unused
+    fn shards_fresh(&self) -> bool {
+        let modified = |path: PathBuf| fs::metadata(path).and_then(|m| m.modified()).ok();
+        match (
+            modified(self.root.join(SHARDS_DIR).join(SHARDS_STAMP)),
+            modified(self.root.join("indices.json")),
+        ) {
+            (Some(stamp), Some(indices)) => stamp >= indices,
+            _ => false,
+        }
+    }
+
+    /// Loads `indices.json`, or no indices if there isn't one yet
+    fn read_indices(&self) -> miette::Result<Vec<Index>> {
+        let path = self.root.join("indices.json");
+        if !path.exists() {
+            return Ok(vec![]);
+        }
+        let file = std::fs::File::open(&path)
+            .map_err(|e| miette!("Failed to open {}: {}", path.display(), e))?;
+        let reader = std::io::BufReader::new(file);
+        let indices = read_indices(reader)
+            .map_err(|e| e.wrap_err(format!("Failed to read {}", path.display())))?;
+        self.set_index_blob_roots(&indices);
+        Ok(indices)
+    }
+
+    /// Rewrites the namespace shards from `indices.json`, returning how many
+    /// were written. `indices.json` remains the source of truth.
+    pub fn write_shards(&self) -> miette::Result<usize> {
+        self.write_shards_for(&self.list_indices()?)
+    }
+
+    /// Writes a shard for every namespace of `indices` and deletes those of
+    /// namespaces and indices that are gone, then stamps them as fresh
+    fn write_shards_for(&self, indices: &[Index]) -> miette::Result<usize> {
+        let shards = self.root.join(SHARDS_DIR);
+        let stamp = shards.join(SHARDS_STAMP);
+        fs::create_dir_all(&shards)
+            .map_err(|e| miette!("Failed to create directory {}: {}", shards.display(), e))?;
+        // Until the stamp is rewritten below, the shards aren't trusted
+        if let Err(e) = fs::remove_file(&stamp)
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            bail!("Failed to remove {}: {}", stamp.display(), e);
+        }
+        let mut written = 0;
+        let mut live = HashSet::new();
+        for index in indices {
+            let dir = self.shard_dir(&index.source);
+            fs::create_dir_all(&dir)
+                .map_err(|e| miette!("Failed to create directory {}: {}", dir.display(), e))?;
+            let source = dir.join(SHARD_SOURCE);
+            fs::write(&source, &index.source)
+                .map_err(|e| miette!("Failed to write {}: {}", source.display(), e))?;
+            live.insert(source);
+            for (name, namespace) in &index.gems {
+                let path = self.shard_path(&index.source, name);
+                let json = serde_json::to_vec(namespace)
+                    .map_err(|e| miette!("Failed to serialize {}: {}", name, e))?;
+                fs::write(&path, json)
+                    .map_err(|e| miette!("Failed to write {}: {}", path.display(), e))?;
+                live.insert(path);
+                written += 1;
+            }
+            live.insert(dir);
+        }
+        for dir in read_dir(&shards)? {
+            if !live.contains(&dir) {
+                fs::remove_dir_all(&dir)
+                    .map_err(|e| miette!("Failed to remove {}: {}", dir.display(), e))?;
+                continue;
+            }
+            for file in read_dir(&dir)? {
+                if !live.contains(&file) {
+                    fs::remove_file(&file)
+                        .map_err(|e| miette!("Failed to remove {}: {}", file.display(), e))?;
+                }
+            }
+        }
+        fs::write(&stamp, b"")
+            .map_err(|e| miette!("Failed to write {}: {}", stamp.display(), e))?;
+        Ok(written)
+    }
+
+    /// Looks up namespace `name` in every index, by source, reading only its
+    /// shards when they are fresh and `indices.json` otherwise
+    pub fn get_namespaces(&self, name: &str) -> miette::Result<Vec<(String, Namespace)>> {
+        let mut namespaces = Vec::new();
+        if !self.shards_fresh() {
+            namespaces = self
+                .read_indices()?
+                .into_iter()
+                .filter_map(|mut index| Some((index.source, index.gems.remove(name)?)))
+                .collect();
+            namespaces.sort_by(|(a, _), (b, _)| a.cmp(b));
+            return Ok(namespaces);
+        }
+        for dir in read_dir(&self.root.join(SHARDS_DIR))? {
+            let Ok(source) = fs::read_to_string(dir.join(SHARD_SOURCE)) else {
+                continue;
+            };
+            let path = self.shard_path(&source, name);
+            let Ok(json) = fs::read(&path) else {
+                continue;
+            };
+            let namespace = serde_json::from_slice(&json)
+                .map_err(|e| miette!("Failed to parse {}: {}", path.display(), e))?;
+            namespaces.push((source, namespace));
+        }
+        namespaces.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(namespaces)
+    }
+
+    /// Sets which hashes `store_blob` computes. SHA-256 is always included.
+    pub fn with_algorithms(mut self, algorithms: Vec<Algorithm>) -> Self {
+        self.algorithms = algorithms;
+        self
+    }
+
+    pub fn blob_root(&self) -> &Path {
+        &self.blob_root
+    }
+
+    /// A cheap liveness check: `indices.json` must open and be in a format
+    /// version this build reads, and the store and blob roots must be
+    /// readable and writable. Neither the indices nor any blob are loaded.
+    pub fn healthcheck(&self) -> miette::Result<()> {
+        let path = self.root.join("indices.json");
+        if path.exists() {
+            let file = fs::File::open(&path)
+                .map_err(|e| miette!("Failed to open {}: {}", path.display(), e))?;
+            read_indices_version(file)
+                .map_err(|e| e.wrap_err(format!("Failed to read {}", path.display())))?;
+        }
+        for dir in [self.root.clone()].into_iter().chain(self.blob_roots()) {
+            fs::read_dir(&dir).map_err(|e| miette!("Failed to read {}: {}", dir.display(), e))?;
+            // Unique per probe, as `serve` answers concurrent `/health`
+            // requests from several threads
+            let probe = dir.join(format!(
+                ".healthcheck-{}-{}",
+                std::process::id(),
+                HEALTHCHECK_PROBES.fetch_add(1, Ordering::Relaxed)
+            ));
+            fs::write(&probe, b"ok")
+                .and_then(|()| fs::remove_file(&probe))
+                .map_err(|e| miette!("Failed to write to {}: {}", dir.display(), e))?;
+        }
+        Ok(())
+    }
+
+    /// Lists every blob in the content store, across every blob directory,
+    /// along with its path on disk
+    pub(crate) fn content_entries(&self) -> miette::Result<Vec<(Integrity, PathBuf)>> {
+        let mut entries = Vec::new();
+        for blob_root in &self.blob_roots() {
+            let content = blob_root.join(BLOB_FORMAT);
+            if content.exists() {
+                Self::content_entries_in(&content, &mut entries)?;
+            }
+        }
+        Ok(entries)
+    }
+
+    fn content_entries_in(
+        content: &Path,
+        entries: &mut Vec<(Integrity, PathBuf)>,
+    ) -> miette::Result<()> {
+        let name = |p: &Path| {
+            p.file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned()
+        };
+        for algo_dir in read_dir(content)? {
+            let Ok(algo) = name(&algo_dir).parse::<ssri::Algorithm>() else {
+                continue;
+            };
+            for first in read_dir(&algo_dir)? {
+                for second in read_dir(&first)? {
+                    for file in read_dir(&second)? {
+                        let hex = format!("{}{}{}", name(&first), name(&second), name(&file));
+                        if let Ok(integrity) = Integrity::from_hex(&hex, algo) {
+                            entries.push((integrity, file));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Cleans up after interrupted writes and repairs the cacache index.
+    ///
+    /// Temp files older than `TMP_FILE_MIN_AGE` are removed as abandoned;
+    /// younger ones may belong to a write still in flight in a concurrent
+    /// `update`, `watch` or `serve --refresh-interval`. Index entries pointing at missing
+    /// content are dropped, and with `verify` every blob is re-hashed and
+    /// removed if it no longer matches its address.
+    pub fn compact(&self, verify: bool) -> miette::Result<CompactReport> {
+        let mut report = CompactReport::default();
+
+        for blob_root in &self.blob_roots() {
+            let tmp = blob_root.join("tmp");
+            if tmp.exists() {
+                for file in read_dir(&tmp)? {
+                    let Ok(metadata) = fs::symlink_metadata(&file) else {
+                        continue;
+                    };
+                    let age = metadata
+                        .modified()
+                        .ok()
+                        .and_then(|modified| modified.elapsed().ok());
+                    if age.is_none_or(|age| age < TMP_FILE_MIN_AGE) {
+                        continue;
+                    }
+                    let len = metadata.len();
+                    let removed = if file.is_dir() {
+                        fs::remove_dir_all(&file)
+                    } else {
+                        fs::remove_file(&file)
+                    };
+                    removed.map_err(|e| miette!("Failed to remove {}: {}", file.display(), e))?;
+                    report.tmp_files_removed += 1;
+                    report.bytes_reclaimed += len;
+                }
+            }
+
+            // cacache fails to list a directory it never wrote an index to,
+            // such as the store's own when every index keeps its blobs apart
+            if !blob_root.join(BLOB_INDEX).exists() {
+                continue;
+            }
+            for entry in cacache::list_sync(blob_root) {
+                let entry = entry.map_err(|e| miette!("Failed to read cache index: {}", e))?;
+                if !cacache::exists_sync(blob_root, &entry.integrity) {
+                    cacache::RemoveOpts::new()
+                        .remove_fully(true)
+                        .remove_sync(blob_root, &entry.key)
+                        .map_err(|e| {
+                            miette!("Failed to remove index entry {}: {}", entry.key, e)
+                        })?;
+                    report.index_entries_removed += 1;
+                }
+            }
+        }
+
+        if verify {
+            for (integrity, path) in self.content_entries()? {
+                let data = fs::read(&path)
+                    .map_err(|e| miette!("Failed to read {}: {}", path.display(), e))?;
+                if integrity.check(&data).is_err() {
+                    eprintln!("Removing corrupt blob {}", integrity);
+                    fs::remove_file(&path)
+                        .map_err(|e| miette!("Failed to remove {}: {}", path.display(), e))?;
+                    report.corrupt_blobs_removed += 1;
+                    report.bytes_reclaimed += data.len() as u64;
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Deletes every blob that no gem in any index refers to, either as its
+    /// package or its extracted `metadata.gz`. With `dry_run`, only reports
+    /// what would be deleted.
+    pub fn prune(&self, dry_run: bool) -> miette::Result<PruneReport> {
+        let mut referenced = HashSet::new();
+        let mut stored = HashSet::new();
+        for index in self.list_indices()?.iter() {
+            for namespace in index.gems.values() {
+                for gem in namespace.versions.values() {
+                    if gem.stored {
+                        stored.insert(gem.full_name.clone());
+                    }
+                    referenced.insert(content_address(&gem.package_integrity).to_string());
+                    for integrity in &gem.previous_integrities {
+                        referenced.insert(content_address(integrity).to_string());
+                    }
+                    if let Some(integrity) = gem.metadata_gz_blob() {
+                        referenced.insert(content_address(integrity).to_string());
+                    }
+                }
+            }
+        }
+
+        let mut report = PruneReport::default();
+        for (integrity, path) in self.content_entries()? {
+            if referenced.contains(&integrity.to_string()) {
+                continue;
+            }
+            let len = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+            if dry_run {
+                println!("Would remove {}", integrity);
+            } else {
+                println!("Removing {}", integrity);
+                fs::remove_file(&path)
+                    .map_err(|e| miette!("Failed to remove {}: {}", path.display(), e))?;
+            }
+            report.blobs_removed += 1;
+            report.bytes_reclaimed += len;
+        }
+
+        // Links left by `--also-link-by-name` for gems no longer stored
+        // would keep hard-linked bytes alive
+        let named = self.root.join(NAMED_GEMS_DIR);
+        if named.is_dir() {
+            for path in read_dir(&named)? {
+                let Some(full_name) = path
+                    .file_name()
+                    .and_then(OsStr::to_str)
+                    .and_then(|file_name| file_name.strip_suffix(".gem"))
+                else {
+                    continue;
+                };
+                if stored.contains(full_name) {
+                    continue;
+                }
+                if dry_run {
+                    println!("Would remove {}", path.display());
+                } else {
+                    println!("Removing {}", path.display());
+                    self.unlink_by_name(full_name)?;
+                }
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// The outcome of `FsStore::prune`
+#[derive(Debug, Default, Serialize)]
+pub struct PruneReport {
+    pub blobs_removed: usize,
+    pub bytes_reclaimed: u64,
+}
+
+/// The outcome of `FsStore::compact`
+#[derive(Debug, Default, Serialize)]
+pub struct CompactReport {
+    pub bytes_reclaimed: u64,
+    pub tmp_files_removed: usize,
+    pub index_entries_removed: usize,
+    pub corrupt_blobs_removed: usize,
+}
+
+fn read_dir(path: &Path) -> miette::Result<Vec<PathBuf>> {
+    fs::read_dir(path)
+        .map_err(|e| miette!("Failed to read directory {}: {}", path.display(), e))?
+        .map(|entry| {
+            entry
+                .map(|e| e.path())
+                .map_err(|e| miette!("Failed to read directory {}: {}", path.display(), e))
+        })
+        .collect()
+}
+
+impl Store for FsStore {
+    /// Kept in the root's marker file. Stores whose marker predates
+    /// identifiers are given one the first time it is asked for, and
+    /// directories without a marker have none.
+    fn store_id(&self) -> miette::Result<Option<String>> {
+        let marker = self.root.join(STORE_MARKER);
+        let Ok(contents) = fs::read_to_string(&marker) else {
+            return Ok(None);
+        };
+        if !contents.trim().is_empty() {
+            return Ok(Some(contents.trim().to_string()));
+        }
+        let store_id = new_store_id();
+        fs::write(&marker, &store_id)
+            .map_err(|e| miette!("Failed to write {}: {}", marker.display(), e))?;
+        Ok(Some(store_id))
+    }
+
+    fn list_indices(&self) -> miette::Result<Cow<'_, [Index]>> {
+        self.read_indices().map(Cow::Owned)
+    }
+
+    fn verified_blobs(&self) -> miette::Result<HashMap<String, u64>> {
+        let path = self.root.join(VERIFIED_FILE);
+        if !path.exists() {
+            return Ok(HashMap::new());
+        }
+        let contents =
+            fs::read(&path).map_err(|e| miette!("Failed to read {}: {}", path.display(), e))?;
+        serde_json::from_slice(&contents)
+            .map_err(|e| miette!("Failed to parse {}: {}", path.display(), e))
+    }
+
+    fn set_verified_blobs(&self, verified: &HashMap<String, u64>) -> miette::Result<()> {
+        let path = self.root.join(VERIFIED_FILE);
+        let tmp_path = self.root.join(format!("{}.tmp", VERIFIED_FILE));
+        let contents = serde_json::to_vec(verified)
+            .map_err(|e| miette!("Failed to serialize {}: {}", path.display(), e))?;
+        fs::write(&tmp_path, contents)
+            .map_err(|e| miette!("Failed to write {}: {}", tmp_path.display(), e))?;
+        fs::rename(&tmp_path, &path)
+            .map_err(|e| miette!("Failed to replace {}: {}", path.display(), e))
+    }
+
+    fn for_each_gem(&self, f: impl FnMut(&str, &Gem) -> miette::Result<()>) -> miette::Result<()> {
+        let path = self.root.join("indices.json");
+        if !path.exists() {
+            return Ok(());
+        }
+        let file = std::fs::File::open(&path)
+            .map_err(|e| miette!("Failed to open {}: {}", path.display(), e))?;
+        for_each_gem_in(std::io::BufReader::new(file), f)
+    }
+
+    fn for_index(&self, index: &Index) -> Option<Self> {
+        let blob_root = index.config.blob_root.as_ref()?;
+        Some(Self {
+            write_root: Some(blob_root.clone()),
+            ..self.clone()
+        })
+    }
+
+    /// The `tmp` directory cacache writes through in the blob root this
+    /// store writes to, which `compact` cleans of abandoned files
+    fn download_dir(&self) -> PathBuf {
+        self.write_root
+            .as_ref()
+            .unwrap_or(&self.blob_root)
+            .join("tmp")
+    }
+
+    fn store_blob<B: AsRef<[u8]>>(&self, blob: B) -> miette::Result<Integrity> {
+        let write_root = self.write_root.as_ref().unwrap_or(&self.blob_root);
+        // With several blob directories, content one already has isn't copied
+        // into another, just as `has_blob` spares downloading it again
+        let existing = (self.blob_roots().len() > 1)
+            .then(|| {
+                IntegrityOpts::new()
+                    .algorithm(Algorithm::Sha256)
+                    .chain(&blob)
+                    .result()
+            })
+            .filter(|integrity| self.has_blob(integrity));
+        let integrity = match existing {
+            Some(integrity) => integrity,
+            None => cacache::write_hash_sync_with_algo(Algorithm::Sha256, write_root, &blob)
+                .map_err(|e| miette!("Failed to store blob: {}", e))?,
+        };
+        let extra = self
+            .algorithms
+            .iter()
+            .filter(|algorithm| **algorithm != Algorithm::Sha256)
+            .fold(None, |opts: Option<IntegrityOpts>, algorithm| {
+                Some(opts.unwrap_or_default().algorithm(*algorithm))
+            });
+        Ok(match extra {
+            Some(opts) => integrity.concat(opts.chain(&blob).result()),
+            None => integrity,
+        })
+    }
+
+    fn get_blob(&self, sha256: &Integrity) -> miette::Result<Vec<u8>> {
+        if self.verify_on_read {
+            let blob_root = self
+                .blob_root_with(sha256)
+                .unwrap_or_else(|| self.blob_root.clone());
+            return cacache::read_hash_sync(blob_root, &content_address(sha256))
+                .map_err(|e| miette!("Failed to get blob: {}", e));
+        }
+        let mut blob = Vec::new();
+        self.get_blob_reader(sha256)?
+            .read_to_end(&mut blob)
+            .map_err(|e| miette!("Failed to get blob: {}", e))?;
+        Ok(blob)
+    }
+
+    /// cacache's hash-checked read, regardless of `--no-verify-on-read`
+    fn check_blob(&self, sha256: &Integrity) -> miette::Result<()> {
+        let blob_root = self
+            .blob_root_with(sha256)
+            .unwrap_or_else(|| self.blob_root.clone());
+        cacache::read_hash_sync(blob_root, &content_address(sha256))
+            .map(|_| ())
+            .map_err(|e| miette!("Failed to get blob: {}", e))
+    }
+
+    fn get_blob_reader(&self, sha256: &Integrity) -> miette::Result<impl Read> {
+        let blob_root = self
+            .blob_root_with(sha256)
+            .unwrap_or_else(|| self.blob_root.clone());
+        cacache::SyncReader::open_hash(blob_root, content_address(sha256))
+            .map_err(|e| miette!("Failed to get blob: {}", e))
+    }
+
+    /// Whether any blob directory has the blob, so an index that keeps its
+    /// blobs apart doesn't download again what another index already has
+    fn has_blob(&self, sha256: &Integrity) -> bool {
+        self.blob_root_with(sha256).is_some()
+    }
+
+    fn remove_blob(&self, sha256: &Integrity) -> miette::Result<()> {
+        let address = content_address(sha256);
+        for blob_root in &self.blob_roots() {
+            if cacache::exists_sync(blob_root, &address) {
+                cacache::remove_hash_sync(blob_root, &address)
+                    .map_err(|e| miette!("Failed to remove blob {}: {}", sha256, e))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Hard links the blob into `gems/`, so the named file takes no extra
+    /// space
+    fn link_by_name(&self, gem: &Gem, replace: bool) -> miette::Result<()> {
+        let path = self
+            .root
+            .join(NAMED_GEMS_DIR)
+            .join(format!("{}.gem", gem.full_name));
+        if !replace && path.exists() {
+            return Ok(());
+        }
+        let blob = self
+            .blob_location(&gem.package_integrity)
+            .ok_or_else(|| miette!("Blob for {} is missing", gem.full_name))?;
+        let link_error = |e| miette!("Failed to link {}: {}", path.display(), e);
+        fs::create_dir_all(self.root.join(NAMED_GEMS_DIR)).map_err(link_error)?;
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(link_error(e)),
+            _ => {}
+        }
+        // Hard links don't cross filesystems, nor does every filesystem
+        // support them, so fall back to a copy
+        if fs::hard_link(&blob, &path).is_err() {
+            fs::copy(&blob, &path).map_err(link_error)?;
+        }
+        Ok(())
+    }
+
+    fn unlink_by_name(&self, full_name: &str) -> miette::Result<()> {
+        let path = self
+            .root
+            .join(NAMED_GEMS_DIR)
+            .join(format!("{}.gem", full_name));
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(miette!("Failed to remove {}: {}", path.display(), e))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// cacache doesn't expose where it keeps content written by hash, so
+    /// this follows its `content-v2/<algorithm>/<xx>/<yy>/<rest>` layout,
+    /// the same one `content_entries` walks.
+    fn blob_location(&self, sha256: &Integrity) -> Option<PathBuf> {
+        let (algorithm, hex) = content_address(sha256).to_hex();
+        let path = self
+            .blob_root_with(sha256)?
+            .join(BLOB_FORMAT)
+            .join(algorithm.to_string())
+            .join(&hex[..2])
+            .join(&hex[2..4])
+            .join(&hex[4..]);
+        path.is_file().then_some(path)
+    }
+
+    fn with_indices<F, R>(&mut self, f: F) -> miette::Result<R>
+    where
+        F: FnOnce(&mut Self, &mut Vec<Index>) -> miette::Result<R>,
+    {
+        let mut indices = self.read_indices()?;
+        let result = f(self, &mut indices)?;
+        // Write to a temp file and rename it over the old one, so a crash
+        // mid-write can't leave a truncated `indices.json`.
+        let path = self.root.join("indices.json");
+        let tmp_path = self.root.join("indices.json.tmp");
+        let file = fs::File::create(&tmp_path)
+            .map_err(|e| miette!("Failed to open {}: {}", tmp_path.display(), e))?;
+        let mut writer = std::io::BufWriter::new(file);
+        write_indices(&mut writer, &indices)?;
+        writer
+            .into_inner()
+            .map_err(|e| miette!("Failed to write {}: {}", tmp_path.display(), e.error()))?
+            .sync_all()
+            .map_err(|e| miette!("Failed to write {}: {}", tmp_path.display(), e))?;
+        fs::rename(&tmp_path, &path)
+            .map_err(|e| miette!("Failed to replace {}: {}", path.display(), e))?;
+        // The rename itself is only durable once the directory is synced
+        fs::File::open(&self.root)
+            .and_then(|dir| dir.sync_all())
+            .map_err(|e| miette!("Failed to sync {}: {}", self.root.display(), e))?;
+        self.set_index_blob_roots(&indices);
+        if self.shard_namespaces {
+            self.write_shards_for(&indices)?;
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory for a test's store, removed first if a previous run
+    /// left it behind
+    fn temp_store_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("gem-mirror-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    const CHECKSUM: &str =
+        "checksum:ab2a6f5b3d3f1e0f4c6d7a8b9c0d1e2f3a4b5c6d7e8f9a0b1c2d3e4f5a6b7c8d";
+
+    #[test]
+    fn platform_full_names_round_trip() {
+        for (line, full_name, platform) in [
+            ("1.15.0-java |", "nokogiri-1.15.0-java", "java"),
+            (
+                "1.15.0-universal-darwin-20 |",
+                "nokogiri-1.15.0-universal-darwin-20",
+                "universal-darwin-20",
+            ),
+            ("1.15.0 |", "nokogiri-1.15.0", "ruby"),
+        ] {
+            let gem = parse_info_line("nokogiri", &format!("{}{}", line, CHECKSUM)).unwrap();
+            assert_eq!(gem.full_name, full_name);
+            assert_eq!(gem.platform, platform);
+            validate_full_name(&gem).unwrap();
+        }
+    }
+
+    #[test]
+    fn malformed_platforms_fail_validation() {
+        let gem = parse_info_line("rack", &format!("1.0-a%b |{}", CHECKSUM)).unwrap();
+        assert!(validate_full_name(&gem).is_err());
+    }
+
+    #[test]
+    fn gem_urls_refuse_names_that_cant_be_a_path_segment() {
+        let source = "https://rubygems.org";
+        for full_name in [
+            "rack-3.0.0/../x",
+            "rack 3.0.0",
+            "rack-3.0.0\t",
+            ".rack-3.0.0",
+            "",
+        ] {
+            assert!(gem_url(source, full_name, true).is_err(), "{full_name:?}");
+        }
+        assert_eq!(
+            gem_url(source, "rack-1.0-a%b?c", true).unwrap(),
+            "https://rubygems.org/gems/rack-1.0-a%25b%3Fc.gem"
+        );
+        assert_eq!(
+            gem_url(source, "nokogiri-1.15.0-x86_64-linux+gnu", true).unwrap(),
+            "https://rubygems.org/gems/nokogiri-1.15.0-x86_64-linux+gnu.gem"
+        );
+        assert_eq!(
+            gem_url(source, "rack 3.0.0", false).unwrap(),
+            "https://rubygems.org/gems/rack 3.0.0.gem"
+        );
+    }
+
+    #[test]
+    fn normalize_checksum_strips_etag_quoting() {
+        for raw in ["\"abc123\"", "W/\"abc123\"", "abc123", " W/\"abc123\" "] {
+            assert_eq!(normalize_checksum(raw), "abc123", "{raw}");
+        }
+    }
+
+    fn namespace(name: &str, lines: &[&str], stored: bool) -> Namespace {
+        let versions = lines
+            .iter()
+            .map(|line| {
+                let mut gem = parse_info_line(name, &format!("{} |{}", line, CHECKSUM)).unwrap();
+                gem.stored = stored;
+                (gem.full_name.clone(), gem)
+            })
+            .collect();
+        Namespace {
+            name: name.to_string(),
+            info_checksum: format!("{}-{}", name, lines.join(",")),
+            versions,
+            last_updated: None,
+            skipped_lines: 0,
+        }
+    }
+
+    /// An index whose `rack` namespace has one stored version, after an
+    /// update found every version yanked upstream
+    fn all_yanked(options: &UpdateOptions) -> Index {
+        let mut index = Index::default();
+        index
+            .gems
+            .insert("rack".to_string(), namespace("rack", &["3.0.0"], true));
+        let fetcher = Fetcher::new(options).unwrap();
+        apply_namespace(&fetcher, &mut index, namespace("rack", &[], false), options).unwrap();
+        index
+    }
+
+    #[test]
+    fn all_yanked_namespace_keeps_stored_versions_by_default() {
+        let index = all_yanked(&UpdateOptions::default());
+        let versions = &index.gems["rack"].versions;
+        assert!(versions["rack-3.0.0"].stored);
+        assert!(!versions["rack-3.0.0"].yanked);
+    }
+
+    #[test]
+    fn all_yanked_namespace_under_reconcile() {
+        let index = all_yanked(&UpdateOptions {
+            reconcile: true,
+            ..Default::default()
+        });
+        assert!(index.gems["rack"].versions.is_empty());
+
+        let index = all_yanked(&UpdateOptions {
+            reconcile: true,
+            mark_yanked: true,
+            ..Default::default()
+        });
+        assert!(index.gems["rack"].versions["rack-3.0.0"].yanked);
+    }
+
+    #[test]
+    fn all_yanked_namespace_is_dropped_when_asked() {
+        let index = all_yanked(&UpdateOptions {
+            drop_empty_namespaces: true,
+            ..Default::default()
+        });
+        assert!(!index.gems.contains_key("rack"));
+    }
+
+    #[test]
+    fn reconcile_keeps_versions_whose_lines_were_skipped() {
+        let options = UpdateOptions {
+            reconcile: true,
+            drop_empty_namespaces: true,
+            ..Default::default()
+        };
+        let fetcher = Fetcher::new(&options).unwrap();
+        let mut index = Index::default();
+        index.gems.insert(
+            "rack".to_string(),
+            namespace("rack", &["2.0.0", "3.0.0"], true),
+        );
+        let mut fetched = namespace("rack", &["3.0.0"], false);
+        fetched.skipped_lines = 1;
+        apply_namespace(&fetcher, &mut index, fetched, &options).unwrap();
+        assert!(index.gems["rack"].versions["rack-2.0.0"].stored);
+
+        let mut fetched = namespace("rack", &[], false);
+        fetched.skipped_lines = 2;
+        apply_namespace(&fetcher, &mut index, fetched, &options).unwrap();
+        assert_eq!(index.gems["rack"].versions.len(), 2);
+    }
+
+    /// The blobs `--exclude-yanked` would delete after `fetched` is applied
+    /// to an index whose `rack` has versions 2.0.0 and 3.0.0 stored
+    fn unlisted_after(fetched: Namespace, options: &UpdateOptions) -> Vec<Integrity> {
+        let mut stored = namespace("rack", &["2.0.0", "3.0.0"], true);
+        for (i, gem) in stored.versions.values_mut().enumerate() {
+            gem.package_integrity = Integrity::from(format!("rack {}", i));
+        }
+        let mut index = Index::default();
+        index.gems.insert("rack".to_string(), stored);
+        let mut indices = vec![index];
+        let listed_before = listed_blobs(&indices);
+        let fetcher = Fetcher::new(options).unwrap();
+        apply_namespace(&fetcher, &mut indices[0], fetched, options).unwrap();
+        unlisted_blobs(&indices, listed_before)
+            .into_iter()
+            .map(|(_, _, integrity)| integrity)
+            .collect()
+    }
+
+    #[test]
+    fn exclude_yanked_only_deletes_for_cleanly_parsed_namespaces() {
+        let options = UpdateOptions {
+            reconcile: true,
+            exclude_yanked: true,
+            drop_empty_namespaces: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            unlisted_after(namespace("rack", &["3.0.0"], false), &options).len(),
+            1
+        );
+
+        let mut skipped = namespace("rack", &["3.0.0"], false);
+        skipped.skipped_lines = 1;
+        assert!(unlisted_after(skipped, &options).is_empty());
+
+        assert!(unlisted_after(namespace("rack", &[], false), &options).is_empty());
+    }
+
+    #[test]
+    fn info_lines_without_a_space_or_pipe_fail() {
+        assert!(parse_info_line("rack", CHECKSUM).is_err());
+        assert!(parse_info_line("rack", &format!("3.0.0{}", CHECKSUM)).is_err());
+        assert!(parse_info_line("rack", &format!("3.0.0 {}", CHECKSUM)).is_err());
+    }
+
+    #[test]
+    fn info_lines_with_no_dependencies_parse() {
+        let gem = parse_info_line("rack", &format!("3.0.0 |{}", CHECKSUM)).unwrap();
+        assert_eq!(gem.full_name, "rack-3.0.0");
+        assert!(gem.dependencies.is_empty());
+
+        let gem = parse_info_line(
+            "rails",
+            &format!("7.0.0 rack:>= 2.2&< 4,thor:~> 1.0|{}", CHECKSUM),
+        )
+        .unwrap();
+        let dependencies = gem
+            .dependencies
+            .iter()
+            .map(|dependency| (dependency.name.as_str(), dependency.requirements.len()))
+            .collect::<Vec<_>>();
+        assert_eq!(dependencies, [("rack", 2), ("thor", 1)]);
+    }
+
+    #[test]
+    fn inline_metadata_is_read_through_metadata_gz() {
+        let store = MemoryStore::default();
+        let metadata = b"--- !ruby/object:Gem::Specification".to_vec();
+        let mut gem = parse_info_line("rack", &format!("3.0.0 |{}", CHECKSUM)).unwrap();
+        gem.set_metadata(Some(Metadata {
+            integrity: Integrity::from(&metadata),
+            inline: Some(BASE64_STANDARD.encode(&metadata)),
+        }));
+        assert!(gem.metadata_gz_blob().is_none());
+        assert_eq!(gem.metadata_gz(&store).unwrap(), Some(metadata));
+    }
+
+    #[test]
+    fn purge_keeps_blobs_other_versions_keep_as_previous() {
+        let mut store = MemoryStore::default();
+        let shared = store.store_blob(b"rack 3.0.0, first upload").unwrap();
+        let own = store.store_blob(b"rack 3.0.0, from the fork").unwrap();
+
+        let mut upstream = namespace("rack", &["3.0.0"], true);
+        let republished = upstream.versions.get_mut("rack-3.0.0").unwrap();
+        republished.previous_integrities.push(shared.clone());
+        let mut fork = namespace("rack", &["3.0.0"], true);
+        let forked = fork.versions.remove("rack-3.0.0").unwrap();
+        let forked = Gem {
+            full_name: "rack-3.0.0.fork".to_string(),
+            version: "3.0.0.fork".to_string(),
+            package_integrity: shared.clone(),
+            previous_integrities: vec![own.clone()],
+            ..forked
+        };
+        fork.versions.insert(forked.full_name.clone(), forked);
+        for (source, namespace) in [("https://a.example", upstream), ("https://b.example", fork)] {
+            let mut index = Index {
+                source: source.to_string(),
+                ..Default::default()
+            };
+            index.gems.insert("rack".to_string(), namespace);
+            store.indices.push(index);
+        }
+
+        assert_eq!(purge(&mut store, "rack-3.0.0.fork", true).unwrap(), 1);
+        assert!(!store.has_blob(&own));
+        assert!(store.has_blob(&shared));
+    }
+
+    #[test]
+    fn create_store_dir_adopts_only_unmarked_stores() {
+        let dir = temp_store_dir("adopt");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("indices.json"), "not json").unwrap();
+        create_store_dir(&dir).unwrap();
+        assert!(!dir.join(STORE_MARKER).exists());
+
+        // Even once this run has written a store into it
+        let mut indices = Vec::new();
+        write_indices(&mut indices, &[]).unwrap();
+        fs::write(dir.join("indices.json"), &indices).unwrap();
+        create_store_dir(&dir).unwrap();
+        assert!(!dir.join(STORE_MARKER).exists());
+
+        let old = temp_store_dir("adopt-old");
+        fs::create_dir_all(&old).unwrap();
+        fs::write(old.join("indices.json"), &indices).unwrap();
+        create_store_dir(&old).unwrap();
+        assert!(old.join(STORE_MARKER).exists());
+
+        let blobs = temp_store_dir("adopt-blobs");
+        fs::create_dir_all(blobs.join(BLOB_FORMAT).join("sha256")).unwrap();
+        create_store_dir(&blobs).unwrap();
+        assert!(blobs.join(STORE_MARKER).exists());
+
+        let other = temp_store_dir("adopt-other");
+        fs::create_dir_all(other.join(BLOB_FORMAT).join("photos")).unwrap();
+        create_store_dir(&other).unwrap();
+        assert!(!other.join(STORE_MARKER).exists());
+    }
+
+    #[test]
+    fn dedupe_merge_keeps_yanked_flags() {
+        let mut kept = namespace("rack", &["2.0.0", "3.0.0"], true);
+        kept.versions.get_mut("rack-2.0.0").unwrap().yanked = true;
+        let mut store = MemoryStore::default();
+        for (source, namespace) in [
+            ("https://a.example/", kept.clone()),
+            ("https://b.example/", kept),
+        ] {
+            let mut index = Index {
+                source: source.to_string(),
+                ..Index::default()
+            };
+            index.gems.insert("rack".to_string(), namespace);
+            store.indices.push(index);
+        }
+        assert_eq!(dedupe_sources(&mut store, 1.0, true).unwrap().len(), 1);
+        assert_eq!(store.indices.len(), 1);
+        let versions = &store.indices[0].gems["rack"].versions;
+        assert!(versions["rack-2.0.0"].yanked);
+        assert!(!versions["rack-3.0.0"].yanked);
+    }
+
+    #[test]
+    fn threshold_must_be_a_fraction() {
+        assert_eq!(parse_threshold("0.9"), Ok(0.9));
+        assert_eq!(parse_threshold("1"), Ok(1.0));
+        for invalid in ["1.5", "-0.1", "NaN", "most"] {
+            assert!(parse_threshold(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn index_blob_roots_follow_indices_json() {
+        let dir = temp_store_dir("blob-roots");
+        let store = FsStore::new(&dir, &dir).unwrap();
+        let extra = dir.join("extra");
+        fs::write(dir.join("indices.json"), "{").unwrap();
+        assert_eq!(store.blob_roots(), std::slice::from_ref(&dir));
+
+        // Neither an unreadable file nor an earlier read hides a later root
+        let mut indices = Vec::new();
+        let index = Index {
+            source: "https://a.example/".to_string(),
+            config: IndexConfig {
+                blob_root: Some(extra.clone()),
+                ..IndexConfig::default()
+            },
+            ..Index::default()
+        };
+        write_indices(&mut indices, &[index]).unwrap();
+        fs::write(dir.join("indices.json"), &indices).unwrap();
+        assert_eq!(store.blob_roots(), [dir.clone(), extra.clone()]);
+
+        let other = dir.join("other");
+        let mut store = store;
+        store
+            .with_indices(|_, indices| {
+                indices[0].config.blob_root = Some(other.clone());
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(store.blob_roots(), [dir, other]);
+    }
+
+    #[test]
+    fn content_range_start_reads_the_first_byte() {
+        let start = |value: Option<&str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(value) = value {
+                headers.insert(CONTENT_RANGE, value.parse().unwrap());
+            }
+            content_range_start(&headers)
+        };
+        assert_eq!(start(Some("bytes 100-199/200")), Some(100));
+        assert_eq!(start(Some("bytes 0-99/*")), Some(0));
+        assert_eq!(start(Some("bytes */200")), None);
+        assert_eq!(start(Some("items 100-199/200")), None);
+        assert_eq!(start(None), None);
+    }
+
+    /// Reads a request's head off `stream`, lowercased
+    fn read_request(stream: &mut std::net::TcpStream) -> String {
+        let mut request = Vec::new();
+        let mut byte = [0];
+        while !request.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte).unwrap();
+            request.push(byte[0]);
+        }
+        String::from_utf8(request).unwrap().to_ascii_lowercase()
+    }
+
+    #[test]
+    fn dropped_downloads_resume_from_where_they_stopped() {
+        use std::io::Write;
+
+        let mut dot_gem = tar::Builder::new(Vec::new());
+        let data = vec![b'x'; 4096];
+        let mut header = tar::Header::new_ustar();
+        header.set_size(data.len() as u64);
+        header.set_cksum();
+        dot_gem
+            .append_data(&mut header, "data.tar.gz", data.as_slice())
+            .unwrap();
+        let dot_gem = dot_gem.into_inner().unwrap();
+        let half = dot_gem.len() / 2;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let source = format!("http://{}", listener.local_addr().unwrap());
+        let server = std::thread::scope(|scope| {
+            let server = scope.spawn(|| {
+                // The first response drops the connection halfway through
+                let (mut stream, _) = listener.accept().unwrap();
+                read_request(&mut stream);
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nETag: \"v1\"\r\n\r\n",
+                    dot_gem.len()
+                )
+                .unwrap();
+                stream.write_all(&dot_gem[..half]).unwrap();
+                drop(stream);
+
+                let (mut stream, _) = listener.accept().unwrap();
+                let request = read_request(&mut stream);
+                write!(
+                    stream,
+                    "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\n\
+                     Content-Range: bytes {}-{}/{}\r\nConnection: close\r\n\r\n",
+                    dot_gem.len() - half,
+                    half,
+                    dot_gem.len() - 1,
+                    dot_gem.len()
+                )
+                .unwrap();
+                stream.write_all(&dot_gem[half..]).unwrap();
+                request
+            });
+
+            let options = UpdateOptions {
+                max_retries: 1,
+                ..Default::default()
+            };
+            let fetcher = Fetcher::new(&options).unwrap();
+            let gem = parse_info_line("rack", &format!("3.0.0 |{}", CHECKSUM)).unwrap();
+            let store = MemoryStore::default();
+            let blob = download_gem(&fetcher, &store, &source, &gem, &options)
+                .unwrap()
+                .unwrap();
+            assert_eq!(blob.read().unwrap(), dot_gem);
+            let path = blob.path.clone();
+            drop(blob);
+            assert!(!path.exists());
+            server.join().unwrap()
+        });
+        assert!(server.contains(&format!("range: bytes={}-\r\n", half)));
+        assert!(server.contains("if-range: \"v1\"\r\n"));
+    }
+
+    #[test]
+    fn clobbering_an_index_keeps_its_blob_root() {
+        let mut store = MemoryStore::default();
+        let config = |blob_root: Option<&str>| IndexConfig {
+            blob_root: blob_root.map(PathBuf::from),
+            ..Default::default()
+        };
+        let source = "https://rubygems.org".to_string();
+        store
+            .add_index(source.clone(), config(Some("/disk/a")), false)
+            .unwrap();
+        store.add_index(source.clone(), config(None), true).unwrap();
+        assert_eq!(
+            store.indices[0].config.blob_root.as_deref(),
+            Some(Path::new("/disk/a"))
+        );
+
+        store.indices[0]
+            .gems
+            .insert("rack".to_string(), namespace("rack", &["3.0.0"], true));
+        assert!(
+            store
+                .add_index(source.clone(), config(Some("/disk/b")), true)
+                .is_err()
+        );
+        store
+            .add_index(source, config(Some("/disk/a")), true)
+            .unwrap();
+    }
+
+    #[test]
+    fn purging_a_version_removes_its_named_link() {
+        let dir = temp_store_dir("unlink");
+        let mut store = FsStore::new(&dir, &dir).unwrap();
+        let integrity = store.store_blob(b"rack 3.0.0").unwrap();
+        let mut gem = parse_info_line("rack", &format!("3.0.0 |{}", CHECKSUM)).unwrap();
+        gem.package_integrity = integrity;
+        gem.stored = true;
+        store.link_by_name(&gem, false).unwrap();
+        let link = dir.join(NAMED_GEMS_DIR).join("rack-3.0.0.gem");
+        assert!(link.is_file());
+
+        let mut index = Index::default();
+        index.gems.insert(
+            "rack".to_string(),
+            Namespace {
+                versions: HashMap::from([(gem.full_name.clone(), gem)]),
+                ..Default::default()
+            },
+        );
+        store
+            .with_indices(|_, indices| {
+                indices.push(index);
+                Ok(())
+            })
+            .unwrap();
+        purge(&mut store, "rack-3.0.0", true).unwrap();
+        assert!(!link.exists());
+    }
+
+    #[test]
+    fn concurrent_healthchecks_pass() {
+        let dir = temp_store_dir("health");
+        let store = FsStore::new(&dir, &dir).unwrap();
+        let mut indices = Vec::new();
+        write_indices(&mut indices, &[]).unwrap();
+        fs::write(dir.join("indices.json"), &indices).unwrap();
+        std::thread::scope(|scope| {
+            let probes =
+                [(); 8].map(|()| scope.spawn(|| (0..20).try_for_each(|_| store.healthcheck())));
+            for probe in probes {
+                probe.join().unwrap().unwrap();
+            }
+        });
+
+        fs::write(dir.join("indices.json"), "{\"version\":999,\"indices\":[]}").unwrap();
+        assert!(store.healthcheck().is_err());
+        fs::write(dir.join("indices.json"), "not json").unwrap();
+        assert!(store.healthcheck().is_err());
+        fs::write(dir.join("indices.json"), "[]").unwrap();
+        store.healthcheck().unwrap();
+    }
+
+    #[test]
+    fn concurrent_identical_writes_store_one_blob() {
+        let dir = temp_store_dir("concurrent-writes");
+        let store = FsStore::new(&dir, &dir).unwrap();
+        let blob = vec![7u8; 64 * 1024];
+        let integrities = std::thread::scope(|scope| {
+            let writers = [(); 2].map(|()| scope.spawn(|| store.store_blob(&blob).unwrap()));
+            writers.map(|writer| writer.join().unwrap())
+        });
+        assert_eq!(integrities[0], integrities[1]);
+        assert_eq!(store.content_entries().unwrap().len(), 1);
+        assert_eq!(store.get_blob(&integrities[0]).unwrap(), blob);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn check_blob_rehashes_without_verify_on_read() {
+        let dir = temp_store_dir("check-blob");
+        let store = FsStore::new(&dir, &dir).unwrap().with_verify_on_read(false);
+        let integrity = store.store_blob(b"pristine").unwrap();
+        store.check_blob(&integrity).unwrap();
+        let path = store.blob_location(&integrity).unwrap();
+        let mut permissions = fs::metadata(&path).unwrap().permissions();
+        #[allow(clippy::permissions_set_readonly_false)]
+        permissions.set_readonly(false);
+        fs::set_permissions(&path, permissions).unwrap();
+        fs::write(&path, b"rotted!!").unwrap();
+        assert_eq!(store.get_blob(&integrity).unwrap(), b"rotted!!");
+        assert!(store.check_blob(&integrity).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn concurrent_identical_writes_to_memory_store() {
+        let store = MemoryStore::default();
+        let blob = b"shared dependency".to_vec();
+        let integrities = std::thread::scope(|scope| {
+            let writers = [(); 2].map(|()| scope.spawn(|| store.store_blob(&blob).unwrap()));
+            writers.map(|writer| writer.join().unwrap())
+        });
+        assert_eq!(integrities[0], integrities[1]);
+        assert_eq!(store.blobs.read().unwrap().len(), 1);
+        assert!(store.has_blob(&integrities[0]));
     }
 }
@@ -0,0 +1,92 @@
+//! Timestamps as the compact index and gem-mirror's own logs write them.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Formats `time` as an RFC 3339 UTC timestamp, e.g. `2024-01-31T12:00:00Z`
+pub(crate) fn format_timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, rem) = (secs / 86400, secs % 86400);
+    // Howard Hinnant's days-to-civil algorithm
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// Parses a `--versions-created-at` timestamp, in the
+/// `YYYY-MM-DDTHH:MM:SSZ` form `format_timestamp` writes
+pub(crate) fn parse_timestamp(s: &str) -> Result<String, String> {
+    let field = |range: std::ops::Range<usize>| {
+        s.get(range)
+            .filter(|field| field.chars().all(|c| c.is_ascii_digit()))
+            .and_then(|field| field.parse::<u16>().ok())
+    };
+    let separators = [
+        (4, b'-'),
+        (7, b'-'),
+        (10, b'T'),
+        (13, b':'),
+        (16, b':'),
+        (19, b'Z'),
+    ];
+    let valid = s.len() == 20
+        && separators
+            .iter()
+            .all(|&(at, separator)| s.as_bytes()[at] == separator)
+        && field(0..4).is_some()
+        && field(5..7).is_some_and(|month| (1..=12).contains(&month))
+        && field(8..10).is_some_and(|day| (1..=31).contains(&day))
+        && field(11..13).is_some_and(|hour| hour < 24)
+        && field(14..16).is_some_and(|minute| minute < 60)
+        && field(17..19).is_some_and(|second| second < 60);
+    if !valid {
+        return Err(format!(
+            "expected a YYYY-MM-DDTHH:MM:SSZ timestamp, got {:?}",
+            s
+        ));
+    }
+    Ok(s.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_what_format_timestamp_writes() {
+        let time = UNIX_EPOCH + std::time::Duration::from_secs(1_706_702_400);
+        let formatted = format_timestamp(time);
+        assert_eq!(formatted, "2024-01-31T12:00:00Z");
+        assert_eq!(parse_timestamp(&formatted), Ok(formatted));
+    }
+
+    #[test]
+    fn rejects_other_timestamp_forms() {
+        for invalid in [
+            "2024-01-31",
+            "2024-01-31 12:00:00Z",
+            "2024-01-31T12:00:00+00:00",
+            "2024-13-31T12:00:00Z",
+            "2024-01-31T24:00:00Z",
+        ] {
+            assert!(parse_timestamp(invalid).is_err(), "{invalid}");
+        }
+    }
+}
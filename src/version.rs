@@ -0,0 +1,326 @@
+//! RubyGems-compatible version parsing and ordering.
+//!
+//! This mirrors `Gem::Version#<=>`: versions are split into numeric and
+//! string segments, trailing zeros are ignored, and any string segment marks
+//! a prerelease that sorts before the corresponding release.
+
+use std::{cmp::Ordering, fmt, str::FromStr};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    /// Digits with leading zeros stripped, compared by length then lexically
+    /// so arbitrarily large numbers order correctly.
+    Number(String),
+    String(String),
+}
+
+impl Segment {
+    fn is_zero(&self) -> bool {
+        matches!(self, Segment::Number(n) if n.is_empty())
+    }
+}
+
+impl Ord for Segment {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Segment::Number(a), Segment::Number(b)) => a.len().cmp(&b.len()).then(a.cmp(b)),
+            (Segment::String(a), Segment::String(b)) => a.cmp(b),
+            (Segment::String(_), Segment::Number(_)) => Ordering::Less,
+            (Segment::Number(_), Segment::String(_)) => Ordering::Greater,
+        }
+    }
+}
+
+impl PartialOrd for Segment {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Version {
+    original: String,
+    segments: Vec<Segment>,
+}
+
+impl Version {
+    pub fn parse(version: &str) -> Self {
+        let mut segments = Vec::new();
+        let mut chars = version.trim().chars().peekable();
+        while let Some(&c) = chars.peek() {
+            if c.is_ascii_digit() {
+                let mut digits = String::new();
+                while let Some(&c) = chars.peek().filter(|c| c.is_ascii_digit()) {
+                    digits.push(c);
+                    chars.next();
+                }
+                segments.push(Segment::Number(digits.trim_start_matches('0').to_string()));
+            } else if c.is_ascii_alphabetic() {
+                let mut letters = String::new();
+                while let Some(&c) = chars.peek().filter(|c| c.is_ascii_alphabetic()) {
+                    letters.push(c);
+                    chars.next();
+                }
+                segments.push(Segment::String(letters));
+            } else {
+                chars.next();
+            }
+        }
+
+        // Canonicalize like `Gem::Version#canonical_segments`: drop trailing
+        // zeros from both the release and the prerelease parts.
+        let split = segments
+            .iter()
+            .position(|s| matches!(s, Segment::String(_)))
+            .unwrap_or(segments.len());
+        let mut prerelease = segments.split_off(split);
+        while segments.last().is_some_and(Segment::is_zero) {
+            segments.pop();
+        }
+        while prerelease.last().is_some_and(Segment::is_zero) {
+            prerelease.pop();
+        }
+        segments.extend(prerelease);
+
+        Self {
+            original: version.to_string(),
+            segments,
+        }
+    }
+
+    /// The version without its prerelease part, like `Gem::Version#release`
+    fn release(&self) -> Version {
+        let segments = self
+            .segments
+            .iter()
+            .take_while(|segment| matches!(segment, Segment::Number(_)))
+            .cloned()
+            .collect();
+        Self {
+            original: self.original.clone(),
+            segments,
+        }
+    }
+
+    /// Whether this version contains any letters, e.g. `1.0.0.pre`
+    pub fn is_prerelease(&self) -> bool {
+        self.segments
+            .iter()
+            .any(|s| matches!(s, Segment::String(_)))
+    }
+}
+
+impl FromStr for Version {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self::parse(s))
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.original)
+    }
+}
+
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Version {}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let zero = Segment::Number(String::new());
+        let len = self.segments.len().max(other.segments.len());
+        for i in 0..len {
+            let lhs = self.segments.get(i).unwrap_or(&zero);
+            let rhs = other.segments.get(i).unwrap_or(&zero);
+            match lhs.cmp(rhs) {
+                Ordering::Equal => continue,
+                ordering => return ordering,
+            }
+        }
+        Ordering::Equal
+    }
+}
+
+/// A single version constraint like `~> 1.2` or `>= 1.0.0.pre`, as listed
+/// for dependencies in the compact index
+#[derive(Debug, Clone)]
+pub struct Requirement {
+    op: String,
+    version: Version,
+}
+
+impl Requirement {
+    /// Parses `<op> <version>`, where a bare version means `=`
+    pub fn parse(requirement: &str) -> Self {
+        let requirement = requirement.trim();
+        let split = requirement
+            .find(|c: char| c.is_ascii_alphanumeric())
+            .unwrap_or(requirement.len());
+        let (op, version) = requirement.split_at(split);
+        let op = match op.trim() {
+            "" => "=",
+            op => op,
+        };
+        Self {
+            op: op.to_string(),
+            version: Version::parse(version),
+        }
+    }
+
+    /// Whether `version` meets the constraint, like
+    /// `Gem::Requirement#satisfied_by?`. An unknown operator matches nothing.
+    pub fn matches(&self, version: &Version) -> bool {
+        match self.op.as_str() {
+            "=" => version == &self.version,
+            "!=" => version != &self.version,
+            ">" => version > &self.version,
+            "<" => version < &self.version,
+            ">=" => version >= &self.version,
+            "<=" => version <= &self.version,
+            // Like RubyGems, the upper bound is checked against the release,
+            // so `~> 1.2` doesn't admit `2.0.0.pre`
+            "~>" => version >= &self.version && version.release() < self.version.bump(),
+            _ => false,
+        }
+    }
+
+    /// Whether the constraint names a prerelease, which is what lets
+    /// prereleases satisfy it
+    pub fn is_prerelease(&self) -> bool {
+        self.version.is_prerelease()
+    }
+}
+
+impl Version {
+    /// The upper bound of `~>`, like `Gem::Version#bump`: drops any
+    /// prerelease part and the last release segment, then increments the
+    /// new last one, so `1.2.3` becomes `1.3`
+    fn bump(&self) -> Version {
+        let mut segments = self
+            .segments
+            .iter()
+            .take_while(|segment| matches!(segment, Segment::Number(_)))
+            .map(|segment| match segment {
+                Segment::Number(n) if n.is_empty() => 0u128,
+                Segment::Number(n) => n.parse().unwrap_or(u128::MAX),
+                Segment::String(_) => unreachable!(),
+            })
+            .collect::<Vec<_>>();
+        // Trailing zeros were canonicalized away, so recover the written
+        // length from the original string
+        let written = self
+            .original
+            .split('.')
+            .take_while(|part| part.chars().all(|c| c.is_ascii_digit()) && !part.is_empty())
+            .count()
+            .max(1);
+        segments.resize(written.max(segments.len()), 0);
+        if segments.len() > 1 {
+            segments.pop();
+        }
+        if let Some(last) = segments.last_mut() {
+            *last = last.saturating_add(1);
+        }
+        let bumped = segments
+            .iter()
+            .map(u128::to_string)
+            .collect::<Vec<_>>()
+            .join(".");
+        Version::parse(&bumped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v(version: &str) -> Version {
+        Version::parse(version)
+    }
+
+    #[test]
+    fn prereleases_sort_before_their_release() {
+        let ordered = [
+            "1.0.0.a",
+            "1.0.0.b",
+            "1.0.0.pre",
+            "1.0.0.pre.2",
+            "1.0.0.pre.10",
+            "1.0.0.rc1",
+            "1.0.0",
+            "1.0.1",
+            "1.10",
+            "20000000000000000000000",
+        ];
+        for pair in ordered.windows(2) {
+            assert!(v(pair[0]) < v(pair[1]), "{} < {}", pair[0], pair[1]);
+        }
+        assert!(v("1.0.0.pre").is_prerelease());
+        assert!(!v("1.0.0").is_prerelease());
+    }
+
+    #[test]
+    fn trailing_zeros_are_ignored() {
+        assert_eq!(v("1"), v("1.0"));
+        assert_eq!(v("1.0.0"), v("1.0"));
+        assert_eq!(v("1.0.0.pre.0"), v("1.0.pre"));
+        assert_eq!(v("01.2"), v("1.2"));
+        assert_ne!(v("1.0.1"), v("1.1"));
+        // Displayed as written, not canonicalized
+        assert_eq!(v("1.0.0").to_string(), "1.0.0");
+    }
+
+    #[test]
+    fn bump_drops_the_last_segment_and_increments() {
+        for (version, bumped) in [
+            ("1.2.3", "1.3"),
+            ("1.2", "2"),
+            ("1", "2"),
+            ("1.0", "2"),
+            ("1.0.0", "1.1"),
+            ("1.2.3.pre", "1.3"),
+        ] {
+            assert_eq!(v(version).bump().to_string(), bumped, "{}", version);
+        }
+    }
+
+    #[test]
+    fn pessimistic_requirements_stop_at_the_bump() {
+        let requirement = Requirement::parse("~> 1.2.3");
+        assert!(requirement.matches(&v("1.2.3")));
+        assert!(requirement.matches(&v("1.2.99")));
+        assert!(!requirement.matches(&v("1.3")));
+        assert!(!requirement.matches(&v("1.2.2")));
+
+        let requirement = Requirement::parse("~> 1.2");
+        assert!(requirement.matches(&v("1.9")));
+        assert!(!requirement.matches(&v("2.0")));
+        assert!(!requirement.matches(&v("2.0.0.pre")));
+    }
+
+    #[test]
+    fn requirement_operators() {
+        assert!(Requirement::parse("1.0").matches(&v("1.0.0")));
+        assert!(Requirement::parse("= 1.0").matches(&v("1")));
+        assert!(Requirement::parse("!= 1.0").matches(&v("1.1")));
+        assert!(Requirement::parse(">= 1.0.0.pre").matches(&v("1.0.0.pre")));
+        assert!(Requirement::parse(">= 1.0.0.pre").is_prerelease());
+        assert!(Requirement::parse("< 2").matches(&v("2.0.0.rc1")));
+        assert!(!Requirement::parse("> 2").matches(&v("2.0")));
+        assert!(Requirement::parse("<= 2").matches(&v("2.0")));
+        assert!(!Requirement::parse("=~ 1.0").matches(&v("1.0")));
+    }
+}
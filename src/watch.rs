@@ -0,0 +1,62 @@
+//! Long-running mirroring: `update` on an interval until asked to stop.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use miette::miette;
+
+/// Set by SIGINT or SIGTERM; checked between cycles so an update in progress
+/// is never cut short.
+static STOP: AtomicBool = AtomicBool::new(false);
+
+/// How often a sleep between cycles checks for a shutdown request
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Runs `cycle` every `interval`, plus up to `jitter` chosen at random each
+/// time, until the process receives SIGINT or SIGTERM. Errors from a cycle are
+/// logged and the next cycle runs as scheduled.
+pub fn watch(
+    interval: Duration,
+    jitter: Option<Duration>,
+    mut cycle: impl FnMut() -> miette::Result<()>,
+) -> miette::Result<()> {
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        // SAFETY: the handler only stores to an atomic, which is
+        // async-signal-safe.
+        unsafe { signal_hook_registry::register(signal, || STOP.store(true, Ordering::SeqCst)) }
+            .map_err(|e| miette!("Failed to install signal handler: {}", e))?;
+    }
+
+    while !STOP.load(Ordering::SeqCst) {
+        let started = Instant::now();
+        if let Err(e) = cycle() {
+            eprintln!("{:?}", e);
+        }
+        println!("Update finished in {:?}", started.elapsed());
+
+        let delay = interval + jitter.map(random_up_to).unwrap_or_default();
+        println!("Next update in {:?}", delay);
+        let wake = Instant::now() + delay;
+        while !STOP.load(Ordering::SeqCst) {
+            let now = Instant::now();
+            if now >= wake {
+                break;
+            }
+            std::thread::sleep(POLL_INTERVAL.min(wake - now));
+        }
+    }
+    println!("Shutting down");
+    Ok(())
+}
+
+/// A random duration in `0..=max`, good enough to spread out mirrors that
+/// were all started at once
+fn random_up_to(max: Duration) -> Duration {
+    let random = RandomState::new().build_hasher().finish();
+    let fraction = random as f64 / u64::MAX as f64;
+    max.mul_f64(fraction)
+}